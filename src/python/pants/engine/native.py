@@ -5,12 +5,15 @@
 from __future__ import (absolute_import, division, generators, nested_scopes, print_function,
                         unicode_literals, with_statement)
 
+import hashlib
 import importlib
 import logging
 import os
 import sys
 import sysconfig
+import threading
 import traceback
+import types
 from contextlib import closing
 
 import cffi
@@ -31,6 +34,7 @@ NATIVE_ENGINE_MODULE = 'native_engine'
 CFFI_TYPEDEFS = '''
 typedef uint64_t   Id;
 typedef void*      Handle;
+typedef uint64_t   TaskBuilderId;
 
 typedef struct {
   Id id_;
@@ -71,12 +75,86 @@ typedef struct {
   Value       handle_;
 } TypeIdBuffer;
 
+typedef struct {
+  TypeConstraint*  constraints_ptr;
+  uint64_t         constraints_len;
+  Value            handle_;
+} TypeConstraintBuffer;
+
 typedef struct {
   Buffer*     bufs_ptr;
   uint64_t    bufs_len;
   Value       handle_;
 } BufferBuffer;
 
+typedef struct {
+  Function         func;
+  TypeConstraint   product;
+  _Bool            cacheable;
+  _Bool            include_subject;
+  int32_t          priority;
+  _Bool            allow_empty;
+  _Bool            allow_self_select;
+  _Bool            shadow;
+  _Bool            has_timeout;
+  uint64_t         timeout_ms;
+  _Bool            has_name;
+  _Bool            has_doc;
+  uint64_t         clause_len;
+} TaskRecord;
+
+typedef struct {
+  _Bool            cacheable;
+  _Bool            include_subject;
+  int32_t          priority;
+  _Bool            allow_empty;
+  _Bool            allow_self_select;
+  _Bool            shadow;
+  _Bool            has_timeout;
+  uint64_t         timeout_ms;
+  _Bool            has_name;
+  Buffer           name;
+  _Bool            has_doc;
+  Buffer           doc;
+} TaskOptionsRecord;
+
+typedef struct {
+  TaskRecord*  records_ptr;
+  uint64_t     records_len;
+  Value        handle_;
+} TaskRecordBuffer;
+
+typedef struct {
+  uint8_t          kind;
+  TypeConstraint   product;
+  _Bool            optional;
+  _Bool            many;
+} SelectorRecord;
+
+typedef struct {
+  SelectorRecord*  records_ptr;
+  uint64_t         records_len;
+  Value            handle_;
+} SelectorRecordBuffer;
+
+typedef struct {
+  uint64_t  products;
+  uint64_t  tasks;
+  uint64_t  selectors;
+  uint64_t  max_clause_len;
+  uint64_t  singletons;
+  uint64_t  aliases;
+  uint64_t  shadowed;
+  uint64_t  mergeable;
+  uint64_t  first_match;
+  uint64_t  resolutions;
+  uint64_t  preparing;
+} TasksStats;
+
+typedef struct {
+  uint64_t  counts[28];
+} OutcomeCounts;
+
 typedef struct {
   _Bool  is_throw;
   Value  value;
@@ -88,6 +166,11 @@ typedef struct {
   TypeId    type_id;
 } Ident;
 
+typedef struct {
+  Value*    constraint;
+  TypeId    type_id;
+} SatisfiedByTypeQuery;
+
 typedef void ExternContext;
 
 // On the rust side the integration is defined in externs.rs
@@ -100,14 +183,19 @@ typedef Buffer           (*extern_ptr_type_to_str)(ExternContext*, TypeId);
 typedef Buffer           (*extern_ptr_val_to_str)(ExternContext*, Value*);
 typedef _Bool            (*extern_ptr_satisfied_by)(ExternContext*, Value*, Value*);
 typedef _Bool            (*extern_ptr_satisfied_by_type)(ExternContext*, Value*, TypeId*);
+typedef Buffer           (*extern_ptr_satisfied_by_type_multi)(ExternContext*, SatisfiedByTypeQuery*, uint64_t);
 typedef Value            (*extern_ptr_store_list)(ExternContext*, Value**, uint64_t, _Bool);
 typedef Value            (*extern_ptr_store_bytes)(ExternContext*, uint8_t*, uint64_t);
 typedef Value            (*extern_ptr_store_i32)(ExternContext*, int32_t);
+typedef _Bool            (*extern_ptr_has_field)(ExternContext*, Value*, uint8_t*, uint64_t);
 typedef Value            (*extern_ptr_project)(ExternContext*, Value*, uint8_t*, uint64_t, TypeId*);
 typedef ValueBuffer      (*extern_ptr_project_multi)(ExternContext*, Value*, uint8_t*, uint64_t);
+typedef TypeIdBuffer     (*extern_ptr_project_type_ids)(ExternContext*, Value*, uint8_t*, uint64_t);
 typedef Value            (*extern_ptr_project_ignoring_type)(ExternContext*, Value*, uint8_t*, uint64_t);
+typedef PyResult         (*extern_ptr_instantiate)(ExternContext*, TypeId*, Value*, uint64_t);
+typedef Buffer           (*extern_ptr_digest)(ExternContext*, Value*);
 typedef Value            (*extern_ptr_create_exception)(ExternContext*, uint8_t*, uint64_t);
-typedef PyResult         (*extern_ptr_call)(ExternContext*, Value*, Value*, uint64_t);
+typedef PyResult         (*extern_ptr_call)(ExternContext*, Value*, Value*, uint64_t, uint64_t);
 typedef PyResult         (*extern_ptr_eval)(ExternContext*, uint8_t*, uint64_t);
 
 typedef void Tasks;
@@ -142,28 +230,56 @@ void externs_set(ExternContext*,
                  extern_ptr_val_to_str,
                  extern_ptr_satisfied_by,
                  extern_ptr_satisfied_by_type,
+                 extern_ptr_satisfied_by_type_multi,
                  extern_ptr_store_list,
                  extern_ptr_store_bytes,
                  extern_ptr_store_i32,
+                 extern_ptr_has_field,
                  extern_ptr_project,
                  extern_ptr_project_ignoring_type,
                  extern_ptr_project_multi,
+                 extern_ptr_project_type_ids,
+                 extern_ptr_instantiate,
+                 extern_ptr_digest,
                  extern_ptr_create_exception,
                  TypeId);
 
 Key externs_key_for(Value);
 Value externs_val_for(Key);
 
-Tasks* tasks_create(void);
-void tasks_task_begin(Tasks*, Function, TypeConstraint);
-void tasks_add_select(Tasks*, TypeConstraint);
-void tasks_add_select_variant(Tasks*, TypeConstraint, Buffer);
-void tasks_add_select_dependencies(Tasks*, TypeConstraint, TypeConstraint, Buffer, TypeIdBuffer);
-void tasks_add_select_transitive(Tasks*, TypeConstraint, TypeConstraint, Buffer, TypeIdBuffer);
-void tasks_add_select_projection(Tasks*, TypeConstraint, TypeId, Buffer, TypeConstraint);
-void tasks_task_end(Tasks*);
-void tasks_singleton_add(Tasks*, Value, TypeConstraint);
+Tasks* tasks_create(Key, TypeId, TypeId);
+TaskBuilderId tasks_builder_begin(Tasks*, Function, TypeConstraint, TaskOptionsRecord);
+PyResult tasks_builder_finish(Tasks*, TaskBuilderId);
+PyResult tasks_builder_replace(Tasks*, TaskBuilderId, Function);
+PyResult tasks_task_alias(Tasks*, TypeConstraint, TypeConstraint);
+PyResult tasks_add_bulk(Tasks*, TaskRecordBuffer, SelectorRecordBuffer, BufferBuffer);
+PyResult tasks_task_remove(Tasks*, Function, TypeConstraint);
+PyResult tasks_task_begin(Tasks*, Function, TypeConstraint, TaskOptionsRecord);
+PyResult tasks_add_select(Tasks*, TaskBuilderId, TypeConstraint, _Bool, _Bool);
+PyResult tasks_add_select_variant(Tasks*, TaskBuilderId, TypeConstraint, Key, _Bool, Buffer);
+PyResult tasks_add_select_subject(Tasks*, TaskBuilderId, TypeConstraint);
+PyResult tasks_add_select_variant_value(Tasks*, TaskBuilderId, TypeConstraint, Key);
+PyResult tasks_add_select_union(Tasks*, TaskBuilderId, TypeConstraintBuffer);
+PyResult tasks_add_select_dependencies(Tasks*, TaskBuilderId, TypeConstraint, TypeConstraint, Buffer, TypeIdBuffer, TypeConstraintBuffer, _Bool, _Bool);
+PyResult tasks_add_select_transitive(Tasks*, TaskBuilderId, TypeConstraint, TypeConstraint, Buffer, TypeIdBuffer, TypeConstraintBuffer);
+PyResult tasks_add_select_projection(Tasks*, TaskBuilderId, TypeConstraint, TypeId, BufferBuffer, TypeConstraint, _Bool);
+PyResult tasks_add_select_projection_alternatives(Tasks*, TaskBuilderId, TypeConstraint, TypeId, BufferBuffer, TypeConstraintBuffer, _Bool);
+PyResult tasks_task_end(Tasks*);
+_Bool tasks_task_abort(Tasks*);
+_Bool tasks_builder_abort(Tasks*, TaskBuilderId);
+PyResult tasks_singleton_add(Tasks*, Value, TypeConstraint);
+PyResult tasks_mergeable_add(Tasks*, TypeConstraint);
+PyResult tasks_first_match_add(Tasks*, TypeConstraint);
+PyResult tasks_goal_add(Tasks*, Key, TypeConstraint);
+Value tasks_goal_product(Tasks*, Key);
+PyResult tasks_freeze(Tasks*);
 void tasks_destroy(Tasks*);
+Value tasks_fingerprint(Tasks*);
+Value tasks_gen_tasks_report(Tasks*, TypeId, TypeConstraint);
+Value tasks_report(Tasks*);
+TasksStats tasks_stats(Tasks*);
+Value tasks_all_products(Tasks*);
+Value tasks_unreachable(Tasks*, TypeIdBuffer, TypeConstraintBuffer);
 
 Scheduler* scheduler_create(Tasks*,
                             Function,
@@ -201,6 +317,7 @@ ExecutionRequest* execution_request_create(void);
 void execution_request_destroy(ExecutionRequest*);
 
 uint64_t graph_len(Scheduler*);
+OutcomeCounts graph_outcome_counts(Scheduler*);
 uint64_t graph_invalidate(Scheduler*, BufferBuffer);
 void graph_visualize(Scheduler*, ExecutionRequest*, char*);
 void graph_trace(Scheduler*, ExecutionRequest*, char*);
@@ -225,7 +342,7 @@ void garbage_collect_store(Scheduler*);
 CFFI_EXTERNS = '''
 extern "Python" {
   void             extern_log(ExternContext*, uint8_t, uint8_t*, uint64_t);
-  PyResult         extern_call(ExternContext*, Value*, Value*, uint64_t);
+  PyResult         extern_call(ExternContext*, Value*, Value*, uint64_t, uint64_t);
   PyResult         extern_eval(ExternContext*, uint8_t*, uint64_t);
   Ident            extern_identify(ExternContext*, Value*);
   _Bool            extern_equals(ExternContext*, Value*, Value*);
@@ -235,12 +352,17 @@ extern "Python" {
   Buffer           extern_val_to_str(ExternContext*, Value*);
   _Bool            extern_satisfied_by(ExternContext*, Value*, Value*);
   _Bool            extern_satisfied_by_type(ExternContext*, Value*, TypeId*);
+  Buffer           extern_satisfied_by_type_multi(ExternContext*, SatisfiedByTypeQuery*, uint64_t);
   Value            extern_store_list(ExternContext*, Value**, uint64_t, _Bool);
   Value            extern_store_bytes(ExternContext*, uint8_t*, uint64_t);
   Value            extern_store_i32(ExternContext*, int32_t);
+  _Bool            extern_has_field(ExternContext*, Value*, uint8_t*, uint64_t);
   Value            extern_project(ExternContext*, Value*, uint8_t*, uint64_t, TypeId*);
   Value            extern_project_ignoring_type(ExternContext*, Value*, uint8_t*, uint64_t);
   ValueBuffer      extern_project_multi(ExternContext*, Value*, uint8_t*, uint64_t);
+  TypeIdBuffer     extern_project_type_ids(ExternContext*, Value*, uint8_t*, uint64_t);
+  PyResult         extern_instantiate(ExternContext*, TypeId*, Value*, uint64_t);
+  Buffer           extern_digest(ExternContext*, Value*);
   Value            extern_create_exception(ExternContext*, uint8_t*, uint64_t);
 }
 '''
@@ -286,15 +408,89 @@ def bootstrap_c_source(output_dir, module_name=NATIVE_ENGINE_MODULE):
   fixup_times(env_script)
 
 
+def _update_with_code(hasher, code):
+  hasher.update(code.co_code)
+  hasher.update(repr(code.co_names).encode('utf-8'))
+  for const in code.co_consts:
+    # Nested code objects (of lambdas, for example) are hashed recursively, since their reprs
+    # include their addresses.
+    if isinstance(const, types.CodeType):
+      _update_with_code(hasher, const)
+    else:
+      hasher.update(repr(const).encode('utf-8'))
+
+
+def _qualified_name(obj):
+  name = getattr(obj, '__qualname__', None) or getattr(obj, '__name__', None) or repr(obj)
+  return '{}:{}'.format(getattr(obj, '__module__', None), name)
+
+
+def _update_with_value(hasher, obj, seen):
+  if callable(obj) and hasattr(obj, '__name__'):
+    hasher.update(_qualified_name(obj).encode('utf-8'))
+    code = getattr(obj, '__code__', None)
+    if code is not None:
+      _update_with_code(hasher, code)
+  elif type(obj).__repr__ is not object.__repr__:
+    hasher.update(repr(obj).encode('utf-8'))
+  elif id(obj) not in seen:
+    # The default repr includes the address of the value, so it is identified by its type and
+    # (recursively) its attributes instead.
+    seen.add(id(obj))
+    hasher.update(_qualified_name(type(obj)).encode('utf-8'))
+    for name, value in sorted(getattr(obj, '__dict__', {}).items()):
+      hasher.update(name.encode('utf-8'))
+      _update_with_value(hasher, value, seen)
+
+
+def digest(obj):
+  """Returns a hex digest of the content of the given value.
+
+  Functions and types are identified by their module, qualified name and code (if any). Other
+  values are identified by their repr if their type defines one, or otherwise by their type and
+  attributes. Unlike the id (or the default repr) of a value, the digest is stable between runs, so
+  it is used to fingerprint the registered rules and singletons.
+  """
+  hasher = hashlib.sha1()
+  _update_with_value(hasher, obj, set())
+  return hasher.hexdigest()
+
+
+def _call_with_timeout(func, args, timeout_ms):
+  """Calls the given function, raising if it does not complete within timeout_ms.
+
+  A python function cannot be interrupted, so the call runs in a daemon thread which is abandoned
+  (to complete in the background) if it times out.
+  """
+  outcome = []
+
+  def run():
+    try:
+      outcome.append((True, func(*args)))
+    except Exception:
+      outcome.append((False, sys.exc_info()))
+
+  thread = threading.Thread(target=run, name='call-{}'.format(getattr(func, '__name__', func)))
+  thread.daemon = True
+  thread.start()
+  thread.join(timeout_ms / 1000.0)
+  if not outcome:
+    raise Exception('{} did not complete within its timeout of {}ms.'.format(func, timeout_ms))
+  returned, val = outcome[0]
+  if not returned:
+    six.reraise(*val)
+  return val
+
+
 def _initialize_externs(ffi):
   """Initializes extern callbacks given a CFFI handle."""
 
   def to_py_str(msg_ptr, msg_len):
     return bytes(ffi.buffer(msg_ptr, msg_len)).decode('utf-8')
 
-  def call(c, func, args):
+  def call(c, func, args, timeout_ms=0):
     try:
-      val = func(*args)
+      val = _call_with_timeout(func, args, timeout_ms) if timeout_ms else func(*args)
       is_throw = False
     except Exception as e:
       val = e
@@ -369,6 +565,15 @@ def _initialize_externs(ffi):
     constraint = ffi.from_handle(constraint_val.handle)
     return constraint.satisfied_by_type(c.from_id(cls_id.id_))
 
+  @ffi.def_extern()
+  def extern_satisfied_by_type_multi(context_handle, queries_ptr, queries_len):
+    """Given an array of (TypeConstraint, TypeId) queries, return a buffer of one bool per query."""
+    c = ffi.from_handle(context_handle)
+    return c.buf(bytes(bytearray(
+      ffi.from_handle(query.constraint.handle).satisfied_by_type(c.from_id(query.type_id.id_))
+      for query in ffi.unpack(queries_ptr, queries_len)
+    )))
+
   @ffi.def_extern()
   def extern_store_list(context_handle, vals_ptr_ptr, vals_len, merge):
     """Given storage and an array of Values, return a new Value to represent the list."""
@@ -399,6 +604,15 @@ def _initialize_externs(ffi):
     c = ffi.from_handle(context_handle)
     return c.to_value(i32)
 
+  @ffi.def_extern()
+  def extern_has_field(context_handle, val, field_str_ptr, field_str_len):
+    """Given a Value for `obj` and a field name, return whether `obj` has the field."""
+    c = ffi.from_handle(context_handle)
+    obj = c.from_value(val)
+    field_name = to_py_str(field_str_ptr, field_str_len)
+
+    return hasattr(obj, field_name)
+
   @ffi.def_extern()
   def extern_project(context_handle, val, field_str_ptr, field_str_len, type_id):
     """Given a Value for `obj`, a field name, and a type, project the field as a new Value."""
@@ -432,6 +646,29 @@ def _initialize_externs(ffi):
 
     return c.vals_buf(tuple(c.to_value(p) for p in getattr(obj, field_name)))
 
+  @ffi.def_extern()
+  def extern_project_type_ids(context_handle, val, field_str_ptr, field_str_len):
+    """Given a Key for `obj`, and a field name, project the field as a list of TypeIds."""
+    c = ffi.from_handle(context_handle)
+    obj = c.from_value(val)
+    field_name = to_py_str(field_str_ptr, field_str_len)
+
+    return c.type_ids_buf(tuple(TypeId(c.to_id(t)) for t in getattr(obj, field_name)))
+
+  @ffi.def_extern()
+  def extern_instantiate(context_handle, type_id, args_ptr, args_len):
+    """Given a type and a series of Values, construct an instance of the type."""
+    c = ffi.from_handle(context_handle)
+    typ = c.from_id(type_id.id_)
+    args = tuple(c.from_value(arg) for arg in ffi.unpack(args_ptr, args_len))
+    return call(c, typ, args)
+
+  @ffi.def_extern()
+  def extern_digest(context_handle, val):
+    """Given a Value, write `digest(val)` and return it."""
+    c = ffi.from_handle(context_handle)
+    return c.utf8_buf(six.text_type(digest(c.from_value(val))))
+
   @ffi.def_extern()
   def extern_create_exception(context_handle, msg_ptr, msg_len):
     """Given a utf8 message string, create an Exception object."""
@@ -440,12 +677,12 @@ def _initialize_externs(ffi):
     return c.to_value(Exception(msg))
 
   @ffi.def_extern()
-  def extern_call(context_handle, func, args_ptr, args_len):
-    """Given a callable, call it."""
+  def extern_call(context_handle, func, args_ptr, args_len, timeout_ms):
+    """Given a callable, call it within the given budget (if non-zero) in milliseconds."""
     c = ffi.from_handle(context_handle)
     runnable = c.from_value(func)
     args = tuple(c.from_value(arg) for arg in ffi.unpack(args_ptr, args_len))
-    return call(c, runnable, args)
+    return call(c, runnable, args, timeout_ms)
 
   @ffi.def_extern()
   def extern_eval(context_handle, python_code_str_ptr, python_code_str_len):
@@ -522,6 +759,18 @@ class ExternContext(object):
     buf = self._ffi.new('TypeId[]', types)
     return (buf, len(types), self.to_value(buf))
 
+  def type_constraints_buf(self, constraints):
+    buf = self._ffi.new('TypeConstraint[]', constraints)
+    return (buf, len(constraints), self.to_value(buf))
+
+  def task_records_buf(self, records):
+    buf = self._ffi.new('TaskRecord[]', records)
+    return (buf, len(records), self.to_value(buf))
+
+  def selector_records_buf(self, records):
+    buf = self._ffi.new('SelectorRecord[]', records)
+    return (buf, len(records), self.to_value(buf))
+
   def to_value(self, obj):
     handle = self._ffi.new_handle(obj)
     self._handles.add(handle)
@@ -620,12 +869,17 @@ class Native(object):
                            self.ffi_lib.extern_val_to_str,
                            self.ffi_lib.extern_satisfied_by,
                            self.ffi_lib.extern_satisfied_by_type,
+                           self.ffi_lib.extern_satisfied_by_type_multi,
                            self.ffi_lib.extern_store_list,
                            self.ffi_lib.extern_store_bytes,
                            self.ffi_lib.extern_store_i32,
+                           self.ffi_lib.extern_has_field,
                            self.ffi_lib.extern_project,
                            self.ffi_lib.extern_project_ignoring_type,
                            self.ffi_lib.extern_project_multi,
+                           self.ffi_lib.extern_project_type_ids,
+                           self.ffi_lib.extern_instantiate,
+                           self.ffi_lib.extern_digest,
                            self.ffi_lib.extern_create_exception,
                            TypeId(context.to_id(str)))
       return context
@@ -653,7 +907,10 @@ class Native(object):
     return self.context.type_ids_buf([TypeId(self.context.to_id(t)) for t in types])
 
   def new_tasks(self):
-    return self.gc(self.lib.tasks_create(), self.lib.tasks_destroy)
+    tasks = self.lib.tasks_create(self.context.to_key(None),
+                                  TypeId(self.context.to_id(six.text_type)),
+                                  TypeId(self.context.to_id(six.binary_type)))
+    return self.gc(tasks, self.lib.tasks_destroy)
 
   def new_execution_request(self):
     return self.gc(self.lib.execution_request_create(), self.lib.execution_request_destroy)
@@ -726,6 +983,8 @@ class Native(object):
         self.context.utf8_buf_buf(ignore_patterns),
         self.to_ids_buf(root_subject_types),
       )
+    if scheduler == self.ffi.NULL:
+      raise ValueError('Failed to create the native Scheduler: see the log for details.')
     return self.gc(scheduler, self.lib.scheduler_destroy)
 
   def set_panic_handler(self):
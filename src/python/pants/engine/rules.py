@@ -5,6 +5,7 @@
 from __future__ import (absolute_import, division, generators, nested_scopes, print_function,
                         unicode_literals, with_statement)
 
+import inspect
 import logging
 from abc import abstractproperty
 from collections import OrderedDict
@@ -50,10 +51,39 @@ class Rule(AbstractClass):
     """Collection of input selectors."""
 
 
-class TaskRule(datatype('TaskRule', ['output_constraint', 'input_selectors', 'func']), Rule):
-  """A Rule that runs a task function when all of its input selectors are satisfied."""
+class TaskRule(datatype('TaskRule', ['output_constraint', 'input_selectors', 'func', 'cacheable',
+                                     'include_subject', 'priority', 'allow_empty',
+                                     'allow_self_select', 'shadow', 'timeout_ms', 'name',
+                                     'doc']),
+               Rule):
+  """A Rule that runs a task function when all of its input selectors are satisfied.
 
-  def __new__(cls, output_type, input_selectors, func):
+  An uncacheable TaskRule is re-run in every execution, along with anything that depends on it.
+
+  If include_subject=True, the subject is passed to the task function as its first argument,
+  followed by the values of its input selectors.
+
+  When several TaskRules produce the same product, those with a higher priority are attempted
+  first, and otherwise they are attempted in registration order.
+
+  A TaskRule with no input selectors is usually a mistake, so one must set allow_empty=True.
+  Likewise, a TaskRule which Selects its own output type for the same subject would wait on itself
+  forever, so one must set allow_self_select=True.
+
+  If shadow=True, the TaskRule replaces any TaskRules registered before it for the same output
+  type, rather than being attempted alongside them.
+
+  If a timeout_ms is given, a call to the task function that does not complete within that budget
+  fails. The function cannot be interrupted, so a call that times out is abandoned to complete in
+  the background.
+
+  If a name is given, it is used in place of the function's name in diagnostics. Likewise, the doc
+  (which defaults to the function's docstring) is summarized in diagnostics.
+  """
+
+  def __new__(cls, output_type, input_selectors, func, cacheable=True, include_subject=False,
+              priority=0, allow_empty=False, allow_self_select=False, shadow=False, timeout_ms=None,
+              name=None, doc=None):
     # Validate result type.
     if isinstance(output_type, Exactly):
       constraint = output_type
@@ -68,8 +98,13 @@ class TaskRule(datatype('TaskRule', ['output_constraint', 'input_selectors', 'fu
       raise TypeError("Expected a list of Selectors for rule `{}`, got: {}".format(
         func.__name__, type(input_selectors)))
 
+    if doc is None:
+      doc = inspect.getdoc(func)
+
     # Create.
-    return super(TaskRule, cls).__new__(cls, constraint, tuple(input_selectors), func)
+    return super(TaskRule, cls).__new__(cls, constraint, tuple(input_selectors), func, cacheable,
+                                        include_subject, priority, allow_empty,
+                                        allow_self_select, shadow, timeout_ms, name, doc)
 
   def __str__(self):
     return '({}, {!r}, {})'.format(type_or_constraint_repr(self.output_constraint),
@@ -100,6 +135,55 @@ class SingletonRule(datatype('SingletonRule', ['output_constraint', 'value']), R
     return '{}({}, {})'.format(type(self).__name__, type_or_constraint_repr(self.output_constraint), self.value)
 
 
+class FirstMatchRule(datatype('FirstMatchRule', ['output_constraint']), Rule):
+  """Marks a product as first-match.
+
+  Rather than requiring exactly one TaskRule to produce a value for the product, its TaskRules are
+  attempted in order of priority and then of registration, and the first value produced is used:
+  later TaskRules thus act as fallbacks for earlier ones.
+  """
+
+  def __new__(cls, output_type):
+    # Validate result type.
+    if isinstance(output_type, Exactly):
+      constraint = output_type
+    elif isinstance(output_type, type):
+      constraint = Exactly(output_type)
+    else:
+      raise TypeError("Expected an output_type for rule; got: {}".format(output_type))
+
+    # Create.
+    return super(FirstMatchRule, cls).__new__(cls, constraint)
+
+  @property
+  def input_selectors(self):
+    return tuple()
+
+
+class MergeableRule(datatype('MergeableRule', ['output_constraint']), Rule):
+  """Marks a product as mergeable.
+
+  Rather than being a conflict, the values produced for the product by multiple TaskRules (which
+  must be collections) are merged into a single collection.
+  """
+
+  def __new__(cls, output_type):
+    # Validate result type.
+    if isinstance(output_type, Exactly):
+      constraint = output_type
+    elif isinstance(output_type, type):
+      constraint = Exactly(output_type)
+    else:
+      raise TypeError("Expected an output_type for rule; got: {}".format(output_type))
+
+    # Create.
+    return super(MergeableRule, cls).__new__(cls, constraint)
+
+  @property
+  def input_selectors(self):
+    return tuple()
+
+
 class RootRule(datatype('RootRule', ['output_constraint']), Rule):
   """Represents a root input to an execution of a rule graph.
   
@@ -112,8 +196,11 @@ class RootRule(datatype('RootRule', ['output_constraint']), Rule):
     return []
 
 
-class RuleIndex(datatype('RuleIndex', ['rules', 'roots'])):
-  """Holds an index of Tasks and Singletons used to instantiate Nodes."""
+class RuleIndex(datatype('RuleIndex', ['rules', 'roots', 'first_match', 'mergeable'])):
+  """Holds an index of Tasks and Singletons used to instantiate Nodes.
+
+  Also records the root types, and the products which were marked first-match or mergeable.
+  """
 
   @classmethod
   def create(cls, rule_entries):
@@ -121,6 +208,8 @@ class RuleIndex(datatype('RuleIndex', ['rules', 'roots'])):
     # NB make tasks ordered so that gen ordering is deterministic.
     serializable_rules = OrderedDict()
     serializable_roots = set()
+    serializable_first_match = OrderedSet()
+    serializable_mergeable = OrderedSet()
 
     def add_task(product_type, rule):
       if product_type not in serializable_rules:
@@ -131,6 +220,12 @@ class RuleIndex(datatype('RuleIndex', ['rules', 'roots'])):
       if isinstance(rule, RootRule):
         serializable_roots.add(rule.output_constraint)
         return
+      if isinstance(rule, FirstMatchRule):
+        serializable_first_match.add(rule.output_constraint)
+        return
+      if isinstance(rule, MergeableRule):
+        serializable_mergeable.add(rule.output_constraint)
+        return
       # TODO: The heterogenity here has some confusing implications here:
       # see https://github.com/pantsbuild/pants/issues/4005
       for kind in rule.output_constraint.types:
@@ -152,4 +247,5 @@ class RuleIndex(datatype('RuleIndex', ['rules', 'roots'])):
                         "Rules either extend Rule, or are static functions "
                         "decorated with @rule.".format(type(entry)))
 
-    return cls(serializable_rules, serializable_roots)
+    return cls(serializable_rules, serializable_roots, serializable_first_match,
+               serializable_mergeable)
@@ -20,8 +20,9 @@ from pants.engine.isolated_process import (ExecuteProcessRequest, ExecuteProcess
 from pants.engine.native import Function, TypeConstraint, TypeId
 from pants.engine.nodes import Return, State, Throw
 from pants.engine.rules import RuleIndex, SingletonRule, TaskRule
-from pants.engine.selectors import (Select, SelectDependencies, SelectProjection, SelectTransitive,
-                                    SelectVariant, constraint_for)
+from pants.engine.selectors import (Select, SelectDependencies, SelectProjection, SelectSubject,
+                                    SelectTransitive, SelectUnion, SelectVariant,
+                                    SelectVariantValue, constraint_for)
 from pants.engine.struct import HasProducts, Variants
 from pants.util.contextutil import temporary_file_path
 from pants.util.objects import datatype
@@ -74,7 +75,18 @@ class ExecutionError(Exception):
 
 
 class WrappedNativeScheduler(object):
-  def __init__(self, native, build_root, work_dir, ignore_patterns, rule_index):
+  # The fields of the native TasksStats struct.
+  _RULES_STATS_FIELDS = ('products', 'tasks', 'selectors', 'max_clause_len', 'singletons',
+                         'aliases', 'shadowed', 'mergeable', 'first_match', 'resolutions',
+                         'preparing')
+
+  # NB: These must match the order of `NodeKey::kind_index` and `graph::outcome_index` in the
+  # native engine.
+  _NODE_KINDS = ('DigestFile', 'ExecuteProcess', 'ReadLink', 'Scandir', 'Select', 'Snapshot',
+                 'Task')
+  _OUTCOMES = ('Return', 'Noop', 'Throw', 'Invalidated')
+
+  def __init__(self, native, build_root, work_dir, ignore_patterns, rule_index, goals=None):
     self._native = native
     # TODO: The only (?) case where we use inheritance rather than exact type unions.
     has_products_constraint = SubclassesOf(HasProducts)
@@ -83,6 +95,8 @@ class WrappedNativeScheduler(object):
     # Create the ExternContext, and the native Scheduler.
     self._tasks = native.new_tasks()
     self._register_rules(rule_index)
+    self._register_goals(goals or {})
+    self._register(native.lib.tasks_freeze)
 
     self._scheduler = native.new_scheduler(
       self._tasks,
@@ -112,6 +126,7 @@ class WrappedNativeScheduler(object):
       constraint_for(ExecuteProcessRequest),
       constraint_for(ExecuteProcessResult),
     )
+    logger.debug('registered rules: %s', self.rules_stats())
 
   def _root_type_ids(self):
     return self._to_ids_buf(sorted(self._root_subject_types))
@@ -151,15 +166,25 @@ class WrappedNativeScheduler(object):
   def _to_constraint(self, type_or_constraint):
     return TypeConstraint(self._to_key(constraint_for(type_or_constraint)))
 
+  def _to_constraints_buf(self, types_or_constraints):
+    return self._native.context.type_constraints_buf(
+      [self._to_constraint(t) for t in types_or_constraints])
+
   def _to_ids_buf(self, types):
     return self._native.to_ids_buf(types)
 
+  def _to_subclass_constraints_buf(self, types):
+    return self._to_constraints_buf([SubclassesOf(t) for t in types])
+
   def _to_utf8_buf(self, string):
     return self._native.context.utf8_buf(string)
 
   def _register_rules(self, rule_index):
     """Record the given RuleIndex on `self._tasks`."""
     registered = set()
+    # TaskRules which may be registered in bulk are batched, but the batch is flushed before any
+    # other registration so that registration order is preserved.
+    bulk = []
     for product_type, rules in rule_index.rules.items():
       # TODO: The rules map has heterogeneous keys, so we normalize them to type constraints
       # and dedupe them before registering to the native engine:
@@ -171,58 +196,184 @@ class WrappedNativeScheduler(object):
           continue
         registered.add(key)
 
+        if type(rule) is TaskRule and self._can_register_in_bulk(rule):
+          bulk.append((output_constraint, rule))
+          continue
+        self._register_tasks_in_bulk(bulk)
+        bulk = []
+
         if type(rule) is SingletonRule:
           self._register_singleton(output_constraint, rule)
         elif type(rule) is TaskRule:
           self._register_task(output_constraint, rule)
         else:
           raise ValueError('Unexpected Rule type: {}'.format(rule))
+    self._register_tasks_in_bulk(bulk)
+
+    for output_constraint in rule_index.first_match:
+      self._register(self._native.lib.tasks_first_match_add, self._to_constraint(output_constraint))
+    for output_constraint in rule_index.mergeable:
+      self._register(self._native.lib.tasks_mergeable_add, self._to_constraint(output_constraint))
+
+  def _register_goals(self, goals):
+    """Record the given dict from goal name to product type on `self._tasks`."""
+    for goal_name, product in sorted(goals.items()):
+      self._register(self._native.lib.tasks_goal_add,
+                     self._to_key(goal_name),
+                     self._to_constraint(product))
+
+  def _register(self, tasks_fn, *args):
+    """Calls the given native registration function for `self._tasks`, raising on failure."""
+    res = tasks_fn(self._tasks, *args)
+    if res.is_throw:
+      raise self._from_value(res.value)
+
+  _BULK_SELECTOR_KINDS = {Select: 0, SelectSubject: 1}
+
+  def _can_register_in_bulk(self, rule):
+    """True if the given TaskRule has only fixed-size selectors."""
+    return all(type(selector) in self._BULK_SELECTOR_KINDS for selector in rule.input_selectors)
+
+  def _register_tasks_in_bulk(self, constraints_and_rules):
+    """Register the given (output constraint, TaskRule) pairs in a single native call."""
+    if not constraints_and_rules:
+      return
+    task_records = []
+    selector_records = []
+    # The names and docs of the rules which have them, in order.
+    strings = []
+    for output_constraint, rule in constraints_and_rules:
+      task_records.append((Function(self._to_key(rule.func)),
+                           output_constraint,
+                           rule.cacheable,
+                           rule.include_subject,
+                           rule.priority,
+                           rule.allow_empty,
+                           rule.allow_self_select,
+                           rule.shadow,
+                           rule.timeout_ms is not None,
+                           rule.timeout_ms or 0,
+                           rule.name is not None,
+                           rule.doc is not None,
+                           len(rule.input_selectors)))
+      strings.extend(s for s in (rule.name, rule.doc) if s is not None)
+      for selector in rule.input_selectors:
+        selector_type = type(selector)
+        selector_records.append((self._BULK_SELECTOR_KINDS[selector_type],
+                                 self._to_constraint(selector.product),
+                                 selector_type is Select and selector.optional,
+                                 selector_type is Select and selector.many))
+    self._register(self._native.lib.tasks_add_bulk,
+                   self._native.context.task_records_buf(task_records),
+                   self._native.context.selector_records_buf(selector_records),
+                   self._native.context.utf8_buf_buf(strings))
 
   def _register_singleton(self, output_constraint, rule):
     """Register the given SingletonRule.
 
     A SingletonRule installed for a type will be the only provider for that type.
     """
-    self._native.lib.tasks_singleton_add(self._tasks,
-                                         self._to_value(rule.value),
-                                         output_constraint)
+    self._register(self._native.lib.tasks_singleton_add,
+                   self._to_value(rule.value),
+                   output_constraint)
 
   def _register_task(self, output_constraint, rule):
     """Register the given TaskRule with the native scheduler."""
+    lib = self._native.lib
     input_selects = rule.input_selectors
     func = rule.func
-    self._native.lib.tasks_task_begin(self._tasks, Function(self._to_key(func)), output_constraint)
-    for selector in input_selects:
-      selector_type = type(selector)
-      product_constraint = self._to_constraint(selector.product)
-      if selector_type is Select:
-        self._native.lib.tasks_add_select(self._tasks, product_constraint)
-      elif selector_type is SelectVariant:
-        key_buf = self._to_utf8_buf(selector.variant_key)
-        self._native.lib.tasks_add_select_variant(self._tasks,
-                                                  product_constraint,
-                                                  key_buf)
-      elif selector_type is SelectDependencies:
-        self._native.lib.tasks_add_select_dependencies(self._tasks,
-                                                       product_constraint,
-                                                       self._to_constraint(selector.dep_product),
-                                                       self._to_utf8_buf(selector.field),
-                                                       self._to_ids_buf(selector.field_types))
-      elif selector_type is SelectTransitive:
-        self._native.lib.tasks_add_select_transitive(self._tasks,
-                                                     product_constraint,
-                                                     self._to_constraint(selector.dep_product),
-                                                     self._to_utf8_buf(selector.field),
-                                                     self._to_ids_buf(selector.field_types))
-      elif selector_type is SelectProjection:
-        self._native.lib.tasks_add_select_projection(self._tasks,
-                                                     self._to_constraint(selector.product),
-                                                     TypeId(self._to_id(selector.projected_subject)),
-                                                     self._to_utf8_buf(selector.field),
-                                                     self._to_constraint(selector.input_product))
-      else:
-        raise ValueError('Unrecognized Selector type: {}'.format(selector))
-    self._native.lib.tasks_task_end(self._tasks)
+    has_name = rule.name is not None
+    has_doc = rule.doc is not None
+    options = dict(cacheable=rule.cacheable,
+                   include_subject=rule.include_subject,
+                   priority=rule.priority,
+                   allow_empty=rule.allow_empty,
+                   allow_self_select=rule.allow_self_select,
+                   shadow=rule.shadow,
+                   has_timeout=rule.timeout_ms is not None,
+                   timeout_ms=rule.timeout_ms or 0,
+                   has_name=has_name,
+                   name=self._to_utf8_buf(rule.name if has_name else ''),
+                   has_doc=has_doc,
+                   doc=self._to_utf8_buf(rule.doc if has_doc else ''))
+    builder = lib.tasks_builder_begin(self._tasks,
+                                      Function(self._to_key(func)),
+                                      output_constraint,
+                                      options)
+    try:
+      for selector in input_selects:
+        selector_type = type(selector)
+        product_constraint = self._to_constraint(selector.product)
+        if selector_type is Select:
+          self._register(lib.tasks_add_select,
+                         builder,
+                         product_constraint,
+                         selector.optional,
+                         selector.many)
+        elif selector_type is SelectSubject:
+          self._register(lib.tasks_add_select_subject, builder, product_constraint)
+        elif selector_type is SelectVariantValue:
+          self._register(lib.tasks_add_select_variant_value,
+                         builder,
+                         product_constraint,
+                         self._to_key(selector.variant_key))
+        elif selector_type is SelectUnion:
+          self._register(lib.tasks_add_select_union,
+                         builder,
+                         self._to_constraints_buf(selector.products))
+        elif selector_type is SelectVariant:
+          has_default = selector.default_value is not None
+          default_buf = self._to_utf8_buf(selector.default_value if has_default else '')
+          self._register(lib.tasks_add_select_variant,
+                         builder,
+                         product_constraint,
+                         self._to_key(selector.variant_key),
+                         has_default,
+                         default_buf)
+        elif selector_type is SelectDependencies:
+          self._register(lib.tasks_add_select_dependencies,
+                         builder,
+                         product_constraint,
+                         self._to_constraint(selector.dep_product),
+                         self._to_utf8_buf(selector.field),
+                         self._to_ids_buf(selector.field_types),
+                         self._to_subclass_constraints_buf(selector.field_types),
+                         selector.skip_missing,
+                         selector.skip_self)
+        elif selector_type is SelectTransitive:
+          self._register(lib.tasks_add_select_transitive,
+                         builder,
+                         product_constraint,
+                         self._to_constraint(selector.dep_product),
+                         self._to_utf8_buf(selector.field),
+                         self._to_ids_buf(selector.field_types),
+                         self._to_subclass_constraints_buf(selector.field_types))
+        elif selector_type is SelectProjection:
+          fields_buf = self._native.context.utf8_buf_buf(selector.fields)
+          if len(selector.input_products) == 1:
+            self._register(lib.tasks_add_select_projection,
+                           builder,
+                           self._to_constraint(selector.product),
+                           TypeId(self._to_id(selector.projected_subject)),
+                           fields_buf,
+                           self._to_constraint(selector.input_product),
+                           selector.skip_missing)
+          else:
+            self._register(lib.tasks_add_select_projection_alternatives,
+                           builder,
+                           self._to_constraint(selector.product),
+                           TypeId(self._to_id(selector.projected_subject)),
+                           fields_buf,
+                           self._to_constraints_buf(selector.input_products),
+                           selector.skip_missing)
+        else:
+          raise ValueError('Unrecognized Selector type: {}'.format(selector))
+    except Exception:
+      # Discard the partially described task, so that it does not prevent the registry from being
+      # frozen if the caller chooses to recover and continue registering other rules.
+      lib.tasks_builder_abort(self._tasks, builder)
+      raise
+    self._register(lib.tasks_builder_finish, builder)
 
   def visualize_graph_to_file(self, execution_request, filename):
     self._native.lib.graph_visualize(self._scheduler, execution_request, bytes(filename))
@@ -261,6 +412,53 @@ class WrappedNativeScheduler(object):
   def graph_len(self):
     return self._native.lib.graph_len(self._scheduler)
 
+  def rules_fingerprint(self):
+    """Returns a hex fingerprint of the registered rules, which is stable between runs."""
+    return self._from_value(self._native.lib.tasks_fingerprint(self._tasks))
+
+  def candidates_report(self, subject_type, product):
+    """Returns a description of the rules which could compute the product for the subject type."""
+    report = self._native.lib.tasks_gen_tasks_report(self._tasks,
+                                                     TypeId(self._to_id(subject_type)),
+                                                     self._to_constraint(product))
+    return self._from_value(report)
+
+  def rules_report(self):
+    """Returns a readable report of the registered rules, grouped by product."""
+    return self._from_value(self._native.lib.tasks_report(self._tasks))
+
+  def all_products(self):
+    """Returns the constraints of all products which the registered rules can provide."""
+    return self._from_value(self._native.lib.tasks_all_products(self._tasks))
+
+  def goal_product(self, goal_name):
+    """Returns the product constraint registered for the given goal name, or None."""
+    return self._from_value(self._native.lib.tasks_goal_product(self._tasks,
+                                                                self._to_key(goal_name)))
+
+  def rules_stats(self):
+    """Returns a dict of counts describing the size of the registered rules."""
+    stats = self._native.lib.tasks_stats(self._tasks)
+    return {field: getattr(stats, field) for field in self._RULES_STATS_FIELDS}
+
+  def unreachable_rules(self, root_products):
+    """Returns descriptions of the rules which can never run for the given root products."""
+    unreachable = self._native.lib.tasks_unreachable(self._tasks,
+                                                     self._root_type_ids(),
+                                                     self._to_constraints_buf(root_products))
+    return self._from_value(unreachable)
+
+  def outcome_counts(self):
+    """Returns a dict from (node kind, outcome) to the number of times Nodes completed with it.
+
+    Only pairs with a non-zero count are included.
+    """
+    counts = self._native.lib.graph_outcome_counts(self._scheduler).counts
+    return {(kind, outcome): counts[k * len(self._OUTCOMES) + o]
+            for k, kind in enumerate(self._NODE_KINDS)
+            for o, outcome in enumerate(self._OUTCOMES)
+            if counts[k * len(self._OUTCOMES) + o]}
+
   def exec_reset(self):
     self._native.lib.execution_reset(self._scheduler)
 
@@ -340,7 +538,8 @@ class LocalScheduler(object):
                                              project_tree.build_root,
                                              work_dir,
                                              project_tree.ignore_patterns,
-                                             rule_index)
+                                             rule_index,
+                                             goals)
 
     # If configured, visualize the rule graph before asserting that it is valid.
     if self._scheduler.visualize_to_dir() is not None:
@@ -352,6 +551,34 @@ class LocalScheduler(object):
   def graph_len(self):
     return self._scheduler.graph_len()
 
+  def rules_fingerprint(self):
+    """Returns a hex fingerprint of the registered rules, for use in keying persistent caches."""
+    return self._scheduler.rules_fingerprint()
+
+  def candidates_report(self, subject_type, product):
+    """Returns a description of the rules which could compute the product for the subject type."""
+    return self._scheduler.candidates_report(subject_type, product)
+
+  def rules_report(self):
+    """Returns a readable report of the registered rules, which is stable between runs."""
+    return self._scheduler.rules_report()
+
+  def rules_stats(self):
+    """Returns a dict of counts describing the size of the registered rules."""
+    return self._scheduler.rules_stats()
+
+  def all_products(self):
+    """Returns the constraints of all products which the registered rules can provide."""
+    return self._scheduler.all_products()
+
+  def unreachable_rules(self, root_products):
+    """Returns descriptions of the rules which can never run for the given root products."""
+    return self._scheduler.unreachable_rules(root_products)
+
+  def outcome_counts(self):
+    """Returns a dict from (node kind, outcome) to the number of times Nodes completed with it."""
+    return self._scheduler.outcome_counts()
+
   def trace(self, execution_request):
     """Yields a stringified 'stacktrace' starting from the scheduler's roots."""
     for line in self._scheduler.graph_trace(execution_request.native):
@@ -487,7 +714,13 @@ class LocalScheduler(object):
     if throw_root_states:
       if self._include_trace_on_error:
         cumulative_trace = '\n'.join(self.trace(request))
-        raise ExecutionError('Received unexpected Throw state(s):\n{}'.format(cumulative_trace))
+        # For roots which had no provider, describe the candidates that were considered.
+        candidates = [self.candidates_report(type(subject), product)
+                      for (subject, product), state in result.root_products
+                      if type(state) is Throw and
+                      str(state.exc).startswith('No task was available')]
+        raise ExecutionError('Received unexpected Throw state(s):\n{}'.format(
+          '\n'.join([cumulative_trace] + candidates)))
 
       unique_exceptions = set(t.exc for t in throw_root_states)
       if len(unique_exceptions) == 1:
@@ -9,7 +9,7 @@ from abc import abstractproperty
 
 import six
 
-from pants.engine.addressable import Exactly
+from pants.engine.addressable import Exactly, TypeConstraint
 from pants.util.meta import AbstractClass
 from pants.util.objects import datatype
 
@@ -23,8 +23,8 @@ def type_or_constraint_repr(constraint):
 
 
 def constraint_for(type_or_constraint):
-  """Given a type or an `Exactly` constraint, returns an `Exactly` constraint."""
-  if isinstance(type_or_constraint, Exactly):
+  """Given a type or a TypeConstraint, returns a TypeConstraint: `Exactly` the type for a type."""
+  if isinstance(type_or_constraint, TypeConstraint):
     return type_or_constraint
   elif isinstance(type_or_constraint, type):
     return Exactly(type_or_constraint)
@@ -48,43 +48,111 @@ class Selector(AbstractClass):
     """The product that this selector produces."""
 
 
-class Select(datatype('Select', ['product', 'optional']), Selector):
+class Select(datatype('Select', ['product', 'optional', 'many']), Selector):
   """Selects the given Product for the Subject provided to the constructor.
 
   If optional=True and no matching product can be produced, will return None.
+
+  If many=True, every value produced for the Product is selected as a list, rather than failing
+  when more than one is produced.
   """
 
-  def __new__(cls, product, optional=False):
-    obj = super(Select, cls).__new__(cls, product, optional)
+  def __new__(cls, product, optional=False, many=False):
+    obj = super(Select, cls).__new__(cls, product, optional, many)
     return obj
 
   def __repr__(self):
-    return '{}({}{})'.format(type(self).__name__,
-                             type_or_constraint_repr(self.product),
-                             ', optional=True' if self.optional else '')
+    return '{}({}{}{})'.format(type(self).__name__,
+                               type_or_constraint_repr(self.product),
+                               ', optional=True' if self.optional else '',
+                               ', many=True' if self.many else '')
+
 
+class SelectSubject(datatype('SelectSubject', ['product']), Selector):
+  """Selects the Subject itself, which must satisfy the given product type.
+
+  Unlike a Select for the Subject's own type, no tasks are consulted.
+  """
+  optional = False
 
-class SelectVariant(datatype('Variant', ['product', 'variant_key']), Selector):
+  def __repr__(self):
+    return '{}({})'.format(type(self).__name__, type_or_constraint_repr(self.product))
+
+
+class SelectVariant(datatype('Variant', ['product', 'variant_key', 'default_value']), Selector):
   """Selects the matching Product and variant name for the Subject provided to the constructor.
 
   For example: a SelectVariant with a variant_key of "thrift" and a product of type ApacheThrift
   will only match when a consumer passes a variant value for "thrift" that matches the name of an
   ApacheThrift value.
+
+  If a default_value is given, it is matched when no value is configured for the variant_key.
   """
   optional = False
 
-  def __new__(cls, product, variant_key):
+  def __new__(cls, product, variant_key, default_value=None):
     if not isinstance(variant_key, six.string_types):
       raise ValueError('Expected variant_key to be a string, but was {!r}'.format(variant_key))
-    return super(SelectVariant, cls).__new__(cls, product, variant_key)
+    if default_value is not None and not isinstance(default_value, six.string_types):
+      raise ValueError('Expected default_value to be a string, but was {!r}'.format(default_value))
+    return super(SelectVariant, cls).__new__(cls, product, variant_key, default_value)
 
   def __repr__(self):
-    return '{}({}, {})'.format(type(self).__name__,
-                               type_or_constraint_repr(self.product),
-                               repr(self.variant_key))
+    return '{}({}, {}{})'.format(type(self).__name__,
+                                 type_or_constraint_repr(self.product),
+                                 repr(self.variant_key),
+                                 '' if self.default_value is None
+                                 else ', default_value={!r}'.format(self.default_value))
+
+
+class SelectVariantValue(datatype('VariantValue', ['variant_key']), Selector):
+  """Selects the value configured for the given variant_key in the Variants of the Subject.
+
+  Unlike SelectVariant, no Product is selected for the Subject: the (string) value itself is
+  selected. If no value is configured for the variant_key, the selection is missing.
+  """
+  optional = False
+  product = six.binary_type
+
+  def __new__(cls, variant_key):
+    if not isinstance(variant_key, six.string_types):
+      raise ValueError('Expected variant_key to be a string, but was {!r}'.format(variant_key))
+    return super(SelectVariantValue, cls).__new__(cls, variant_key)
+
+  def __repr__(self):
+    return '{}({})'.format(type(self).__name__, repr(self.variant_key))
+
 
+class SelectUnion(datatype('Union', ['products']), Selector):
+  """Selects the first of the given Products that can be computed for the Subject.
 
-class SelectDependencies(datatype('Dependencies', ['product', 'dep_product', 'field', 'field_types']),
+  The products are attempted in the order in which they are declared, so later products act as
+  fallbacks for earlier ones. Each product must be a type or an `Exactly` constraint.
+  """
+  optional = False
+
+  def __new__(cls, products):
+    products = tuple(products)
+    if not products:
+      raise ValueError('Expected at least one product for a union.')
+    for product in products:
+      if not isinstance(product, (type, Exactly)):
+        raise TypeError('Expected a type or an Exactly constraint: got: {}'.format(product))
+    return super(SelectUnion, cls).__new__(cls, products)
+
+  @property
+  def product(self):
+    """A constraint satisfied by a value of any of the products."""
+    return Exactly(*(t for p in self.products for t in constraint_for(p).types))
+
+  def __repr__(self):
+    return '{}({})'.format(type(self).__name__,
+                           ', '.join(type_or_constraint_repr(p) for p in self.products))
+
+
+class SelectDependencies(datatype('Dependencies',
+                                  ['product', 'dep_product', 'field', 'field_types', 'skip_missing',
+                                   'skip_self']),
                          Selector):
   """Selects a product for each of the dependencies of a product for the Subject.
 
@@ -93,15 +161,23 @@ class SelectDependencies(datatype('Dependencies', ['product', 'dep_product', 'fi
   order they were declared.
 
   Field types are used to statically declare the types expected to be contained by the
-  `dep_product`.
+  `dep_product`. If any are given, members which are not instances of (a subclass of) one of them
+  are skipped, and a member which is an instance of a subclass is treated as the field type.
+
+  If skip_missing=True, dependencies which cannot provide the product are omitted rather than
+  causing a failure.
+
+  Unless skip_self=False, the Subject is omitted if it appears among its own dependencies.
   """
 
   DEFAULT_FIELD = 'dependencies'
 
   optional = False
 
-  def __new__(cls, product, dep_product, field=DEFAULT_FIELD, field_types=tuple()):
-    return super(SelectDependencies, cls).__new__(cls, product, dep_product, field, field_types)
+  def __new__(cls, product, dep_product, field=DEFAULT_FIELD, field_types=tuple(),
+              skip_missing=False, skip_self=True):
+    return super(SelectDependencies, cls).__new__(cls, product, dep_product, field, field_types,
+                                                  skip_missing, skip_self)
 
   @property
   def input_product_selector(self):
@@ -120,11 +196,13 @@ class SelectDependencies(datatype('Dependencies', ['product', 'dep_product', 'fi
       field_name_portion = ', {}'.format(repr(self.field))
     else:
       field_name_portion = ''
-    return '{}({}, {}{}{})'.format(type(self).__name__,
-                                     type_or_constraint_repr(self.product),
-                                     type_or_constraint_repr(self.dep_product),
-                                     field_name_portion,
-                                     field_types_portion)
+    return '{}({}, {}{}{}{}{})'.format(type(self).__name__,
+                                         type_or_constraint_repr(self.product),
+                                         type_or_constraint_repr(self.dep_product),
+                                         field_name_portion,
+                                         field_types_portion,
+                                         ', skip_missing=True' if self.skip_missing else '',
+                                         '' if self.skip_self else ', skip_self=False')
 
 
 class SelectTransitive(datatype('Transitive', ['product', 'dep_product', 'field', 'field_types']),
@@ -147,21 +225,43 @@ class SelectTransitive(datatype('Transitive', ['product', 'dep_product', 'field'
     return super(SelectTransitive, cls).__new__(cls, product, dep_product, field, field_types)
 
 
-class SelectProjection(datatype('Projection', ['product', 'projected_subject', 'field', 'input_product']), Selector):
+class SelectProjection(datatype('Projection', ['product', 'projected_subject', 'field', 'input_product',
+                                               'skip_missing']),
+                       Selector):
   """Selects a field of the given Subject to produce a Subject, Product dependency from.
 
   Projecting an input allows for deduplication in the graph, where multiple Subjects
   resolve to a single backing Subject instead.
 
   For convenience, if a single field is requested and it is of the requested type, the field value
-  is projected directly rather than attempting to use it to construct the projected type.
+  is projected directly rather than attempting to use it to construct the projected type. If a
+  tuple of fields is requested, their values are passed to the constructor of the projected type.
+
+  If skip_missing=True, a projected subject which cannot provide the product causes the selection
+  to be missing (like a Select) rather than failing.
+
+  If a tuple of input products is given, they are attempted in order, and the field(s) are
+  projected from the first of them which can be computed for the Subject.
   """
   optional = False
 
-  def __new__(cls, product, projected_subject, field, input_product):
-    if not isinstance(field, six.string_types):
-      raise ValueError('Expected `field` to be a string, but was: {!r}'.format(field))
-    return super(SelectProjection, cls).__new__(cls, product, projected_subject, field, input_product)
+  def __new__(cls, product, projected_subject, field, input_product, skip_missing=False):
+    fields = field if isinstance(field, tuple) else (field,)
+    if not fields or not all(isinstance(f, six.string_types) for f in fields):
+      raise ValueError('Expected `field` to be a string or a non-empty tuple of strings, but '
+                       'was: {!r}'.format(field))
+    return super(SelectProjection, cls).__new__(cls, product, projected_subject, field, input_product,
+                                                skip_missing)
+
+  @property
+  def fields(self):
+    """The field(s) to project, as a tuple."""
+    return self.field if isinstance(self.field, tuple) else (self.field,)
+
+  @property
+  def input_products(self):
+    """The alternative input products, as a tuple."""
+    return self.input_product if isinstance(self.input_product, tuple) else (self.input_product,)
 
   @property
   def input_product_selector(self):
@@ -172,9 +272,14 @@ class SelectProjection(datatype('Projection', ['product', 'projected_subject', '
     return Select(self.product)
 
   def __repr__(self):
-    return '{}({}, {}, {}, {})'.format(type(self).__name__,
-                                       type_or_constraint_repr(self.product),
-                                       self.projected_subject.__name__,
-                                       repr(self.field),
-                                       getattr(
-                                         self.input_product, '__name__', repr(self.input_product)))
+    input_product_reprs = [getattr(p, '__name__', repr(p)) for p in self.input_products]
+    if isinstance(self.input_product, tuple):
+      input_product_portion = '({})'.format(', '.join(input_product_reprs))
+    else:
+      input_product_portion = input_product_reprs[0]
+    return '{}({}, {}, {}, {}{})'.format(type(self).__name__,
+                                         type_or_constraint_repr(self.product),
+                                         self.projected_subject.__name__,
+                                         repr(self.field),
+                                         input_product_portion,
+                                         ', skip_missing=True' if self.skip_missing else '')
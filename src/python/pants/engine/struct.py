@@ -331,7 +331,20 @@ class Variants(Struct):
 
   def __init__(self, default=None, **kwargs):
     """
-    :param dict default: A dict of default variant values.
+    :param dict default: A dict of default variant values. A value may also be a list of
+      acceptable values, in priority order.
     """
     # TODO: enforce the type of variants using the Addressable framework.
     super(Variants, self).__init__(default=default, **kwargs)
+
+  @property
+  def default_pairs(self):
+    """The default variant values as a flat tuple of alternating keys and values.
+
+    A key configured with a list of values appears once per value, in priority order.
+    """
+    pairs = []
+    for key, value in sorted((self.default or {}).items()):
+      for v in (value if isinstance(value, (list, tuple)) else [value]):
+        pairs.extend((key, v))
+    return tuple(pairs)
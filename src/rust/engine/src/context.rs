@@ -97,10 +97,32 @@ impl Context {
   /// Get the future value for the given Node implementation.
   ///
   pub fn get<N: Node>(&self, node: N) -> NodeFuture<N::Output> {
+    self.get_with_id(node).1
+  }
+
+  ///
+  /// Like `get`, but additionally returns the EntryId of the given Node.
+  ///
+  pub fn get_with_id<N: Node>(&self, node: N) -> (EntryId, NodeFuture<N::Output>) {
     // TODO: Odd place for this... could do it periodically in the background?
     maybe_drain_handles().map(|handles| { externs::drop_handles(handles); });
     self.core.graph.get(self.entry_id, self, node)
   }
+
+  ///
+  /// Records that the Node that this Context was created for selected the value produced by the
+  /// given dependency.
+  ///
+  pub fn record_producer(&self, producer_id: EntryId) {
+    self.core.graph.record_producer(self.entry_id, producer_id)
+  }
+
+  ///
+  /// Records a diagnostic for the Node that this Context was created for.
+  ///
+  pub fn record_diagnostic(&self, diagnostic: String) {
+    self.core.graph.record_diagnostic(self.entry_id, diagnostic)
+  }
 }
 
 pub trait ContextFactory {
@@ -3,7 +3,6 @@
 
 use fnv::FnvHasher;
 
-use std::collections::HashMap;
 use std::{fmt, hash};
 use std::ops::Drop;
 
@@ -13,8 +12,8 @@ use handles::{Handle, enqueue_drop_handle};
 pub type FNV = hash::BuildHasherDefault<FnvHasher>;
 
 ///
-/// Variants represent a string->string map. For hashability purposes, they're stored
-/// as sorted string tuples.
+/// Variants represent a string->string map, in which a key may have multiple values (in priority
+/// order). For hashability purposes, they're stored as string tuples sorted by key.
 ///
 #[repr(C)]
 #[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
@@ -22,21 +21,32 @@ pub struct Variants(pub Vec<(String, String)>);
 
 impl Variants {
   ///
-  /// Merges right over self (by key, and then sorted by key).
+  /// Creates Variants from the given pairs, which are sorted by key while preserving the priority
+  /// order of the values for each key.
   ///
-  /// TODO: Unused: see https://github.com/pantsbuild/pants/issues/4020
+  pub fn new(mut pairs: Vec<(String, String)>) -> Variants {
+    pairs.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+    Variants(pairs)
+  }
+
+  ///
+  /// Merges right over self (by key, and then sorted by key): when both sides configure a key,
+  /// the value(s) from right win.
   ///
-  #[allow(dead_code)]
   pub fn merge(&self, right: Variants) -> Variants {
-    // Merge.
-    let mut left: HashMap<_, _, FNV> = self.0.iter().cloned().collect();
-    left.extend(right.0);
-    // Convert back to a vector and sort.
-    let mut result: Vec<(String, String)> = left.into_iter().collect();
-    result.sort();
-    Variants(result)
+    let mut result: Vec<(String, String)> = self
+      .0
+      .iter()
+      .filter(|&&(ref k, _)| !right.0.iter().any(|&(ref rk, _)| rk == k))
+      .cloned()
+      .collect();
+    result.extend(right.0);
+    Variants::new(result)
   }
 
+  ///
+  /// Returns the highest priority value configured for the given key.
+  ///
   pub fn find(&self, key: &String) -> Option<&str> {
     self.0.iter().find(|&&(ref k, _)| k == key).map(
       |&(_, ref v)| {
@@ -44,6 +54,18 @@ impl Variants {
       },
     )
   }
+
+  ///
+  /// Returns all values configured for the given key, in priority order.
+  ///
+  pub fn find_all(&self, key: &String) -> Vec<&str> {
+    self
+      .0
+      .iter()
+      .filter(|&&(ref k, _)| k == key)
+      .map(|&(_, ref v)| v.as_str())
+      .collect()
+  }
 }
 
 pub type Id = u64;
@@ -138,6 +160,20 @@ impl Value {
   pub unsafe fn clone_without_handle(&self) -> Value {
     Value(self.0)
   }
+
+  ///
+  /// Wraps a Handle that was not provided by python: used by `mock_externs` to represent its
+  /// objects.
+  ///
+  #[cfg(test)]
+  pub fn from_handle(handle: Handle) -> Value {
+    Value(handle)
+  }
+
+  #[cfg(test)]
+  pub fn handle(&self) -> Handle {
+    self.0
+  }
 }
 
 ///
@@ -162,26 +198,112 @@ pub enum Failure {
   Invalidated,
   /// There was no valid combination of rules to satisfy a request.
   Noop(Noop),
-  /// A rule raised an exception.
-  Throw(Value, String),
+  /// A rule raised an exception. Records the exception, its python traceback, and descriptions
+  /// of the Nodes that the exception propagated through (innermost first).
+  Throw(Value, String, Vec<String>),
+}
+
+impl Failure {
+  ///
+  /// If this Failure is a Throw, records that it propagated through the Node described by `frame`.
+  ///
+  pub fn with_frame<F: FnOnce() -> String>(self, frame: F) -> Failure {
+    match self {
+      Failure::Throw(exc, traceback, mut path) => {
+        path.push(frame());
+        Failure::Throw(exc, traceback, path)
+      }
+      f => f,
+    }
+  }
+
+  ///
+  /// Renders a Throw, its traceback, and the Nodes that it propagated through as a
+  /// "computing X because of Y" stack.
+  ///
+  pub fn render_trace(&self) -> String {
+    match self {
+      &Failure::Throw(ref exc, ref traceback, ref path) => {
+        let mut lines = vec![format!("Throw({})", externs::val_to_str(exc))];
+        lines.extend(traceback.split("\n").map(|l| format!("  {}", l)));
+        lines.extend(path.iter().enumerate().map(|(i, frame)| if i == 0 {
+          format!("  computing {}", frame)
+        } else {
+          format!("    because of {}", frame)
+        }));
+        lines.join("\n")
+      }
+      f => format!("{:?}", f),
+    }
+  }
 }
 
 // NB: enum members are listed in ascending priority order based on how likely they are
 // to be useful to users.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Noop {
-  NoTask,
-  NoVariant,
+  // The candidates that were attempted, and the reasons that they did not produce a value.
+  NoTask(Vec<(String, Noop)>),
+  // The clauses of a Task (by index, and described) that could not be satisfied, and why.
+  MissingInputs(Vec<(usize, String, Noop)>),
+  // The variant key that was not configured.
+  NoVariant(String),
   Cycle,
 }
 
+impl Noop {
+  ///
+  /// Follows the highest priority nested reason down to the Noop that originally caused this one.
+  ///
+  pub fn root_cause(&self) -> &Noop {
+    let nested = match self {
+      &Noop::NoTask(ref attempted) => attempted.iter().map(|&(_, ref noop)| noop).max(),
+      &Noop::MissingInputs(ref missing) => missing.iter().map(|&(_, _, ref noop)| noop).max(),
+      &Noop::NoVariant(_) |
+      &Noop::Cycle => None,
+    };
+    nested.map(|noop| noop.root_cause()).unwrap_or(self)
+  }
+}
+
 impl fmt::Debug for Noop {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    f.write_str(match self {
-      &Noop::Cycle => "Dep graph contained a cycle.",
-      &Noop::NoTask => "No task was available to compute the value.",
-      &Noop::NoVariant => "A matching variant key was not configured in variants.",
-    })
+    match self {
+      &Noop::Cycle => f.write_str("Dep graph contained a cycle."),
+      &Noop::NoTask(ref attempted) => {
+        f.write_str("No task was available to compute the value.")?;
+        for &(ref candidate, ref noop) in attempted {
+          // Indent the reasons of nested candidates beneath their parent.
+          write!(
+            f,
+            "\n  {}: {}",
+            candidate,
+            format!("{:?}", noop).replace("\n", "\n  ")
+          )?;
+        }
+        Ok(())
+      }
+      &Noop::MissingInputs(ref missing) => {
+        f.write_str("Was missing inputs for the task.")?;
+        for &(ref index, ref selector, ref noop) in missing {
+          write!(
+            f,
+            "\n  clause {} ({}): {}",
+            index,
+            selector,
+            format!("{:?}", noop).replace("\n", "\n  ")
+          )?;
+        }
+        Ok(())
+      }
+      &Noop::NoVariant(ref key) => {
+        write!(
+          f,
+          "A matching variant key `{}` was not configured in variants.",
+          key
+        )
+      }
+    }
   }
 }
 
@@ -192,5 +314,53 @@ pub fn throw(msg: &str) -> Failure {
       "Traceback (no traceback):\n  <pants native internals>\nException: {}",
       msg
     ).to_string(),
+    Vec::new(),
   )
 }
+
+#[cfg(test)]
+mod tests {
+  use super::Variants;
+
+  fn variants(pairs: &[(&str, &str)]) -> Variants {
+    Variants::new(
+      pairs
+        .iter()
+        .map(|&(k, v)| (k.to_string(), v.to_string()))
+        .collect(),
+    )
+  }
+
+  #[test]
+  fn merge_right_wins_for_conflicting_keys() {
+    let left = variants(&[("a", "left"), ("b", "left1"), ("b", "left2")]);
+    let right = variants(&[("b", "right"), ("c", "right")]);
+
+    assert_eq!(
+      variants(&[("a", "left"), ("b", "right"), ("c", "right")]),
+      left.merge(right)
+    );
+  }
+
+  #[test]
+  fn merge_preserves_priority_of_right_values() {
+    let left = variants(&[("a", "left")]);
+    let right = variants(&[("a", "right1"), ("a", "right2")]);
+
+    let merged = left.merge(right);
+    assert_eq!(vec!["right1", "right2"], merged.find_all(&"a".to_string()));
+    assert_eq!(Some("right1"), merged.find(&"a".to_string()));
+  }
+
+  #[test]
+  fn merge_empty() {
+    let configured = variants(&[("a", "1"), ("b", "2")]);
+
+    assert_eq!(configured, configured.merge(Variants::default()));
+    assert_eq!(configured, Variants::default().merge(configured.clone()));
+    assert_eq!(
+      Variants::default(),
+      Variants::default().merge(Variants::default())
+    );
+  }
+}
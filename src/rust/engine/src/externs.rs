@@ -1,31 +1,32 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::mem;
 use std::os::raw;
 use std::os::unix::ffi::OsStringExt;
-use std::string::FromUtf8Error;
+use std::slice;
 use std::sync::RwLock;
 
-use core::{Failure, Function, Key, TypeConstraint, TypeId, Value};
+use core::{Failure, Field, FNV, Function, Key, TypeConstraint, TypeId, Value};
 use handles::Handle;
 use interning::Interns;
 use log;
+use tasks::TaskOptions;
 
 
 pub fn eval(python: &str) -> Result<Value, Failure> {
-  with_externs(|e| {
-    (e.eval)(e.context, python.as_ptr(), python.len() as u64)
-  }).into()
+  let result = with_externs(|e| e.eval(python));
+  to_failure(result)
 }
 
 pub fn identify(val: &Value) -> Ident {
-  with_externs(|e| (e.identify)(e.context, val))
+  with_externs(|e| e.identify(val))
 }
 
 pub fn equals(val1: &Value, val2: &Value) -> bool {
-  with_externs(|e| (e.equals)(e.context, val1, val2))
+  with_externs(|e| e.equals(val1, val2))
 }
 
 pub fn key_for(val: Value) -> Key {
@@ -39,73 +40,128 @@ pub fn val_for(key: &Key) -> Value {
 }
 
 pub fn clone_val(val: &Value) -> Value {
-  with_externs(|e| (e.clone_val)(e.context, val))
+  with_externs(|e| e.clone_val(val))
 }
 
 pub fn drop_handles(handles: Vec<Handle>) {
-  with_externs(|e| {
-    (e.drop_handles)(e.context, handles.as_ptr(), handles.len() as u64)
-  })
+  with_externs(|e| e.drop_handles(&handles))
 }
 
 pub fn satisfied_by(constraint: &TypeConstraint, obj: &Value) -> bool {
   let interns = INTERNS.read().unwrap();
-  with_externs(|e| {
-    (e.satisfied_by)(e.context, interns.get(&constraint.0), obj)
-  })
+  with_externs(|e| e.satisfied_by(interns.get(&constraint.0), obj))
 }
 
 pub fn satisfied_by_type(constraint: &TypeConstraint, cls: &TypeId) -> bool {
+  satisfied_by_types(&[(*constraint, *cls)])[0]
+}
+
+///
+/// Returns whether each of the given types satisfies the TypeConstraint it is paired with.
+///
+/// Since types are immutable the results are cached, and the pairs that were not already cached
+/// are checked in a single call into python.
+///
+pub fn satisfied_by_types(checks: &[(TypeConstraint, TypeId)]) -> Vec<bool> {
+  let mut results: Vec<Option<bool>> = {
+    let cache = SATISFIED_BY_TYPE.read().unwrap();
+    checks.iter().map(|check| cache.get(check).cloned()).collect()
+  };
+  let misses: Vec<usize> = (0..checks.len()).filter(|&i| results[i].is_none()).collect();
+  if misses.is_empty() {
+    return results.into_iter().map(|r| r.unwrap()).collect();
+  }
+
+  let satisfied = {
+    let interns = INTERNS.read().unwrap();
+    if misses.len() == 1 {
+      let (constraint, cls) = checks[misses[0]];
+      vec![
+        with_externs(|e| e.satisfied_by_type(interns.get(&constraint.0), &cls)),
+      ]
+    } else {
+      let queries: Vec<(&Value, TypeId)> = misses
+        .iter()
+        .map(|&i| {
+          let (constraint, cls) = checks[i];
+          (interns.get(&constraint.0), cls)
+        })
+        .collect();
+      with_externs(|e| e.satisfied_by_type_multi(&queries))
+    }
+  };
+  let mut cache = SATISFIED_BY_TYPE.write().unwrap();
+  for (&i, is_satisfied) in misses.iter().zip(satisfied) {
+    cache.insert(checks[i], is_satisfied);
+    results[i] = Some(is_satisfied);
+  }
+  results.into_iter().map(|r| r.unwrap()).collect()
+}
+
+///
+/// Returns the TypeIds of the types that the given constraint was declared with (for an
+/// `Exactly` constraint, the types that satisfy it).
+///
+pub fn constraint_types(constraint: &TypeConstraint) -> Result<Vec<TypeId>, String> {
   let interns = INTERNS.read().unwrap();
-  with_externs(|e| {
-    (e.satisfied_by_type)(e.context, interns.get(&constraint.0), cls)
-  })
+  with_externs(|e| e.project_type_ids(interns.get(&constraint.0), "types"))
 }
 
 pub fn store_list(values: Vec<&Value>, merge: bool) -> Value {
-  with_externs(|e| {
-    let values_clone: Vec<*const Value> = values.into_iter().map(|v| v as *const Value).collect();
-    (e.store_list)(
-      e.context,
-      values_clone.as_ptr(),
-      values_clone.len() as u64,
-      merge,
-    )
-  })
+  with_externs(|e| e.store_list(&values, merge))
 }
 
 pub fn store_bytes(bytes: &[u8]) -> Value {
-  with_externs(|e| {
-    (e.store_bytes)(e.context, bytes.as_ptr(), bytes.len() as u64)
-  })
+  with_externs(|e| e.store_bytes(bytes))
 }
 
 pub fn store_i32(val: i32) -> Value {
-  with_externs(|e| (e.store_i32)(e.context, val))
+  with_externs(|e| e.store_i32(val))
 }
 
 pub fn project(value: &Value, field: &str, type_id: &TypeId) -> Value {
-  with_externs(|e| {
-    (e.project)(
-      e.context,
-      value,
-      field.as_ptr(),
-      field.len() as u64,
-      type_id,
-    )
-  })
+  with_externs(|e| e.project(value, field, type_id))
 }
 
 pub fn project_ignoring_type(value: &Value, field: &str) -> Value {
-  with_externs(|e| {
-    (e.project_ignoring_type)(e.context, value, field.as_ptr(), field.len() as u64)
-  })
+  with_externs(|e| e.project_ignoring_type(value, field))
 }
 
 pub fn project_multi(value: &Value, field: &str) -> Vec<Value> {
-  with_externs(|e| {
-    (e.project_multi)(e.context, value, field.as_ptr(), field.len() as u64).to_vec()
-  })
+  with_externs(|e| e.project_multi(value, field))
+}
+
+///
+/// Projects the given field of the value for the given Key as the given type, and returns a Key
+/// for the result, or None if the value does not have the field. Keys represent immutable values,
+/// so projections (including missing fields) are cached, up to MAX_PROJECTIONS of them.
+///
+pub fn project_key(key: &Key, field: &str, type_id: &TypeId) -> Option<Key> {
+  let projection = (*key, field.to_string(), *type_id);
+  if let Some(projected) = PROJECTIONS.read().unwrap().get(&projection) {
+    return *projected;
+  }
+  let value = val_for(key);
+  let projected = if has_field(&value, field) {
+    Some(key_for(project(&value, field, type_id)))
+  } else {
+    None
+  };
+  let mut projections = PROJECTIONS.write().unwrap();
+  if projections.len() >= MAX_PROJECTIONS {
+    // Rather than tracking the use of each projection to evict it individually, start over.
+    projections.clear();
+  }
+  projections.insert(projection, projected);
+  projected
+}
+
+///
+/// Returns true if the given value has the given field (which may be empty), as opposed to not
+/// having the field at all.
+///
+pub fn has_field(value: &Value, field: &str) -> bool {
+  with_externs(|e| e.has_field(value, field))
 }
 
 pub fn project_multi_strs(item: &Value, field: &str) -> Vec<String> {
@@ -116,16 +172,7 @@ pub fn project_multi_strs(item: &Value, field: &str) -> Vec<String> {
 }
 
 pub fn project_str(value: &Value, field: &str) -> String {
-  let name_val = with_externs(|e| {
-    (e.project)(
-      e.context,
-      value,
-      field.as_ptr(),
-      field.len() as u64,
-      &e.py_str_type,
-    )
-  });
-  val_to_str(&name_val)
+  with_externs(|e| e.project_str(value, field))
 }
 
 pub fn key_to_str(key: &Key) -> String {
@@ -133,29 +180,31 @@ pub fn key_to_str(key: &Key) -> String {
 }
 
 pub fn type_to_str(type_id: TypeId) -> String {
-  with_externs(|e| {
-    (e.type_to_str)(e.context, type_id)
-      .to_string()
-      .unwrap_or_else(|e| {
-        format!("<failed to decode unicode for {:?}: {}>", type_id, e)
-      })
-  })
+  with_externs(|e| e.type_to_str(type_id))
 }
 
 pub fn val_to_str(val: &Value) -> String {
-  with_externs(|e| {
-    (e.val_to_str)(e.context, val).to_string().unwrap_or_else(
-      |e| {
-        format!("<failed to decode unicode for {:?}: {}>", val, e)
-      },
-    )
-  })
+  with_externs(|e| e.val_to_str(val))
+}
+
+pub fn digest(val: &Value) -> String {
+  with_externs(|e| e.digest(val))
+}
+
+pub fn function_digest(func: &Function) -> String {
+  digest(&val_for(&func.0))
 }
 
 pub fn create_exception(msg: &str) -> Value {
-  with_externs(|e| {
-    (e.create_exception)(e.context, msg.as_ptr(), msg.len() as u64)
-  })
+  with_externs(|e| e.create_exception(msg))
+}
+
+///
+/// Constructs an instance of the given type from the given arguments.
+///
+pub fn instantiate(type_id: &TypeId, args: &[Value]) -> Result<Value, Failure> {
+  let result = with_externs(|e| e.instantiate(type_id, args));
+  to_failure(result)
 }
 
 pub fn call_method(value: &Value, method: &str, args: &[Value]) -> Result<Value, Failure> {
@@ -163,9 +212,20 @@ pub fn call_method(value: &Value, method: &str, args: &[Value]) -> Result<Value,
 }
 
 pub fn call(func: &Value, args: &[Value]) -> Result<Value, Failure> {
-  with_externs(|e| {
-    (e.call)(e.context, func, args.as_ptr(), args.len() as u64)
-  }).into()
+  call_with_timeout(func, args, None)
+}
+
+///
+/// Calls the given function with a budget (if any) for the call, which the Externs are
+/// responsible for enforcing.
+///
+pub fn call_with_timeout(
+  func: &Value,
+  args: &[Value],
+  timeout_ms: Option<u64>,
+) -> Result<Value, Failure> {
+  let result = with_externs(|e| e.call(func, args, timeout_ms));
+  to_failure(result)
 }
 
 ///
@@ -180,13 +240,105 @@ pub fn unsafe_call(func: &Function, args: &[Value]) -> Value {
   })
 }
 
+///
+/// Converts the raised exception (if any) of an extern call into a Failure. Must be called
+/// outside of `with_externs`, because rendering the traceback calls back into the Externs.
+///
+fn to_failure(result: Result<Value, Value>) -> Result<Value, Failure> {
+  result.map_err(|exc| {
+    let traceback = project_str(&exc, "_formatted_exc");
+    Failure::Throw(exc, traceback, Vec::new())
+  })
+}
+
+///
+/// The operations on python objects that the engine calls back for. In production these are
+/// implemented by `FfiExterns`, which calls into python via cffi, but tests may install an
+/// implementation (see `mock_externs::MockExterns`) that does not require python at all.
+///
+/// Methods that may raise return the raised exception as the Err value.
+///
+pub trait Externs: Send + Sync {
+  fn log(&self, level: u8, msg: &str);
+
+  ///
+  /// Calls the given function. If a timeout_ms is given, a call that does not complete within
+  /// that budget should raise rather than block the caller.
+  ///
+  fn call(&self, func: &Value, args: &[Value], timeout_ms: Option<u64>) -> Result<Value, Value>;
+
+  fn eval(&self, python: &str) -> Result<Value, Value>;
+
+  fn identify(&self, val: &Value) -> Ident;
+
+  fn equals(&self, val1: &Value, val2: &Value) -> bool;
+
+  fn clone_val(&self, val: &Value) -> Value;
+
+  fn drop_handles(&self, handles: &[Handle]);
+
+  fn satisfied_by(&self, constraint: &Value, obj: &Value) -> bool;
+
+  fn satisfied_by_type(&self, constraint: &Value, cls: &TypeId) -> bool;
+
+  ///
+  /// Checks each of the given pairs as `satisfied_by_type` would, in a single call.
+  ///
+  fn satisfied_by_type_multi(&self, queries: &[(&Value, TypeId)]) -> Vec<bool>;
+
+  fn store_list(&self, values: &[&Value], merge: bool) -> Value;
+
+  fn store_bytes(&self, bytes: &[u8]) -> Value;
+
+  fn store_i32(&self, val: i32) -> Value;
+
+  ///
+  /// Returns true if the value has the given field.
+  ///
+  fn has_field(&self, value: &Value, field: &str) -> bool;
+
+  fn project(&self, value: &Value, field: &str, type_id: &TypeId) -> Value;
+
+  fn project_ignoring_type(&self, value: &Value, field: &str) -> Value;
+
+  fn project_multi(&self, value: &Value, field: &str) -> Vec<Value>;
+
+  ///
+  /// Projects the given field of the value as a list of types, and returns their TypeIds.
+  ///
+  fn project_type_ids(&self, value: &Value, field: &str) -> Result<Vec<TypeId>, String>;
+
+  ///
+  /// Projects the given field of the value as a string, and renders it.
+  ///
+  fn project_str(&self, value: &Value, field: &str) -> String;
+
+  fn instantiate(&self, type_id: &TypeId, args: &[Value]) -> Result<Value, Value>;
+
+  fn type_to_str(&self, type_id: TypeId) -> String;
+
+  fn val_to_str(&self, val: &Value) -> String;
+
+  ///
+  /// Returns a digest of the content of the given value (for a function, its module, qualified
+  /// name and code) which, unlike the Key or the repr of the value, is stable between runs.
+  ///
+  fn digest(&self, val: &Value) -> String;
+
+  fn create_exception(&self, msg: &str) -> Value;
+}
+
 /////////////////////////////////////////////////////////////////////////////////////////
 /// The remainder of this file deals with the static initialization of the Externs.
 /////////////////////////////////////////////////////////////////////////////////////////
 
 lazy_static! {
-  static ref EXTERNS: RwLock<Option<Externs>> = RwLock::new(None);
+  static ref EXTERNS: RwLock<Option<Box<Externs>>> = RwLock::new(None);
   static ref INTERNS: RwLock<Interns> = RwLock::new(Interns::new());
+  static ref SATISFIED_BY_TYPE: RwLock<HashMap<(TypeConstraint, TypeId), bool, FNV>> =
+    RwLock::new(HashMap::default());
+  static ref PROJECTIONS: RwLock<HashMap<(Key, Field, TypeId), Option<Key>, FNV>> =
+    RwLock::new(HashMap::default());
   static ref LOGGER: FfiLogger = FfiLogger{};
 }
 
@@ -194,9 +346,14 @@ lazy_static! {
 /// Set the static Externs for this process. All other methods of this module will fail
 /// until this has been called.
 ///
-pub fn set_externs(externs: Externs) {
+/// The cached results of type checks and of projections refer to the TypeIds and Keys of the
+/// previous Externs, so they are cleared.
+///
+pub fn set_externs(externs: Box<Externs>) {
   let mut externs_ref = EXTERNS.write().unwrap();
   *externs_ref = Some(externs);
+  SATISFIED_BY_TYPE.write().unwrap().clear();
+  PROJECTIONS.write().unwrap().clear();
   LOGGER.init();
 }
 
@@ -208,13 +365,17 @@ where
   let externs = externs_opt.as_ref().unwrap_or_else(|| {
     panic!("externs used before static initialization.")
   });
-  f(externs)
+  f(externs.as_ref())
 }
 
 // An opaque pointer to a context used by the extern functions.
 pub type ExternContext = raw::c_void;
 
-pub struct Externs {
+///
+/// The Externs of a pants process: function pointers (and an opaque context) provided by python
+/// via cffi.
+///
+pub struct FfiExterns {
   context: *const ExternContext,
   log: LogExtern,
   call: CallExtern,
@@ -225,24 +386,29 @@ pub struct Externs {
   drop_handles: DropHandlesExtern,
   satisfied_by: SatisfiedByExtern,
   satisfied_by_type: SatisfiedByTypeExtern,
+  satisfied_by_type_multi: SatisfiedByTypeMultiExtern,
   store_list: StoreListExtern,
   store_bytes: StoreBytesExtern,
   store_i32: StoreI32Extern,
+  has_field: HasFieldExtern,
   project: ProjectExtern,
   project_ignoring_type: ProjectIgnoringTypeExtern,
   project_multi: ProjectMultiExtern,
+  project_type_ids: ProjectTypeIdsExtern,
+  instantiate: InstantiateExtern,
   type_to_str: TypeToStrExtern,
   val_to_str: ValToStrExtern,
+  digest: DigestExtern,
   create_exception: CreateExceptionExtern,
   // TODO: This type is also declared on `types::Types`.
   py_str_type: TypeId,
 }
 
 // The pointer to the context is safe for sharing between threads.
-unsafe impl Sync for Externs {}
-unsafe impl Send for Externs {}
+unsafe impl Sync for FfiExterns {}
+unsafe impl Send for FfiExterns {}
 
-impl Externs {
+impl FfiExterns {
   pub fn new(
     ext_context: *const ExternContext,
     log: LogExtern,
@@ -256,16 +422,21 @@ impl Externs {
     val_to_str: ValToStrExtern,
     satisfied_by: SatisfiedByExtern,
     satisfied_by_type: SatisfiedByTypeExtern,
+    satisfied_by_type_multi: SatisfiedByTypeMultiExtern,
     store_list: StoreListExtern,
     store_bytes: StoreBytesExtern,
     store_i32: StoreI32Extern,
+    has_field: HasFieldExtern,
     project: ProjectExtern,
     project_ignoring_type: ProjectIgnoringTypeExtern,
     project_multi: ProjectMultiExtern,
+    project_type_ids: ProjectTypeIdsExtern,
+    instantiate: InstantiateExtern,
+    digest: DigestExtern,
     create_exception: CreateExceptionExtern,
     py_str_type: TypeId,
-  ) -> Externs {
-    Externs {
+  ) -> FfiExterns {
+    FfiExterns {
       context: ext_context,
       log: log,
       call: call,
@@ -276,20 +447,180 @@ impl Externs {
       drop_handles: drop_handles,
       satisfied_by: satisfied_by,
       satisfied_by_type: satisfied_by_type,
+      satisfied_by_type_multi: satisfied_by_type_multi,
       store_list: store_list,
       store_bytes: store_bytes,
       store_i32: store_i32,
+      has_field: has_field,
       project: project,
       project_ignoring_type: project_ignoring_type,
       project_multi: project_multi,
+      project_type_ids: project_type_ids,
+      instantiate: instantiate,
       type_to_str: type_to_str,
       val_to_str: val_to_str,
+      digest: digest,
       create_exception: create_exception,
       py_str_type: py_str_type,
     }
   }
 }
 
+impl Externs for FfiExterns {
+  fn log(&self, level: u8, msg: &str) {
+    (self.log)(self.context, level, msg.as_ptr(), msg.len() as u64)
+  }
+
+  fn call(&self, func: &Value, args: &[Value], timeout_ms: Option<u64>) -> Result<Value, Value> {
+    // NB: A timeout_ms of 0 indicates that the call has no budget.
+    (self.call)(
+      self.context,
+      func,
+      args.as_ptr(),
+      args.len() as u64,
+      timeout_ms.unwrap_or(0),
+    ).into()
+  }
+
+  fn eval(&self, python: &str) -> Result<Value, Value> {
+    (self.eval)(self.context, python.as_ptr(), python.len() as u64).into()
+  }
+
+  fn identify(&self, val: &Value) -> Ident {
+    (self.identify)(self.context, val)
+  }
+
+  fn equals(&self, val1: &Value, val2: &Value) -> bool {
+    (self.equals)(self.context, val1, val2)
+  }
+
+  fn clone_val(&self, val: &Value) -> Value {
+    (self.clone_val)(self.context, val)
+  }
+
+  fn drop_handles(&self, handles: &[Handle]) {
+    (self.drop_handles)(self.context, handles.as_ptr(), handles.len() as u64)
+  }
+
+  fn satisfied_by(&self, constraint: &Value, obj: &Value) -> bool {
+    (self.satisfied_by)(self.context, constraint, obj)
+  }
+
+  fn satisfied_by_type(&self, constraint: &Value, cls: &TypeId) -> bool {
+    (self.satisfied_by_type)(self.context, constraint, cls)
+  }
+
+  fn satisfied_by_type_multi(&self, queries: &[(&Value, TypeId)]) -> Vec<bool> {
+    let queries: Vec<SatisfiedByTypeQuery> = queries
+      .iter()
+      .map(|&(constraint, type_id)| {
+        SatisfiedByTypeQuery {
+          constraint: constraint as *const Value,
+          type_id: type_id,
+        }
+      })
+      .collect();
+    let satisfied =
+      (self.satisfied_by_type_multi)(self.context, queries.as_ptr(), queries.len() as u64)
+        .to_bytes();
+    match satisfied {
+      Ok(ref bytes) if bytes.len() == queries.len() => {
+        bytes.iter().map(|&satisfied| satisfied != 0).collect()
+      }
+      // The batch could not be read back: fall back to checking the queries one at a time.
+      _ => {
+        queries
+          .iter()
+          .map(|query| {
+            (self.satisfied_by_type)(self.context, query.constraint, &query.type_id)
+          })
+          .collect()
+      }
+    }
+  }
+
+  fn store_list(&self, values: &[&Value], merge: bool) -> Value {
+    let values_clone: Vec<*const Value> = values.iter().map(|&v| v as *const Value).collect();
+    (self.store_list)(
+      self.context,
+      values_clone.as_ptr(),
+      values_clone.len() as u64,
+      merge,
+    )
+  }
+
+  fn store_bytes(&self, bytes: &[u8]) -> Value {
+    (self.store_bytes)(self.context, bytes.as_ptr(), bytes.len() as u64)
+  }
+
+  fn store_i32(&self, val: i32) -> Value {
+    (self.store_i32)(self.context, val)
+  }
+
+  fn project(&self, value: &Value, field: &str, type_id: &TypeId) -> Value {
+    (self.project)(
+      self.context,
+      value,
+      field.as_ptr(),
+      field.len() as u64,
+      type_id,
+    )
+  }
+
+  fn has_field(&self, value: &Value, field: &str) -> bool {
+    (self.has_field)(self.context, value, field.as_ptr(), field.len() as u64)
+  }
+
+  fn project_ignoring_type(&self, value: &Value, field: &str) -> Value {
+    (self.project_ignoring_type)(self.context, value, field.as_ptr(), field.len() as u64)
+  }
+
+  fn project_multi(&self, value: &Value, field: &str) -> Vec<Value> {
+    // A buffer with a corrupted length is treated as empty rather than trusted.
+    (self.project_multi)(self.context, value, field.as_ptr(), field.len() as u64)
+      .into_vec()
+      .unwrap_or_else(|_| Vec::new())
+  }
+
+  fn project_type_ids(&self, value: &Value, field: &str) -> Result<Vec<TypeId>, String> {
+    (self.project_type_ids)(self.context, value, field.as_ptr(), field.len() as u64).to_vec()
+  }
+
+  fn project_str(&self, value: &Value, field: &str) -> String {
+    let name_val = self.project(value, field, &self.py_str_type);
+    self.val_to_str(&name_val)
+  }
+
+  fn instantiate(&self, type_id: &TypeId, args: &[Value]) -> Result<Value, Value> {
+    (self.instantiate)(self.context, type_id, args.as_ptr(), args.len() as u64).into()
+  }
+
+  fn type_to_str(&self, type_id: TypeId) -> String {
+    (self.type_to_str)(self.context, type_id)
+      .to_string()
+      .unwrap_or_else(|e| {
+        format!("<failed to decode {:?}: {}>", type_id, e)
+      })
+  }
+
+  fn val_to_str(&self, val: &Value) -> String {
+    // NB: The Debug impl of Value calls back into `val_to_str`, so it may not be used here.
+    (self.val_to_str)(self.context, val).to_string().unwrap_or_else(
+      |e| format!("<failed to decode value: {}>", e),
+    )
+  }
+
+  fn digest(&self, val: &Value) -> String {
+    (self.digest)(self.context, val)
+      .to_string()
+      .unwrap_or_else(|e| format!("<failed to decode digest: {}>", e))
+  }
+
+  fn create_exception(&self, msg: &str) -> Value {
+    (self.create_exception)(self.context, msg.as_ptr(), msg.len() as u64)
+  }
+}
+
 pub type LogExtern = extern "C" fn(*const ExternContext, u8, str_ptr: *const u8, str_len: u64);
 
 // TODO: Type alias used to avoid rustfmt breaking itself by rendering a 101 character line.
@@ -300,6 +631,19 @@ pub type SatisfiedByExtern = extern "C" fn(*const ExternContext, *const Value, *
 pub type SatisfiedByTypeExtern = extern "C" fn(*const ExternContext, *const Value, *const TypeId)
                                                -> bool;
 
+// A TypeConstraint and a TypeId to check in a call to `satisfied_by_type_multi`.
+#[repr(C)]
+pub struct SatisfiedByTypeQuery {
+  constraint: *const Value,
+  type_id: TypeId,
+}
+
+// Returns a Buffer containing one byte per query, which is non-zero if the query was satisfied.
+pub type SatisfiedByTypeMultiExtern = extern "C" fn(*const ExternContext,
+                                                    *const SatisfiedByTypeQuery,
+                                                    u64)
+                                                    -> Buffer;
+
 pub type IdentifyExtern = extern "C" fn(*const ExternContext, *const Value) -> Ident;
 
 pub type EqualsExtern = extern "C" fn(*const ExternContext, *const Value, *const Value) -> bool;
@@ -329,17 +673,28 @@ pub struct PyResult {
   value: Value,
 }
 
-impl From<PyResult> for Result<Value, Failure> {
+impl From<PyResult> for Result<Value, Value> {
   fn from(result: PyResult) -> Self {
     if result.is_throw {
-      let traceback = project_str(&result.value, "_formatted_exc");
-      Err(Failure::Throw(result.value, traceback))
+      Err(result.value)
     } else {
       Ok(result.value)
     }
   }
 }
 
+impl From<Result<Value, String>> for PyResult {
+  fn from(res: Result<Value, String>) -> Self {
+    match res {
+      Ok(value) => PyResult { is_throw: false, value: value },
+      Err(msg) => PyResult {
+        is_throw: true,
+        value: create_exception(&msg),
+      },
+    }
+  }
+}
+
 impl From<Result<(), String>> for PyResult {
   fn from(res: Result<(), String>) -> Self {
     match res {
@@ -360,7 +715,16 @@ pub struct Ident {
   pub type_id: TypeId,
 }
 
-// Points to an array containing a series of values allocated by Python.
+// The *Buffer structs below point to arrays allocated by Python, which Rust must not free.
+// Instead, each Buffer holds a `handle_` Value that keeps its array alive on the Python side:
+// like any other Value, the handle is released (via `drop_handles`) once the Buffer is dropped.
+// Contents must therefore be copied out of a Buffer (as `to_vec` and friends do) before it is
+// dropped. Because `with_vec` only borrows the array as a slice, Rust never takes ownership of
+// it: an invalid Buffer is reported as an error, and the Buffer is then dropped as usual.
+
+// Points to an array containing a series of values allocated by Python. Unlike the other
+// Buffers, the handles of the values themselves are owned by the receiver of the ValueBuffer,
+// so they may be taken only once.
 #[repr(C)]
 pub struct ValueBuffer {
   values_ptr: *mut Value,
@@ -370,11 +734,11 @@ pub struct ValueBuffer {
 }
 
 impl ValueBuffer {
-  pub fn to_vec(&self) -> Vec<Value> {
+  pub fn into_vec(self) -> Result<Vec<Value>, String> {
     with_vec(
       self.values_ptr,
       self.values_len as usize,
-      |value_vec| unsafe { value_vec.iter().map(|v| v.clone_without_handle()).collect() },
+      |values| unsafe { values.iter().map(|v| v.clone_without_handle()).collect() },
     )
   }
 }
@@ -390,8 +754,154 @@ pub struct TypeIdBuffer {
 }
 
 impl TypeIdBuffer {
-  pub fn to_vec(&self) -> Vec<TypeId> {
-    with_vec(self.ids_ptr, self.ids_len as usize, |vec| vec.clone())
+  pub fn to_vec(&self) -> Result<Vec<TypeId>, String> {
+    with_vec(self.ids_ptr, self.ids_len as usize, |slice| slice.to_vec())
+  }
+}
+
+// Points to an array of TypeConstraints.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TypeConstraintBuffer {
+  constraints_ptr: *mut TypeConstraint,
+  constraints_len: u64,
+  // handle to hold the underlying array alive
+  handle_: Value,
+}
+
+impl TypeConstraintBuffer {
+  pub fn to_vec(&self) -> Result<Vec<TypeConstraint>, String> {
+    with_vec(
+      self.constraints_ptr,
+      self.constraints_len as usize,
+      |slice| slice.to_vec(),
+    )
+  }
+}
+
+// Describes one Task in a bulk registration: its clause is made up of the next `clause_len`
+// SelectorRecords of the registration, and its name and doc (if it `has_name` or `has_doc`) are
+// the next strings of the registration, in that order.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct TaskRecord {
+  pub func: Function,
+  pub product: TypeConstraint,
+  pub cacheable: bool,
+  pub include_subject: bool,
+  pub priority: i32,
+  pub allow_empty: bool,
+  pub allow_self_select: bool,
+  pub shadow: bool,
+  pub has_timeout: bool,
+  pub timeout_ms: u64,
+  pub has_name: bool,
+  pub has_doc: bool,
+  pub clause_len: u64,
+}
+
+// The options of a Task begun incrementally: the C representation of tasks::TaskOptions, in
+// which `timeout_ms`, `name` and `doc` are only meaningful if their `has_*` flag is set.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TaskOptionsRecord {
+  pub cacheable: bool,
+  pub include_subject: bool,
+  pub priority: i32,
+  pub allow_empty: bool,
+  pub allow_self_select: bool,
+  pub shadow: bool,
+  pub has_timeout: bool,
+  pub timeout_ms: u64,
+  pub has_name: bool,
+  pub name: Buffer,
+  pub has_doc: bool,
+  pub doc: Buffer,
+}
+
+impl TaskOptionsRecord {
+  pub fn name(&self) -> Result<Option<String>, String> {
+    if self.has_name {
+      self.name.to_string().map(Some)
+    } else {
+      Ok(None)
+    }
+  }
+
+  pub fn doc(&self) -> Result<Option<String>, String> {
+    if self.has_doc {
+      self.doc.to_string().map(Some)
+    } else {
+      Ok(None)
+    }
+  }
+
+  ///
+  /// Converts to TaskOptions, given the already-decoded name and doc.
+  ///
+  pub fn to_options(&self, name: Option<String>, doc: Option<String>) -> TaskOptions {
+    TaskOptions {
+      cacheable: self.cacheable,
+      include_subject: self.include_subject,
+      priority: self.priority,
+      allow_empty: self.allow_empty,
+      allow_self_select: self.allow_self_select,
+      shadow: self.shadow,
+      timeout_ms: if self.has_timeout {
+        Some(self.timeout_ms)
+      } else {
+        None
+      },
+      name: name,
+      doc: doc,
+    }
+  }
+}
+
+// Points to an array of TaskRecords.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TaskRecordBuffer {
+  records_ptr: *mut TaskRecord,
+  records_len: u64,
+  // handle to hold the underlying array alive
+  handle_: Value,
+}
+
+impl TaskRecordBuffer {
+  pub fn to_vec(&self) -> Result<Vec<TaskRecord>, String> {
+    with_vec(self.records_ptr, self.records_len as usize, |slice| slice.to_vec())
+  }
+}
+
+// The kinds of SelectorRecord: selectors with variable-length parameters may only be registered
+// incrementally.
+pub const SELECTOR_RECORD_SELECT: u8 = 0;
+pub const SELECTOR_RECORD_SELECT_SUBJECT: u8 = 1;
+
+// Describes one Selector in a bulk registration. `optional` and `many` apply only to Selects.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct SelectorRecord {
+  pub kind: u8,
+  pub product: TypeConstraint,
+  pub optional: bool,
+  pub many: bool,
+}
+
+// Points to an array of SelectorRecords.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SelectorRecordBuffer {
+  records_ptr: *mut SelectorRecord,
+  records_len: u64,
+  // handle to hold the underlying array alive
+  handle_: Value,
+}
+
+impl SelectorRecordBuffer {
+  pub fn to_vec(&self) -> Result<Vec<SelectorRecord>, String> {
+    with_vec(self.records_ptr, self.records_len as usize, |slice| slice.to_vec())
   }
 }
 
@@ -401,12 +911,24 @@ pub type ProjectIgnoringTypeExtern = extern "C" fn(*const ExternContext,
                                                    field_name_len: u64)
                                                    -> Value;
 
+pub type HasFieldExtern = extern "C" fn(*const ExternContext,
+                                        *const Value,
+                                        field_name_ptr: *const u8,
+                                        field_name_len: u64)
+                                        -> bool;
+
 pub type ProjectMultiExtern = extern "C" fn(*const ExternContext,
                                             *const Value,
                                             field_name_ptr: *const u8,
                                             field_name_len: u64)
                                             -> ValueBuffer;
 
+pub type ProjectTypeIdsExtern = extern "C" fn(*const ExternContext,
+                                              *const Value,
+                                              field_name_ptr: *const u8,
+                                              field_name_len: u64)
+                                              -> TypeIdBuffer;
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct Buffer {
@@ -417,16 +939,18 @@ pub struct Buffer {
 }
 
 impl Buffer {
-  pub fn to_bytes(&self) -> Vec<u8> {
-    with_vec(self.bytes_ptr, self.bytes_len as usize, |vec| vec.clone())
+  pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+    with_vec(self.bytes_ptr, self.bytes_len as usize, |slice| slice.to_vec())
   }
 
-  pub fn to_os_string(&self) -> OsString {
-    OsString::from_vec(self.to_bytes())
+  pub fn to_os_string(&self) -> Result<OsString, String> {
+    self.to_bytes().map(OsString::from_vec)
   }
 
-  pub fn to_string(&self) -> Result<String, FromUtf8Error> {
-    String::from_utf8(self.to_bytes())
+  pub fn to_string(&self) -> Result<String, String> {
+    self.to_bytes().and_then(|bytes| {
+      String::from_utf8(bytes).map_err(|e| format!("{}", e))
+    })
   }
 }
 
@@ -440,26 +964,25 @@ pub struct BufferBuffer {
 }
 
 impl BufferBuffer {
-  pub fn to_bytes_vecs(&self) -> Vec<Vec<u8>> {
-    with_vec(self.bufs_ptr, self.bufs_len as usize, |vec| {
-      vec.iter().map(|b| b.to_bytes()).collect()
-    })
+  pub fn to_bytes_vecs(&self) -> Result<Vec<Vec<u8>>, String> {
+    with_vec(self.bufs_ptr, self.bufs_len as usize, |bufs| {
+      bufs.iter().map(|b| b.to_bytes()).collect()
+    }).and_then(|bytes_vecs| bytes_vecs)
   }
 
-  pub fn to_os_strings(&self) -> Vec<OsString> {
-    self
-      .to_bytes_vecs()
-      .into_iter()
-      .map(|v| OsString::from_vec(v))
-      .collect()
+  pub fn to_os_strings(&self) -> Result<Vec<OsString>, String> {
+    self.to_bytes_vecs().map(|bytes_vecs| {
+      bytes_vecs.into_iter().map(OsString::from_vec).collect()
+    })
   }
 
-  pub fn to_strings(&self) -> Result<Vec<String>, FromUtf8Error> {
-    self
-      .to_bytes_vecs()
-      .into_iter()
-      .map(|v| String::from_utf8(v))
-      .collect()
+  pub fn to_strings(&self) -> Result<Vec<String>, String> {
+    self.to_bytes_vecs().and_then(|bytes_vecs| {
+      bytes_vecs
+        .into_iter()
+        .map(|v| String::from_utf8(v).map_err(|e| format!("{}", e)))
+        .collect()
+    })
   }
 }
 
@@ -467,25 +990,70 @@ pub type TypeToStrExtern = extern "C" fn(*const ExternContext, TypeId) -> Buffer
 
 pub type ValToStrExtern = extern "C" fn(*const ExternContext, *const Value) -> Buffer;
 
+pub type DigestExtern = extern "C" fn(*const ExternContext, *const Value) -> Buffer;
+
 pub type CreateExceptionExtern = extern "C" fn(*const ExternContext,
                                                str_ptr: *const u8,
                                                str_len: u64)
                                                -> Value;
 
-pub type CallExtern = extern "C" fn(*const ExternContext, *const Value, *const Value, u64)
+pub type CallExtern = extern "C" fn(*const ExternContext,
+                                    *const Value,
+                                    *const Value,
+                                    u64,
+                                    timeout_ms: u64)
                                     -> PyResult;
 
+pub type InstantiateExtern = extern "C" fn(*const ExternContext,
+                                           *const TypeId,
+                                           *const Value,
+                                           u64)
+                                           -> PyResult;
+
 pub type EvalExtern = extern "C" fn(*const ExternContext, python_ptr: *const u8, python_len: u64)
                                     -> PyResult;
 
-pub fn with_vec<F, C, T>(c_ptr: *mut C, c_len: usize, f: F) -> T
+// The maximum number of projections cached by `project_key`.
+const MAX_PROJECTIONS: usize = 1 << 16;
+
+// The maximum number of items that will be read from a buffer provided by Python: a longer
+// buffer is assumed to have a corrupted length.
+const MAX_BUFFER_LEN: usize = 1 << 28;
+
+///
+/// Provides a view of the given buffer (which remains owned by Python) as a slice. A null pointer
+/// is accepted for an empty buffer.
+///
+/// Buffers are received by `extern "C"` functions, which must not panic: an invalid length is
+/// reported as an error instead.
+///
+pub fn with_vec<F, C, T>(c_ptr: *mut C, c_len: usize, f: F) -> Result<T, String>
 where
-  F: FnOnce(&Vec<C>) -> T,
+  F: FnOnce(&[C]) -> T,
 {
-  let cs = unsafe { Vec::from_raw_parts(c_ptr, c_len, c_len) };
-  let output = f(&cs);
-  mem::forget(cs);
-  output
+  if c_ptr.is_null() || c_len == 0 {
+    if c_len != 0 {
+      return Err(format!(
+        "Received a null pointer for a buffer of length {}.",
+        c_len
+      ));
+    }
+    return Ok(f(&[]));
+  }
+  if c_len > MAX_BUFFER_LEN {
+    return Err(format!(
+      "Received a buffer of length {}, which exceeds the maximum of {}: its length was likely \
+       corrupted.",
+      c_len,
+      MAX_BUFFER_LEN
+    ));
+  }
+  debug_assert!(
+    c_ptr as usize % mem::align_of::<C>() == 0,
+    "Received a misaligned pointer for a buffer."
+  );
+  let cs = unsafe { slice::from_raw_parts(c_ptr, c_len) };
+  Ok(f(cs))
 }
 
 #[repr(u8)]
@@ -534,15 +1102,164 @@ impl log::Log for FfiLogger {
       log::Level::Trace => PythonLogLevel::Debug,
     };
     let message = format!("{}", record.args());
-    with_externs(|e| {
-      (e.log)(
-        e.context,
-        level as u8,
-        message.as_ptr(),
-        message.len() as u64,
-      )
-    })
+    with_externs(|e| e.log(level as u8, &message))
   }
 
   fn flush(&self) {}
 }
+
+#[cfg(test)]
+mod tests {
+  use std::mem;
+  use std::ptr;
+
+  use core::{TypeId, Value};
+  use handles::{Handle, drain_handles};
+  use mock_externs::mock;
+  use super::{Buffer, MAX_BUFFER_LEN, TypeIdBuffer, ValueBuffer, drop_handles, key_for,
+              project_key, satisfied_by_type, satisfied_by_types, store_bytes, store_i32,
+              val_for, with_vec};
+
+  fn handle() -> Value {
+    Value::from_handle(1 as Handle)
+  }
+
+  #[test]
+  fn with_vec_null_empty() {
+    let len = with_vec(ptr::null_mut::<u64>(), 0, |slice| slice.len());
+    assert_eq!(Ok(0), len);
+  }
+
+  #[test]
+  fn with_vec_round_trip() {
+    let mut ids = vec![TypeId(1), TypeId(2)];
+    let buffer = TypeIdBuffer {
+      ids_ptr: ids.as_mut_ptr(),
+      ids_len: ids.len() as u64,
+      handle_: handle(),
+    };
+    assert_eq!(Ok(ids.clone()), buffer.to_vec());
+    mem::forget(buffer);
+  }
+
+  #[test]
+  fn empty_type_id_buffer() {
+    let buffer = TypeIdBuffer {
+      ids_ptr: ptr::null_mut(),
+      ids_len: 0,
+      handle_: handle(),
+    };
+    assert_eq!(Ok(Vec::<TypeId>::new()), buffer.to_vec());
+    mem::forget(buffer);
+  }
+
+  #[test]
+  fn empty_buffer() {
+    let buffer = Buffer {
+      bytes_ptr: ptr::null_mut(),
+      bytes_len: 0,
+      handle_: handle(),
+    };
+    assert_eq!(Ok(Vec::<u8>::new()), buffer.to_bytes());
+    assert_eq!(Ok("".to_string()), buffer.to_string());
+    mem::forget(buffer);
+  }
+
+  #[test]
+  fn with_vec_null_nonempty() {
+    assert_eq!(
+      Err("Received a null pointer for a buffer of length 3.".to_string()),
+      with_vec(ptr::null_mut::<u64>(), 3, |slice| slice.len())
+    );
+  }
+
+  #[test]
+  fn with_vec_corrupted_len() {
+    let mut byte = 0u8;
+    let err = with_vec(&mut byte as *mut u8, MAX_BUFFER_LEN + 1, |slice| slice.len()).unwrap_err();
+    assert!(err.contains("its length was likely corrupted"));
+  }
+
+  #[test]
+  fn value_buffer_drops_each_handle_once() {
+    let mock = mock();
+    let mut buffer_handles = Vec::new();
+    let mut received = Vec::new();
+    for i in 0..1000 {
+      let mut values = vec![store_i32(i), store_i32(-i)];
+      let buffer = ValueBuffer {
+        values_ptr: values.as_mut_ptr(),
+        values_len: values.len() as u64,
+        handle_: store_bytes(b"buffer"),
+      };
+      buffer_handles.push(buffer.handle_.handle());
+      received.extend(buffer.into_vec().unwrap());
+      // The array is owned by python, and its values by the receiver of the buffer.
+      mem::forget(values);
+    }
+    drop_handles(drain_handles());
+
+    // Each buffer released its own handle, but not the handles of the values it held.
+    for handle in buffer_handles {
+      assert_eq!(1, mock.drop_handles_calls(handle));
+    }
+    let received_handles: Vec<Handle> = received.iter().map(|v| v.handle()).collect();
+    for &handle in &received_handles {
+      assert_eq!(0, mock.drop_handles_calls(handle));
+    }
+
+    mem::drop(received);
+    drop_handles(drain_handles());
+    for handle in received_handles {
+      assert_eq!(1, mock.drop_handles_calls(handle));
+    }
+  }
+
+  #[test]
+  fn satisfied_by_types_batches_misses() {
+    let mock = mock();
+    let base = mock.new_type("Base");
+    let sub = mock.new_subtype("Sub", base);
+    let other = mock.new_type("Other");
+    let constraint = mock.subclasses_of(&[base]);
+
+    assert_eq!(
+      vec![true, true, false],
+      satisfied_by_types(&[(constraint, base), (constraint, sub), (constraint, other)])
+    );
+    assert_eq!(1, mock.satisfied_by_type_calls(&constraint));
+
+    // Only the check which was not cached calls back.
+    let subsub = mock.new_subtype("SubSub", sub);
+    assert_eq!(
+      vec![false, true],
+      satisfied_by_types(&[(constraint, other), (constraint, subsub)])
+    );
+    assert_eq!(2, mock.satisfied_by_type_calls(&constraint));
+    assert!(satisfied_by_type(&constraint, &base));
+    assert_eq!(2, mock.satisfied_by_type_calls(&constraint));
+  }
+
+  #[test]
+  fn project_key_missing_and_cached() {
+    let mock = mock();
+    let type_id = mock.new_type("Projectable");
+    let key = key_for(mock.instance(type_id, vec![("name", store_bytes(b"a"))]));
+    let value = val_for(&key);
+
+    // The first lookup of a present field calls `has_field` and `project`...
+    let projected = project_key(&key, "name", &type_id);
+    assert_eq!(Some(key_for(store_bytes(b"a"))), projected);
+    assert_eq!(2, mock.projection_calls(&value));
+    // ...and the second makes no calls.
+    assert_eq!(projected, project_key(&key, "name", &type_id));
+    assert_eq!(2, mock.projection_calls(&value));
+
+    // The first lookup of a missing field calls only `has_field`...
+    assert_eq!(None, project_key(&key, "missing", &type_id));
+    assert_eq!(3, mock.projection_calls(&value));
+    // ...and a missing field is cached as well.
+    assert_eq!(None, project_key(&key, "missing", &type_id));
+    assert_eq!(3, mock.projection_calls(&value));
+  }
+}
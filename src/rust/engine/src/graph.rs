@@ -3,10 +3,12 @@
 
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::fs::OpenOptions;
 use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use petgraph::Direction;
 use petgraph::stable_graph::{NodeIndex, StableDiGraph, StableGraph};
@@ -16,8 +18,9 @@ use externs;
 use boxfuture::{BoxFuture, Boxable};
 use context::ContextFactory;
 use core::{Failure, FNV, Noop};
+use fnv::FnvHasher;
 use hashing;
-use nodes::{DigestFile, Node, NodeFuture, NodeKey, NodeResult, TryInto};
+use nodes::{DigestFile, NODE_KIND_COUNT, Node, NodeFuture, NodeKey, NodeResult, TryInto};
 
 
 // 2^32 Nodes ought to be more than enough for anyone!
@@ -40,6 +43,66 @@ impl EntryStateGetter for EntryStateField {
   }
 }
 
+///
+/// The number of outcomes of a completed Node: see `outcome_index`.
+///
+const OUTCOME_COUNT: usize = 4;
+
+///
+/// The index of the outcome of a completed Node, for use in stats. NB: The order of the outcomes
+/// must match `_OUTCOMES` in scheduler.py.
+///
+fn outcome_index(result: &Result<NodeResult, Failure>) -> usize {
+  match result {
+    &Ok(_) => 0,
+    &Err(Failure::Noop(_)) => 1,
+    &Err(Failure::Throw(..)) => 2,
+    &Err(Failure::Invalidated) => 3,
+  }
+}
+
+///
+/// A snapshot of the number of times that Nodes of each kind completed with each outcome. The
+/// count for a kind (see `NodeKey::kind_index`) and an outcome (see `outcome_index`) is at index
+/// `kind_index * OUTCOME_COUNT + outcome_index`.
+///
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OutcomeCounts {
+  pub counts: [u64; NODE_KIND_COUNT * OUTCOME_COUNT],
+}
+
+///
+/// Counts the completions of Nodes by kind and outcome. Counters are allocated once, so recording
+/// a completion is a single atomic increment.
+///
+struct OutcomeCounters {
+  counts: Vec<AtomicUsize>,
+}
+
+impl OutcomeCounters {
+  fn new() -> OutcomeCounters {
+    OutcomeCounters {
+      counts: (0..NODE_KIND_COUNT * OUTCOME_COUNT)
+        .map(|_| AtomicUsize::new(0))
+        .collect(),
+    }
+  }
+
+  fn record(&self, kind_index: usize, result: &Result<NodeResult, Failure>) {
+    let index = kind_index * OUTCOME_COUNT + outcome_index(result);
+    self.counts[index].fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn snapshot(&self) -> OutcomeCounts {
+    let mut snapshot = OutcomeCounts::default();
+    for (count, counter) in snapshot.counts.iter_mut().zip(&self.counts) {
+      *count = counter.load(Ordering::Relaxed) as u64;
+    }
+    snapshot
+  }
+}
+
 ///
 /// Because there are guaranteed to be more edges than nodes in Graphs, we mark cyclic
 /// dependencies via a wrapper around the NodeKey (rather than adding a byte to every
@@ -60,6 +123,46 @@ impl EntryKey {
   }
 }
 
+///
+/// An EntryKey paired with its precomputed hash.
+///
+/// NodeKeys can be expensive to hash (Select and Task nodes contain their rule graph entries), so
+/// a key is hashed once when it is wrapped, and the cached value is what the Nodes map sees when
+/// it is probed, grown, or rebuilt: a key stored in the map is never hashed again. A key that is
+/// only used to probe the map is wrapped (and so hashed) once per lookup. Equality compares the
+/// hashes first, and only falls back to comparing the keys themselves when they match.
+///
+#[derive(Clone, Debug)]
+struct HashedEntryKey {
+  hash: u64,
+  key: EntryKey,
+}
+
+impl HashedEntryKey {
+  fn new(key: EntryKey) -> HashedEntryKey {
+    let mut hasher = FnvHasher::default();
+    key.hash(&mut hasher);
+    HashedEntryKey {
+      hash: hasher.finish(),
+      key: key,
+    }
+  }
+}
+
+impl PartialEq for HashedEntryKey {
+  fn eq(&self, other: &HashedEntryKey) -> bool {
+    self.hash == other.hash && self.key == other.key
+  }
+}
+
+impl Eq for HashedEntryKey {}
+
+impl Hash for HashedEntryKey {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    state.write_u64(self.hash);
+  }
+}
+
 ///
 /// An Entry and its adjacencies.
 ///
@@ -69,6 +172,12 @@ pub struct Entry {
   // maps is painful.
   node: EntryKey,
   state: Option<EntryStateField>,
+  // Notes recorded by the Node while it ran (for example, inputs that it skipped), which are
+  // rendered along with its result.
+  diagnostics: Vec<String>,
+  // The dependencies which produced the values selected by the Node while it ran: for a Select,
+  // the Task(s) whose values it returned.
+  producers: Vec<EntryId>,
 }
 
 impl Entry {
@@ -81,6 +190,8 @@ impl Entry {
     Entry {
       node: node,
       state: None,
+      diagnostics: Vec::new(),
+      producers: Vec::new(),
     }
   }
 
@@ -96,9 +207,15 @@ impl Entry {
   }
 
   ///
-  /// Returns a reference to the Node's Future, starting it if need be.
+  /// Returns a reference to the Node's Future, starting it if need be. The outcome of the Node is
+  /// recorded in the given counters when it completes.
   ///
-  fn state(&mut self, context_factory: &ContextFactory, entry_id: EntryId) -> EntryStateField {
+  fn state(
+    &mut self,
+    context_factory: &ContextFactory,
+    entry_id: EntryId,
+    counters: &Arc<OutcomeCounters>,
+  ) -> EntryStateField {
     if let Some(ref state) = self.state {
       state.clone()
     } else {
@@ -107,7 +224,16 @@ impl Entry {
           // Wrap the launch in future::lazy to defer it until after we're outside the Graph lock.
           let context = context_factory.create(entry_id);
           let node = n.clone();
-          future::lazy(move || node.run(context)).to_boxed()
+          let frame = n.clone();
+          let kind_index = n.kind_index();
+          let counters = counters.clone();
+          future::lazy(move || node.run(context))
+            .map_err(move |failure| failure.with_frame(|| frame.format()))
+            .then(move |result| {
+              counters.record(kind_index, &result);
+              result
+            })
+            .to_boxed()
         }
         &EntryKey::Cyclic(_) => {
           // The type-checker cannot unify BoxFuture<_, Failure> with BoxFuture<NodeResult, Failure>
@@ -118,10 +244,17 @@ impl Entry {
       };
 
       self.state = Some(state.shared());
-      self.state(context_factory, entry_id)
+      self.state(context_factory, entry_id, counters)
     }
   }
 
+  ///
+  /// The dependencies which produced the values that this Node selected.
+  ///
+  fn producers(&self) -> &[EntryId] {
+    &self.producers
+  }
+
   ///
   /// If the Future for this Node has already completed, returns a clone of its result.
   ///
@@ -134,15 +267,25 @@ impl Entry {
   fn format<N: Node>(&self) -> String {
     let state = match self.peek::<N>() {
       Some(Ok(ref nr)) => format!("{:?}", nr),
-      Some(Err(Failure::Throw(ref v, _))) => externs::val_to_str(v),
+      Some(Err(Failure::Throw(ref v, _, _))) => externs::val_to_str(v),
       Some(Err(ref x)) => format!("{:?}", x),
       None => "<None>".to_string(),
     };
-    format!("{} == {}", self.node.content().format(), state).replace("\"", "\\\"")
+    let diagnostics = if self.diagnostics.is_empty() {
+      "".to_string()
+    } else {
+      format!(" ({})", self.diagnostics.join(" "))
+    };
+    format!(
+      "{} == {}{}",
+      self.node.content().format(),
+      state,
+      diagnostics
+    ).replace("\"", "\\\"")
   }
 }
 
-type Nodes = HashMap<EntryKey, EntryId>;
+type Nodes = HashMap<HashedEntryKey, EntryId, FNV>;
 
 struct InnerGraph {
   nodes: Nodes,
@@ -150,12 +293,12 @@ struct InnerGraph {
 }
 
 impl InnerGraph {
-  fn entry(&self, node: &EntryKey) -> Option<&Entry> {
+  fn entry(&self, node: EntryKey) -> Option<&Entry> {
     self.entry_id(node).and_then(|&id| self.entry_for_id(id))
   }
 
-  fn entry_id(&self, node: &EntryKey) -> Option<&EntryId> {
-    self.nodes.get(node)
+  fn entry_id(&self, node: EntryKey) -> Option<&EntryId> {
+    self.nodes.get(&HashedEntryKey::new(node))
   }
 
   fn entry_for_id(&self, id: EntryId) -> Option<&Entry> {
@@ -177,12 +320,13 @@ impl InnerGraph {
   }
 
   fn ensure_entry_internal<'a>(pg: &mut PGraph, nodes: &mut Nodes, node: EntryKey) -> EntryId {
+    let node = HashedEntryKey::new(node);
     if let Some(&id) = nodes.get(&node) {
       return id;
     }
 
     // New entry.
-    let id = pg.add_node(Entry::new(node.clone()));
+    let id = pg.add_node(Entry::new(node.key.clone()));
     nodes.insert(node, id);
     id
   }
@@ -261,7 +405,7 @@ impl InnerGraph {
         .nodes
         .iter()
         .filter_map(|(node, &entry_id)| {
-          node.content().fs_subject().and_then(
+          node.key.content().fs_subject().and_then(
             |path| if paths.contains(path) {
               Some(entry_id)
             } else {
@@ -281,6 +425,58 @@ impl InnerGraph {
     result
   }
 
+  ///
+  /// Clears the state of all (non-cyclic) uncacheable Nodes so that they will be re-run, and
+  /// returns their ids along with their previous results (if they had completed).
+  ///
+  fn reset_uncacheable(&mut self) -> Vec<(EntryId, Option<Result<NodeResult, Failure>>)> {
+    let ids: Vec<EntryId> = self
+      .nodes
+      .iter()
+      .filter_map(|(node, &entry_id)| match &node.key {
+        &EntryKey::Valid(ref n) if !n.is_cacheable() => Some(entry_id),
+        _ => None,
+      })
+      .collect();
+    ids
+      .into_iter()
+      .map(|id| {
+        let entry = self.entry_for_id_mut(id).expect("Uncacheable entry was missing.");
+        let previous = entry.peek::<NodeKey>();
+        entry.state = None;
+        entry.diagnostics.clear();
+        entry.producers.clear();
+        (id, previous)
+      })
+      .collect()
+  }
+
+  ///
+  /// Removes the transitive dependents of the given re-run Nodes, whose results changed. Re-run
+  /// Nodes which depend directly on a changed Node are retained, because they were re-run against
+  /// its new result.
+  ///
+  fn invalidate_dependents(
+    &mut self,
+    changed: Vec<EntryId>,
+    rerun: &HashSet<EntryId, FNV>,
+  ) -> usize {
+    let ids: HashSet<EntryId, FNV> = {
+      let root_ids = changed
+        .into_iter()
+        .flat_map(|id| {
+          self.pg.neighbors_directed(id, Direction::Incoming).collect::<Vec<_>>()
+        })
+        .filter(|dep_id| !rerun.contains(dep_id))
+        .collect();
+      self.walk(root_ids, true).collect()
+    };
+
+    let result = ids.len();
+    InnerGraph::invalidate_internal(&mut self.pg, &mut self.nodes, ids);
+    result
+  }
+
   fn invalidate_internal(pg: &mut PGraph, nodes: &mut Nodes, ids: HashSet<EntryId, FNV>) {
     if ids.is_empty() {
       return;
@@ -288,16 +484,20 @@ impl InnerGraph {
 
     for &id in &ids {
       // Validate that all dependents of the id are also scheduled for removal.
-      assert!(pg.neighbors_directed(id, Direction::Incoming).all(|dep| {
-        ids.contains(&dep)
-      }));
+      assert!(
+        pg.neighbors_directed(id, Direction::Incoming).all(
+          |dep| ids.contains(&dep),
+        ),
+        "The dependents of {} were not scheduled for removal.",
+        pg[id].node.content().describe()
+      );
 
       // Remove the entry from the graph (which will also remove dependent edges).
       pg.remove_node(id);
     }
 
     // Filter the Nodes to delete any with matching ids.
-    let filtered: Vec<(EntryKey, EntryId)> = nodes
+    let filtered: Vec<(HashedEntryKey, EntryId)> = nodes
       .drain()
       .filter(|&(_, id)| !ids.contains(&id))
       .collect();
@@ -340,7 +540,7 @@ impl InnerGraph {
 
     let root_entries = roots
       .iter()
-      .filter_map(|n| self.entry_id(&EntryKey::Valid(n.clone())))
+      .filter_map(|n| self.entry_id(EntryKey::Valid(n.clone())))
       .map(|&eid| eid)
       .collect();
     let predicate = |_| true;
@@ -362,11 +562,19 @@ impl InnerGraph {
           continue;
         }
 
-        // Write an entry per edge.
+        // Write an entry per edge, highlighting the edges to the deps that produced the values
+        // which the entry selected.
         let dep_str = dep_entry.format::<NodeKey>();
-        try!(f.write_fmt(
-          format_args!("    \"{}\" -> \"{}\"\n", node_str, dep_str),
-        ));
+        try!(f.write_fmt(format_args!(
+          "    \"{}\" -> \"{}\"{}\n",
+          node_str,
+          dep_str,
+          if entry.producers().contains(&dep_id) {
+            " [style=bold]"
+          } else {
+            ""
+          }
+        )));
       }
     }
 
@@ -407,21 +615,26 @@ impl InnerGraph {
     let _format = |eid: EntryId, level: Level| -> String {
       let entry = self.unsafe_entry_for_id(eid);
       let indent = _indent(level);
-      let output = format!("{}Computing {}", indent, entry.node.content().format());
+      let mut output = format!("{}Computing {}", indent, entry.node.content().format_verbose());
+      for diagnostic in &entry.diagnostics {
+        output.push_str(&format!("\n{}  {}", indent, diagnostic));
+      }
+      for &producer_id in entry.producers() {
+        output.push_str(&format!(
+          "\n{}  selected {}",
+          indent,
+          self.unsafe_entry_for_id(producer_id).format::<NodeKey>()
+        ));
+      }
       if is_one_level_above_bottom(eid) {
         let state_str = match entry.peek::<NodeKey>() {
           None => "<None>".to_string(),
           Some(Ok(ref x)) => format!("{:?}", x),
-          Some(Err(Failure::Throw(ref x, ref traceback))) => {
-            format!(
-              "Throw({})\n{}",
-              externs::val_to_str(x),
-              traceback
-                .split("\n")
-                .map(|l| format!("{}    {}", indent, l))
-                .collect::<Vec<_>>()
-                .join("\n")
-            )
+          Some(Err(ref f @ Failure::Throw(..))) => {
+            f.render_trace()
+              .split("\n")
+              .collect::<Vec<_>>()
+              .join(&format!("\n{}  ", indent))
           }
           Some(Err(Failure::Noop(ref x))) => format!("Noop({:?})", x),
           Some(Err(Failure::Invalidated)) => "Invalidated".to_string(),
@@ -433,7 +646,7 @@ impl InnerGraph {
     };
 
     let root_entries = self
-      .entry_id(&EntryKey::Valid(root.clone()))
+      .entry_id(EntryKey::Valid(root.clone()))
       .map(|&eid| vec![eid])
       .unwrap_or_else(|| vec![]);
     for t in self.leveled_walk(root_entries, |eid, _| !is_bottom(eid), false) {
@@ -468,6 +681,7 @@ impl InnerGraph {
 ///
 pub struct Graph {
   inner: Mutex<InnerGraph>,
+  counters: Arc<OutcomeCounters>,
 }
 
 impl Graph {
@@ -476,7 +690,10 @@ impl Graph {
       nodes: HashMap::default(),
       pg: StableGraph::new(),
     };
-    Graph { inner: Mutex::new(inner) }
+    Graph {
+      inner: Mutex::new(inner),
+      counters: Arc::new(OutcomeCounters::new()),
+    }
   }
 
   pub fn len(&self) -> usize {
@@ -490,25 +707,26 @@ impl Graph {
   pub fn peek<N: Node>(&self, node: N) -> Option<Result<N::Output, Failure>> {
     let node = node.into();
     let inner = self.inner.lock().unwrap();
-    inner.entry(&EntryKey::Valid(node)).and_then(
+    inner.entry(EntryKey::Valid(node)).and_then(
       |e| e.peek::<N>(),
     )
   }
 
   ///
   /// In the context of the given src Node, declare a dependency on the given dst Node and
-  /// begin its execution if it has not already started.
+  /// begin its execution if it has not already started. Returns the id of the entry for the dst
+  /// Node, along with its future value.
   ///
   pub fn get<N: Node>(
     &self,
     src_id: EntryId,
     context: &ContextFactory,
     dst_node: N,
-  ) -> NodeFuture<N::Output> {
+  ) -> (EntryId, NodeFuture<N::Output>) {
     let dst_node = dst_node.into();
 
     // Get or create the destination, and then insert the dep and return its state.
-    let dst_state = {
+    let (dst_id, dst_state) = {
       let mut inner = self.inner.lock().unwrap();
       let dst_id = {
         // TODO: doing cycle detection under the lock... unfortunate, but probably unavoidable
@@ -523,19 +741,21 @@ impl Graph {
         }
       };
 
-      // Declare the dep, and return the state of the destination.
-      inner.pg.add_edge(src_id, dst_id, ());
-      inner
+      // Declare the dep (once, regardless of how many times it is requested), and return the
+      // state of the destination.
+      inner.pg.update_edge(src_id, dst_id, ());
+      let dst_state = inner
         .entry_for_id_mut(dst_id)
-        .map(|entry| entry.state(context, dst_id))
+        .map(|entry| entry.state(context, dst_id, &self.counters))
         .unwrap_or_else(|| {
           (future::err(Failure::Invalidated).to_boxed() as BoxFuture<_, _>).shared()
-        })
+        });
+      (dst_id, dst_state)
     };
 
     // Got the destination's state. Now that we're outside the graph locks, we can safely
     // retrieve it.
-    dst_state.get::<N>()
+    (dst_id, dst_state.get::<N>())
   }
 
   ///
@@ -548,7 +768,7 @@ impl Graph {
       let id = inner.ensure_entry(EntryKey::Valid(node.into()));
       inner
         .entry_for_id_mut(id)
-        .map(|entry| entry.state(context, id))
+        .map(|entry| entry.state(context, id, &self.counters))
         .unwrap_or_else(|| {
           (future::err(Failure::Invalidated).to_boxed() as BoxFuture<_, _>).shared()
         })
@@ -557,11 +777,86 @@ impl Graph {
     state.get::<N>()
   }
 
+  ///
+  /// Records that the given entry selected the value produced by the given dependency (if the
+  /// entry has not since been removed).
+  ///
+  pub fn record_producer(&self, entry_id: EntryId, producer_id: EntryId) {
+    let mut inner = self.inner.lock().unwrap();
+    if let Some(entry) = inner.entry_for_id_mut(entry_id) {
+      entry.producers.push(producer_id);
+    }
+  }
+
+  ///
+  /// Records a diagnostic for the given entry (if it has not since been removed), which is
+  /// rendered along with its result in traces and visualizations.
+  ///
+  pub fn record_diagnostic(&self, entry_id: EntryId, diagnostic: String) {
+    let mut inner = self.inner.lock().unwrap();
+    if let Some(entry) = inner.entry_for_id_mut(entry_id) {
+      entry.diagnostics.push(diagnostic);
+    }
+  }
+
   pub fn invalidate(&self, paths: HashSet<PathBuf>) -> usize {
     let mut inner = self.inner.lock().unwrap();
     inner.invalidate(paths)
   }
 
+  ///
+  /// Re-runs all uncacheable Nodes, and removes the transitive dependents of any whose result
+  /// changed (so that they will be re-run the next time they are requested). Dependents of an
+  /// uncacheable Node whose result is unchanged are reused.
+  ///
+  /// Returns the number of removed dependents.
+  ///
+  pub fn rerun_uncacheable(&self, context: &ContextFactory) -> usize {
+    // Reset all uncacheable Nodes before starting any of them, so that uncacheable Nodes which
+    // depend on one another observe one another's new results.
+    let reruns = {
+      let mut inner = self.inner.lock().unwrap();
+      let previous = inner.reset_uncacheable();
+      previous
+        .into_iter()
+        .map(|(id, prev)| {
+          let state = inner
+            .entry_for_id_mut(id)
+            .map(|entry| entry.state(context, id, &self.counters))
+            .expect("Uncacheable entry was missing.");
+          (id, prev, state)
+        })
+        .collect::<Vec<_>>()
+    };
+    if reruns.is_empty() {
+      return 0;
+    }
+
+    // Run them outside the lock, and compare their new results to their previous results.
+    let rerun: HashSet<EntryId, FNV> = reruns.iter().map(|&(id, _, _)| id).collect();
+    let changed: Vec<EntryId> = reruns
+      .into_iter()
+      .filter_map(|(id, prev, state)| {
+        let next = state.get::<NodeKey>().wait();
+        let unchanged = match (prev, next) {
+          (Some(Ok(NodeResult::Value(ref p))), Ok(NodeResult::Value(ref n))) => {
+            externs::equals(p, n)
+          }
+          (Some(Err(Failure::Noop(ref p))), Err(Failure::Noop(ref n))) => p == n,
+          _ => false,
+        };
+        if unchanged {
+          None
+        } else {
+          Some(id)
+        }
+      })
+      .collect();
+
+    let mut inner = self.inner.lock().unwrap();
+    inner.invalidate_dependents(changed, &rerun)
+  }
+
   pub fn trace(&self, root: &NodeKey, path: &Path) -> io::Result<()> {
     let inner = self.inner.lock().unwrap();
     inner.trace(root, path)
@@ -572,6 +867,15 @@ impl Graph {
     inner.visualize(roots, path)
   }
 
+  ///
+  /// Returns the number of times that Nodes of each kind completed with each outcome. Outcomes are
+  /// recorded as Nodes complete, so a Node which is re-run is counted once per run, and Nodes
+  /// which have since been removed are still counted.
+  ///
+  pub fn outcome_counts(&self) -> OutcomeCounts {
+    self.counters.snapshot()
+  }
+
   pub fn all_digests(&self) -> Vec<hashing::Digest> {
     let inner = self.inner.lock().unwrap();
     inner.all_digests()
@@ -48,3 +48,12 @@ pub fn maybe_drain_handles() -> Option<Vec<Handle>> {
     None
   }
 }
+
+///
+/// Drains all queued Handles, regardless of their number.
+///
+#[cfg(test)]
+pub fn drain_handles() -> Vec<Handle> {
+  let mut q = DROPPING_HANDLES.lock().unwrap();
+  q.drain(..).map(|sh| sh.0).collect()
+}
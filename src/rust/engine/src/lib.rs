@@ -7,6 +7,8 @@ mod externs;
 mod graph;
 mod handles;
 mod interning;
+#[cfg(test)]
+mod mock_externs;
 mod nodes;
 mod rule_graph;
 mod scheduler;
@@ -35,18 +37,24 @@ use std::mem;
 use std::os::raw;
 use std::panic;
 use std::path::{Path, PathBuf};
+use std::ptr;
 
 
 use context::Core;
 use core::{Failure, Function, Key, TypeConstraint, TypeId, Value};
 use externs::{Buffer, BufferBuffer, CloneValExtern, DropHandlesExtern, CreateExceptionExtern,
-              ExternContext, Externs, TypeToStrExtern, CallExtern, EvalExtern, LogExtern,
-              IdentifyExtern, ProjectExtern, ProjectMultiExtern, ProjectIgnoringTypeExtern,
-              PyResult, SatisfiedByExtern, StoreI32Extern, SatisfiedByTypeExtern, StoreListExtern,
-              StoreBytesExtern, TypeIdBuffer, EqualsExtern, ValToStrExtern};
+              ExternContext, FfiExterns, TypeToStrExtern, CallExtern, EvalExtern, LogExtern,
+              DigestExtern, HasFieldExtern, IdentifyExtern, InstantiateExtern,
+              ProjectExtern, ProjectMultiExtern, ProjectIgnoringTypeExtern, ProjectTypeIdsExtern,
+              PyResult, SatisfiedByExtern, SelectorRecordBuffer, StoreI32Extern,
+              SatisfiedByTypeExtern, SatisfiedByTypeMultiExtern, StoreListExtern,
+              StoreBytesExtern, TaskOptionsRecord, TaskRecordBuffer,
+              TypeConstraintBuffer, TypeIdBuffer, EqualsExtern, ValToStrExtern};
+use graph::OutcomeCounts;
 use rule_graph::{GraphMaker, RuleGraph};
 use scheduler::{ExecutionRequest, RootResult, Scheduler};
-use tasks::Tasks;
+use selectors::{Cardinality, OnMissing};
+use tasks::{TaskBuilderId, TaskError, Tasks, TasksStats};
 use types::Types;
 
 #[repr(C)]
@@ -70,7 +78,7 @@ impl RawNode {
   fn create(subject: &Key, product: &TypeConstraint, state: RootResult) -> RawNode {
     let (state_tag, state_value) = match state {
       Ok(v) => (RawStateTag::Return as u8, v),
-      Err(Failure::Throw(exc, _)) => (RawStateTag::Throw as u8, exc),
+      Err(Failure::Throw(exc, _, _)) => (RawStateTag::Throw as u8, exc),
       Err(Failure::Noop(noop)) => (
         RawStateTag::Noop as u8,
         externs::create_exception(&format!("{:?}", noop)),
@@ -133,16 +141,21 @@ pub extern "C" fn externs_set(
   val_to_str: ValToStrExtern,
   satisfied_by: SatisfiedByExtern,
   satisfied_by_type: SatisfiedByTypeExtern,
+  satisfied_by_type_multi: SatisfiedByTypeMultiExtern,
   store_list: StoreListExtern,
   store_bytes: StoreBytesExtern,
   store_i32: StoreI32Extern,
+  has_field: HasFieldExtern,
   project: ProjectExtern,
   project_ignoring_type: ProjectIgnoringTypeExtern,
   project_multi: ProjectMultiExtern,
+  project_type_ids: ProjectTypeIdsExtern,
+  instantiate: InstantiateExtern,
+  digest: DigestExtern,
   create_exception: CreateExceptionExtern,
   py_str_type: TypeId,
 ) {
-  externs::set_externs(Externs::new(
+  externs::set_externs(Box::new(FfiExterns::new(
     ext_context,
     log,
     call,
@@ -155,15 +168,20 @@ pub extern "C" fn externs_set(
     val_to_str,
     satisfied_by,
     satisfied_by_type,
+    satisfied_by_type_multi,
     store_list,
     store_bytes,
     store_i32,
+    has_field,
     project,
     project_ignoring_type,
     project_multi,
+    project_type_ids,
+    instantiate,
+    digest,
     create_exception,
     py_str_type,
-  ));
+  )));
 }
 
 #[no_mangle]
@@ -213,10 +231,21 @@ pub extern "C" fn scheduler_create(
   ignore_patterns_buf: BufferBuffer,
   root_type_ids: TypeIdBuffer,
 ) -> *const Scheduler {
-  let root_type_ids = root_type_ids.to_vec();
-  let ignore_patterns = ignore_patterns_buf.to_strings().unwrap_or_else(|e| {
-    panic!("Failed to decode ignore patterns as UTF8: {:?}", e)
+  let decoded = root_type_ids.to_vec().and_then(|root_type_ids| {
+    let ignore_patterns = ignore_patterns_buf.to_strings().map_err(|e| {
+      format!("Failed to decode ignore patterns as UTF8: {}", e)
+    })?;
+    let build_root = build_root_buf.to_os_string()?;
+    let work_dir = work_dir_buf.to_os_string()?;
+    Ok((root_type_ids, ignore_patterns, build_root, work_dir))
   });
+  let (root_type_ids, ignore_patterns, build_root, work_dir) = match decoded {
+    Ok(decoded) => decoded,
+    Err(e) => {
+      error!("Failed to create Scheduler: {}", e);
+      return ptr::null();
+    }
+  };
   let tasks = with_tasks(tasks_ptr, |tasks| tasks.clone());
   // Allocate on the heap via `Box` and return a raw pointer to the boxed value.
   Box::into_raw(Box::new(Scheduler::new(Core::new(
@@ -247,9 +276,9 @@ pub extern "C" fn scheduler_create(
       string: type_string,
       bytes: type_bytes,
     },
-    build_root_buf.to_os_string().as_ref(),
+    build_root.as_ref(),
     ignore_patterns,
-    work_dir_buf.to_os_string().as_ref(),
+    work_dir.as_ref(),
   ))))
 }
 
@@ -294,9 +323,15 @@ pub extern "C" fn execution_execute(
 }
 
 #[no_mangle]
-pub extern "C" fn tasks_create() -> *const Tasks {
+pub extern "C" fn tasks_create(
+  key_none: Key,
+  type_string: TypeId,
+  type_bytes: TypeId,
+) -> *const Tasks {
   // Allocate on the heap via `Box` and return a raw pointer to the boxed value.
-  Box::into_raw(Box::new(Tasks::new()))
+  Box::into_raw(Box::new(
+    Tasks::new(key_none, vec![type_string, type_bytes]),
+  ))
 }
 
 #[no_mangle]
@@ -304,97 +339,405 @@ pub extern "C" fn tasks_singleton_add(
   tasks_ptr: *mut Tasks,
   value: Value,
   output_constraint: TypeConstraint,
-) {
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| {
+    tasks.singleton_add(value, output_constraint)
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_mergeable_add(tasks_ptr: *mut Tasks, product: TypeConstraint) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| tasks.mergeable_add(product))
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_first_match_add(
+  tasks_ptr: *mut Tasks,
+  product: TypeConstraint,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| tasks.first_match_add(product))
+}
+
+///
+/// Registers a goal name for a product: see `Tasks::goal_add`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_goal_add(
+  tasks_ptr: *mut Tasks,
+  name: Key,
+  product: TypeConstraint,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| tasks.goal_add(name, product))
+}
+
+///
+/// Returns the product constraint registered for the given goal name, or None.
+///
+#[no_mangle]
+pub extern "C" fn tasks_goal_product(tasks_ptr: *mut Tasks, name: Key) -> Value {
+  with_tasks(tasks_ptr, |tasks| match tasks.goal_product(&name) {
+    Some(product) => externs::val_for(&product.0),
+    None => externs::eval("None").unwrap(),
+  })
+}
+
+///
+/// Freezes the registry before it is used to create a Scheduler: see `Tasks::freeze`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_freeze(tasks_ptr: *mut Tasks) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| tasks.freeze())
+}
+
+///
+/// Begins building a Task, and returns an id which identifies it in calls to the `tasks_add_*`
+/// functions and to `tasks_builder_finish`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_builder_begin(
+  tasks_ptr: *mut Tasks,
+  func: Function,
+  output_type: TypeConstraint,
+  options: TaskOptionsRecord,
+) -> TaskBuilderId {
+  // NB: An id must be returned, so a name or doc which fails to decode is recorded on the
+  // builder, and returned when it is finished.
+  let decoded = options
+    .name()
+    .map_err(|e| format!("Failed to decode name for task: {}", e))
+    .and_then(|name| {
+      options
+        .doc()
+        .map_err(|e| format!("Failed to decode doc for task: {}", e))
+        .map(|doc| (name, doc))
+    });
+  with_tasks(tasks_ptr, |tasks| match decoded {
+    Ok((name, doc)) => tasks.builder_begin(func, output_type, options.to_options(name, doc)),
+    Err(msg) => {
+      let id = tasks.builder_begin(func, output_type, options.to_options(None, None));
+      // The builder was just begun, so it is known.
+      let _ = tasks.builder_fail(id, TaskError::Invalid(msg));
+      id
+    }
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_builder_finish(
+  tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| tasks.builder_finish(builder_id))
+}
+
+///
+/// Finishes the given builder by replacing the Task with the given function that was registered
+/// for the same product.
+///
+#[no_mangle]
+pub extern "C" fn tasks_builder_replace(
+  tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
+  replaced: Function,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| {
+    tasks.builder_replace(builder_id, &replaced)
+  })
+}
+
+///
+/// Makes lookups of Tasks for the alias resolve to the Tasks of the product: see
+/// `Tasks::task_alias`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_task_alias(
+  tasks_ptr: *mut Tasks,
+  product: TypeConstraint,
+  alias: TypeConstraint,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| tasks.task_alias(product, alias))
+}
+
+///
+/// Registers many Tasks in one call: see `Tasks::bulk_add`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_add_bulk(
+  tasks_ptr: *mut Tasks,
+  task_records: TaskRecordBuffer,
+  selector_records: SelectorRecordBuffer,
+  strings: BufferBuffer,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| {
+    let task_records = task_records.to_vec().map_err(TaskError::Invalid)?;
+    let selector_records = selector_records.to_vec().map_err(TaskError::Invalid)?;
+    let strings = strings.to_strings().map_err(TaskError::Invalid)?;
+    tasks.bulk_add(&task_records, &selector_records, &strings)
+  })
+}
+
+///
+/// Removes the Tasks with the given function that were registered for the given product, and
+/// returns True if there were any.
+///
+#[no_mangle]
+pub extern "C" fn tasks_task_remove(
+  tasks_ptr: *mut Tasks,
+  func: Function,
+  product: TypeConstraint,
+) -> PyResult {
   with_tasks(tasks_ptr, |tasks| {
-    tasks.singleton_add(value, output_constraint);
+    tasks
+      .task_remove(&func, &product)
+      .map(|removed| externs::eval(if removed { "True" } else { "False" }).unwrap())
+      .map_err(|e| e.to_string())
+      .into()
   })
 }
 
+///
+/// Begins building a Task using the legacy single-task lifecycle: clauses should be added with the
+/// LEGACY_TASK_BUILDER id (0), and the Task should be completed with `tasks_task_end`.
+///
 #[no_mangle]
 pub extern "C" fn tasks_task_begin(
   tasks_ptr: *mut Tasks,
   func: Function,
   output_type: TypeConstraint,
-) {
-  with_tasks(tasks_ptr, |tasks| { tasks.task_begin(func, output_type); })
+  options: TaskOptionsRecord,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| {
+    let name = options.name().map_err(|e| {
+      TaskError::Invalid(format!("Failed to decode name for task: {}", e))
+    })?;
+    let doc = options.doc().map_err(|e| {
+      TaskError::Invalid(format!("Failed to decode doc for task: {}", e))
+    })?;
+    tasks.task_begin(func, output_type, options.to_options(name, doc))
+  })
 }
 
 #[no_mangle]
-pub extern "C" fn tasks_add_select(tasks_ptr: *mut Tasks, product: TypeConstraint) {
-  with_tasks(tasks_ptr, |tasks| { tasks.add_select(product, None); })
+pub extern "C" fn tasks_add_select(
+  tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
+  product: TypeConstraint,
+  optional: bool,
+  many: bool,
+) -> PyResult {
+  let cardinality = if many {
+    Cardinality::Many
+  } else {
+    Cardinality::One
+  };
+  register_with_tasks(tasks_ptr, |tasks| {
+    tasks.add_select(builder_id, product, None, None, optional, cardinality)
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_add_select_subject(
+  tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
+  product: TypeConstraint,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| tasks.add_select_subject(builder_id, product))
 }
 
 #[no_mangle]
 pub extern "C" fn tasks_add_select_variant(
   tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
   product: TypeConstraint,
-  variant_key_buf: Buffer,
-) {
-  let variant_key = variant_key_buf.to_string().expect(
-    "Failed to decode key for select_variant",
-  );
-  with_tasks(tasks_ptr, |tasks| {
-    tasks.add_select(product, Some(variant_key));
+  variant_key: Key,
+  has_default_variant_value: bool,
+  default_variant_value_buf: Buffer,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| {
+    let default_variant_value = if has_default_variant_value {
+      Some(default_variant_value_buf.to_string().map_err(|e| {
+        TaskError::Invalid(format!(
+          "Failed to decode default value for select_variant: {}",
+          e
+        ))
+      })?)
+    } else {
+      None
+    };
+    tasks.add_select(
+      builder_id,
+      product,
+      Some(variant_key),
+      default_variant_value,
+      false,
+      Cardinality::One,
+    )
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_add_select_variant_value(
+  tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
+  product: TypeConstraint,
+  variant_key: Key,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| {
+    tasks.add_select_variant(builder_id, product, variant_key)
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_add_select_union(
+  tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
+  products: TypeConstraintBuffer,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| {
+    let products = products.to_vec().map_err(TaskError::Invalid)?;
+    tasks.add_select_union(builder_id, products)
   })
 }
 
 #[no_mangle]
 pub extern "C" fn tasks_add_select_dependencies(
   tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
   product: TypeConstraint,
   dep_product: TypeConstraint,
   field: Buffer,
   field_types: TypeIdBuffer,
-) {
-  with_tasks(tasks_ptr, |tasks| {
+  field_type_constraints: TypeConstraintBuffer,
+  skip_missing: bool,
+  skip_self: bool,
+) -> PyResult {
+  let on_missing = if skip_missing {
+    OnMissing::Skip
+  } else {
+    OnMissing::Throw
+  };
+  register_with_tasks(tasks_ptr, |tasks| {
+    let field = field.to_string().map_err(TaskError::Invalid)?;
+    let field_types = field_types.to_vec().map_err(TaskError::Invalid)?;
+    let field_type_constraints = field_type_constraints.to_vec().map_err(TaskError::Invalid)?;
     tasks.add_select_dependencies(
+      builder_id,
       product,
       dep_product,
-      field.to_string().expect("field to be a string"),
-      field_types.to_vec(),
-    );
+      field,
+      field_types,
+      field_type_constraints,
+      on_missing,
+      skip_self,
+    )
   })
 }
 
 #[no_mangle]
 pub extern "C" fn tasks_add_select_transitive(
   tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
   product: TypeConstraint,
   dep_product: TypeConstraint,
   field: Buffer,
   field_types: TypeIdBuffer,
-) {
-  with_tasks(tasks_ptr, |tasks| {
+  field_type_constraints: TypeConstraintBuffer,
+) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| {
+    let field = field.to_string().map_err(TaskError::Invalid)?;
+    let field_types = field_types.to_vec().map_err(TaskError::Invalid)?;
+    let field_type_constraints = field_type_constraints.to_vec().map_err(TaskError::Invalid)?;
     tasks.add_select_transitive(
+      builder_id,
       product,
       dep_product,
-      field.to_string().expect("field to be a string"),
-      field_types.to_vec(),
-    );
+      field,
+      field_types,
+      field_type_constraints,
+    )
   })
 }
 
 #[no_mangle]
 pub extern "C" fn tasks_add_select_projection(
   tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
   product: TypeConstraint,
   projected_subject: TypeId,
-  field: Buffer,
+  fields: BufferBuffer,
   input_product: TypeConstraint,
-) {
-  with_tasks(tasks_ptr, |tasks| {
+  skip_missing: bool,
+) -> PyResult {
+  let on_missing = if skip_missing {
+    OnMissing::Skip
+  } else {
+    OnMissing::Throw
+  };
+  register_with_tasks(tasks_ptr, |tasks| {
+    let fields = fields.to_strings().map_err(TaskError::Invalid)?;
     tasks.add_select_projection(
+      builder_id,
       product,
       projected_subject,
-      field.to_string().expect("field to be a string"),
-      input_product,
-    );
+      fields,
+      vec![input_product],
+      on_missing,
+    )
   })
 }
 
+///
+/// Like `tasks_add_select_projection`, but with alternative input products which are attempted
+/// in order.
+///
 #[no_mangle]
-pub extern "C" fn tasks_task_end(tasks_ptr: *mut Tasks) {
-  with_tasks(tasks_ptr, |tasks| { tasks.task_end(); })
+pub extern "C" fn tasks_add_select_projection_alternatives(
+  tasks_ptr: *mut Tasks,
+  builder_id: TaskBuilderId,
+  product: TypeConstraint,
+  projected_subject: TypeId,
+  fields: BufferBuffer,
+  input_products: TypeConstraintBuffer,
+  skip_missing: bool,
+) -> PyResult {
+  let on_missing = if skip_missing {
+    OnMissing::Skip
+  } else {
+    OnMissing::Throw
+  };
+  register_with_tasks(tasks_ptr, |tasks| {
+    let fields = fields.to_strings().map_err(TaskError::Invalid)?;
+    let input_products = input_products.to_vec().map_err(TaskError::Invalid)?;
+    tasks.add_select_projection(
+      builder_id,
+      product,
+      projected_subject,
+      fields,
+      input_products,
+      on_missing,
+    )
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_task_end(tasks_ptr: *mut Tasks) -> PyResult {
+  register_with_tasks(tasks_ptr, |tasks| tasks.task_end())
+}
+
+///
+/// Discards the Task begun by `tasks_task_begin`, and returns true if there was one.
+///
+#[no_mangle]
+pub extern "C" fn tasks_task_abort(tasks_ptr: *mut Tasks) -> bool {
+  with_tasks(tasks_ptr, |tasks| tasks.task_abort())
+}
+
+///
+/// Discards the Task being built by the given builder, and returns true if there was one.
+///
+#[no_mangle]
+pub extern "C" fn tasks_builder_abort(tasks_ptr: *mut Tasks, builder_id: TaskBuilderId) -> bool {
+  with_tasks(tasks_ptr, |tasks| tasks.builder_abort(builder_id))
 }
 
 #[no_mangle]
@@ -402,14 +745,105 @@ pub extern "C" fn tasks_destroy(tasks_ptr: *mut Tasks) {
   let _ = unsafe { Box::from_raw(tasks_ptr) };
 }
 
+///
+/// Returns the hex fingerprint of the registered Tasks, which is stable between runs for
+/// equivalent registrations.
+///
 #[no_mangle]
-pub extern "C" fn graph_invalidate(scheduler_ptr: *mut Scheduler, paths_buf: BufferBuffer) -> u64 {
-  with_scheduler(scheduler_ptr, |scheduler| {
-    let paths = paths_buf
-      .to_os_strings()
+pub extern "C" fn tasks_fingerprint(tasks_ptr: *mut Tasks) -> Value {
+  with_tasks(tasks_ptr, |tasks| {
+    externs::store_bytes(tasks.fingerprint().0.to_hex().as_bytes())
+  })
+}
+
+///
+/// Describes the candidates able to produce the given product for the given subject type: see
+/// `Tasks::gen_tasks_report`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_gen_tasks_report(
+  tasks_ptr: *mut Tasks,
+  subject_type: TypeId,
+  product: TypeConstraint,
+) -> Value {
+  with_tasks(tasks_ptr, |tasks| {
+    externs::store_bytes(tasks.gen_tasks_report(subject_type, &product).as_bytes())
+  })
+}
+
+///
+/// Returns a readable report of the registered singletons and Tasks: see `Tasks::report`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_report(tasks_ptr: *mut Tasks) -> Value {
+  with_tasks(tasks_ptr, |tasks| externs::store_bytes(tasks.report().as_bytes()))
+}
+
+///
+/// Returns a list of every product that the registry can provide: see `Tasks::all_products`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_all_products(tasks_ptr: *mut Tasks) -> Value {
+  with_tasks(tasks_ptr, |tasks| {
+    let products: Vec<Value> = tasks
+      .all_products()
       .into_iter()
-      .map(|os_str| PathBuf::from(os_str))
+      .map(|product| externs::val_for(&product.0))
       .collect();
+    externs::store_list(products.iter().collect(), false)
+  })
+}
+
+///
+/// Returns counts describing the size of the registry: see `Tasks::stats`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_stats(tasks_ptr: *mut Tasks) -> TasksStats {
+  with_tasks(tasks_ptr, |tasks| tasks.stats())
+}
+
+///
+/// Returns a list of descriptions of the Tasks which are unreachable from the given roots: see
+/// `Tasks::unreachable`.
+///
+#[no_mangle]
+pub extern "C" fn tasks_unreachable(
+  tasks_ptr: *mut Tasks,
+  root_subject_types: TypeIdBuffer,
+  root_products: TypeConstraintBuffer,
+) -> Value {
+  let roots = root_subject_types.to_vec().and_then(|root_subject_types| {
+    root_products.to_vec().map(|root_products| {
+      (root_subject_types, root_products)
+    })
+  });
+  let (root_subject_types, root_products) = match roots {
+    Ok(roots) => roots,
+    Err(e) => {
+      error!("Failed to decode roots for unreachable tasks: {}", e);
+      return externs::store_list(vec![], false);
+    }
+  };
+  with_tasks(tasks_ptr, |tasks| {
+    let unreachable: Vec<Value> = tasks
+      .unreachable(&root_subject_types, &root_products)
+      .into_iter()
+      .map(|task| externs::store_bytes(rule_graph::task_display(task).as_bytes()))
+      .collect();
+    externs::store_list(unreachable.iter().collect(), false)
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn graph_invalidate(scheduler_ptr: *mut Scheduler, paths_buf: BufferBuffer) -> u64 {
+  let paths = match paths_buf.to_os_strings() {
+    Ok(os_strs) => os_strs.into_iter().map(|os_str| PathBuf::from(os_str)).collect(),
+    Err(e) => {
+      error!("Failed to decode paths to invalidate: {}", e);
+      return 0;
+    }
+  };
+  with_scheduler(scheduler_ptr, |scheduler| {
     scheduler.core.graph.invalidate(paths) as u64
   })
 }
@@ -419,6 +853,15 @@ pub extern "C" fn graph_len(scheduler_ptr: *mut Scheduler) -> u64 {
   with_scheduler(scheduler_ptr, |scheduler| scheduler.core.graph.len() as u64)
 }
 
+///
+/// Returns the number of times that Nodes of each kind completed with each outcome: see
+/// `Graph::outcome_counts`.
+///
+#[no_mangle]
+pub extern "C" fn graph_outcome_counts(scheduler_ptr: *mut Scheduler) -> OutcomeCounts {
+  with_scheduler(scheduler_ptr, |scheduler| scheduler.core.graph.outcome_counts())
+}
+
 #[no_mangle]
 pub extern "C" fn graph_visualize(
   scheduler_ptr: *mut Scheduler,
@@ -476,12 +919,14 @@ pub extern "C" fn execution_request_destroy(ptr: *mut ExecutionRequest) {
 
 #[no_mangle]
 pub extern "C" fn validator_run(scheduler_ptr: *mut Scheduler) -> Value {
-  with_scheduler(scheduler_ptr, |scheduler| match scheduler
-    .core
-    .rule_graph
-    .validate() {
-    Result::Ok(_) => externs::store_list(vec![], false),
-    Result::Err(msg) => externs::create_exception(&msg),
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let core = &scheduler.core;
+    match core.rule_graph.validate().and_then(|()| {
+      core.rule_graph.validate_goals(&core.tasks.all_goals())
+    }) {
+      Result::Ok(_) => externs::store_list(vec![], false),
+      Result::Err(msg) => externs::create_exception(&msg),
+    }
   })
 }
 
@@ -491,11 +936,18 @@ pub extern "C" fn rule_graph_visualize(
   subject_types: TypeIdBuffer,
   path_ptr: *const raw::c_char,
 ) {
+  let subject_types = match subject_types.to_vec() {
+    Ok(subject_types) => subject_types,
+    Err(e) => {
+      error!("Failed to decode subject types to visualize: {}", e);
+      return;
+    }
+  };
   with_scheduler(scheduler_ptr, |scheduler| {
     let path_str = unsafe { CStr::from_ptr(path_ptr).to_string_lossy().into_owned() };
     let path = PathBuf::from(path_str);
 
-    let graph = graph_full(scheduler, subject_types.to_vec());
+    let graph = graph_full(scheduler, subject_types);
     write_to_file(path.as_path(), &graph).unwrap_or_else(|e| {
       println!("Failed to visualize to {}: {:?}", path.display(), e);
     });
@@ -611,3 +1063,14 @@ where
   mem::forget(tasks);
   t
 }
+
+///
+/// Like `with_tasks`, but for the fallible registration methods: rather than panicking across the
+/// C api, a TaskError is returned to the caller as an exception.
+///
+fn register_with_tasks<F>(tasks_ptr: *mut Tasks, f: F) -> PyResult
+where
+  F: FnOnce(&mut Tasks) -> Result<(), TaskError>,
+{
+  with_tasks(tasks_ptr, |tasks| f(tasks).map_err(|e| e.to_string()).into())
+}
@@ -0,0 +1,609 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::os::raw;
+use std::sync::{Arc, Once, ONCE_INIT, RwLock};
+
+use core::{Function, TypeConstraint, TypeId, Value};
+use externs::{self, Externs, Ident};
+use handles::Handle;
+
+///
+/// An in-memory stand-in for a python object. Objects are never collected, and are identified by
+/// their index in `State::objects`.
+///
+#[derive(Clone, Debug)]
+enum Object {
+  None,
+  Bool(bool),
+  Int(i32),
+  Str(String),
+  List(Vec<usize>),
+  Type(TypeId),
+  // A TypeConstraint: an `Exactly` constraint if `subclasses` is false, and a `SubclassesOf`
+  // constraint otherwise.
+  Constraint { subclasses: bool, types: Vec<TypeId> },
+  Instance(TypeId, Vec<(String, usize)>),
+  Function(String),
+  // A method of the object with the given id, bound by `project_ignoring_type`.
+  Method(usize, String),
+  Exception(String),
+}
+
+const NONE_TYPE: TypeId = TypeId(1);
+const BOOL_TYPE: TypeId = TypeId(2);
+const INT_TYPE: TypeId = TypeId(3);
+const STR_TYPE: TypeId = TypeId(4);
+const LIST_TYPE: TypeId = TypeId(5);
+const TYPE_TYPE: TypeId = TypeId(6);
+const CONSTRAINT_TYPE: TypeId = TypeId(7);
+const FUNCTION_TYPE: TypeId = TypeId(8);
+const METHOD_TYPE: TypeId = TypeId(9);
+const EXCEPTION_TYPE: TypeId = TypeId(10);
+
+struct MockType {
+  name: String,
+  parent: Option<TypeId>,
+}
+
+struct State {
+  // Indexed by TypeId: the 0th type is `core::ANY_TYPE`.
+  types: Vec<MockType>,
+  objects: Vec<Object>,
+  // The number of calls to `satisfied_by_type` (batched or not) that included each constraint.
+  satisfied_by_type_calls: HashMap<usize, usize>,
+  // The number of calls to `drop_handles` that included each object.
+  drop_handles_calls: HashMap<usize, usize>,
+  // The number of calls to `has_field` or `project` on each object.
+  projection_calls: HashMap<usize, usize>,
+}
+
+impl State {
+  fn new() -> State {
+    let names = vec![
+      "Any",
+      "NoneType",
+      "bool",
+      "int",
+      "str",
+      "list",
+      "type",
+      "TypeConstraint",
+      "function",
+      "instancemethod",
+      "Exception",
+    ];
+    State {
+      types: names
+        .into_iter()
+        .map(|name| {
+          MockType {
+            name: name.to_string(),
+            parent: None,
+          }
+        })
+        .collect(),
+      objects: Vec::new(),
+      satisfied_by_type_calls: HashMap::new(),
+      drop_handles_calls: HashMap::new(),
+      projection_calls: HashMap::new(),
+    }
+  }
+
+  fn alloc(&mut self, object: Object) -> Value {
+    self.objects.push(object);
+    Value::from_handle(to_handle(self.objects.len() - 1))
+  }
+
+  fn get(&self, value: &Value) -> &Object {
+    &self.objects[to_id(value.handle())]
+  }
+
+  fn type_of(&self, object: &Object) -> TypeId {
+    match object {
+      &Object::None => NONE_TYPE,
+      &Object::Bool(_) => BOOL_TYPE,
+      &Object::Int(_) => INT_TYPE,
+      &Object::Str(_) => STR_TYPE,
+      &Object::List(_) => LIST_TYPE,
+      &Object::Type(_) => TYPE_TYPE,
+      &Object::Constraint { .. } => CONSTRAINT_TYPE,
+      &Object::Instance(type_id, _) => type_id,
+      &Object::Function(_) => FUNCTION_TYPE,
+      &Object::Method(..) => METHOD_TYPE,
+      &Object::Exception(_) => EXCEPTION_TYPE,
+    }
+  }
+
+  fn is_subclass(&self, cls: TypeId, parent: TypeId) -> bool {
+    let mut current = Some(cls);
+    while let Some(type_id) = current {
+      if type_id == parent {
+        return true;
+      }
+      current = self.types[type_id.0 as usize].parent;
+    }
+    false
+  }
+
+  fn satisfied_by_type(&self, constraint: &Object, cls: TypeId) -> bool {
+    match constraint {
+      &Object::Constraint {
+        subclasses,
+        ref types,
+      } => {
+        types.iter().any(|&t| if subclasses {
+          self.is_subclass(cls, t)
+        } else {
+          t == cls
+        })
+      }
+      o => panic!("Not a TypeConstraint: {:?}", o),
+    }
+  }
+
+  ///
+  /// Objects with python value semantics are equal by content: all others are equal by identity.
+  ///
+  fn equals(&self, id1: usize, id2: usize) -> bool {
+    match (&self.objects[id1], &self.objects[id2]) {
+      (&Object::None, &Object::None) => true,
+      (&Object::Bool(b1), &Object::Bool(b2)) => b1 == b2,
+      (&Object::Int(i1), &Object::Int(i2)) => i1 == i2,
+      (&Object::Str(ref s1), &Object::Str(ref s2)) => s1 == s2,
+      (&Object::Type(t1), &Object::Type(t2)) => t1 == t2,
+      _ => id1 == id2,
+    }
+  }
+
+  fn hash(&self, id: usize) -> i64 {
+    let mut hasher = DefaultHasher::new();
+    match &self.objects[id] {
+      &Object::None => 0u8.hash(&mut hasher),
+      &Object::Bool(b) => b.hash(&mut hasher),
+      &Object::Int(i) => i.hash(&mut hasher),
+      &Object::Str(ref s) => s.hash(&mut hasher),
+      &Object::Type(t) => t.hash(&mut hasher),
+      _ => id.hash(&mut hasher),
+    }
+    hasher.finish() as i64
+  }
+
+  fn has_field(&self, id: usize, field: &str) -> bool {
+    match &self.objects[id] {
+      &Object::Instance(_, ref fields) => fields.iter().any(|&(ref name, _)| name == field),
+      &Object::Constraint { .. } => {
+        field == "satisfied_by_type" || field == "graph_str" || field == "types"
+      }
+      &Object::Function(_) => field == "__name__",
+      &Object::Exception(_) => field == "_formatted_exc",
+      _ => false,
+    }
+  }
+
+  fn field(&mut self, id: usize, field: &str) -> Option<Value> {
+    if !self.has_field(id, field) {
+      return None;
+    }
+    let projected = match self.objects[id].clone() {
+      Object::Instance(_, fields) => {
+        return fields.into_iter().find(|&(ref name, _)| name == field).map(
+          |(_, field_id)| {
+            Value::from_handle(to_handle(field_id))
+          },
+        )
+      }
+      Object::Constraint { types, .. } => {
+        if field == "types" {
+          let ids = types
+            .into_iter()
+            .map(|t| to_id(self.alloc(Object::Type(t)).handle()))
+            .collect();
+          Object::List(ids)
+        } else {
+          Object::Method(id, field.to_string())
+        }
+      }
+      Object::Function(name) => Object::Str(name),
+      Object::Exception(msg) => {
+        Object::Str(format!(
+          "Traceback (no traceback):\n  <mock externs>\nException: {}",
+          msg
+        ))
+      }
+      _ => return None,
+    };
+    Some(self.alloc(projected))
+  }
+
+  fn project(&mut self, value: &Value, field: &str) -> Value {
+    let id = to_id(value.handle());
+    self.field(id, field).unwrap_or_else(|| {
+      panic!("{:?} has no field `{}`.", self.objects[id], field)
+    })
+  }
+
+  fn to_str(&self, id: usize) -> String {
+    match &self.objects[id] {
+      &Object::None => "None".to_string(),
+      &Object::Bool(true) => "True".to_string(),
+      &Object::Bool(false) => "False".to_string(),
+      &Object::Int(i) => format!("{}", i),
+      &Object::Str(ref s) => s.clone(),
+      &Object::List(ref ids) => format!("[{}]", self.to_strs(ids).join(", ")),
+      &Object::Type(t) => self.types[t.0 as usize].name.clone(),
+      &Object::Constraint {
+        subclasses,
+        ref types,
+      } => {
+        format!(
+          "{}({})",
+          if subclasses { "SubclassesOf" } else { "Exactly" },
+          types
+            .iter()
+            .map(|t| self.types[t.0 as usize].name.clone())
+            .collect::<Vec<_>>()
+            .join(", ")
+        )
+      }
+      &Object::Instance(type_id, ref fields) => {
+        format!(
+          "{}({})",
+          self.types[type_id.0 as usize].name,
+          fields
+            .iter()
+            .map(|&(ref name, field_id)| format!("{}={}", name, self.to_str(field_id)))
+            .collect::<Vec<_>>()
+            .join(", ")
+        )
+      }
+      &Object::Function(ref name) => name.clone(),
+      &Object::Method(_, ref name) => format!("<bound method {}>", name),
+      &Object::Exception(ref msg) => msg.clone(),
+    }
+  }
+
+  fn to_strs(&self, ids: &[usize]) -> Vec<String> {
+    ids.iter().map(|&id| self.to_str(id)).collect()
+  }
+}
+
+fn to_handle(id: usize) -> Handle {
+  // Offset by one to avoid using a null pointer as a Handle.
+  (id + 1) as *const raw::c_void
+}
+
+fn to_id(handle: Handle) -> usize {
+  handle as usize - 1
+}
+
+///
+/// An implementation of Externs that does not require python, for use in tests.
+///
+/// Tests declare types (optionally as subclasses of other types) and construct instances with
+/// named fields, which are then visible to the engine via the usual `externs` functions. Keys are
+/// created for mock values via `externs::key_for` as usual: since hashes are computed
+/// deterministically from the content of a value (or its identity), so are Keys.
+///
+#[derive(Clone)]
+pub struct MockExterns {
+  state: Arc<RwLock<State>>,
+}
+
+lazy_static! {
+  static ref MOCK: MockExterns = MockExterns {
+    state: Arc::new(RwLock::new(State::new())),
+  };
+}
+
+static INSTALL: Once = ONCE_INIT;
+
+///
+/// Returns the MockExterns for this process, installing it as the static Externs on first use.
+///
+/// Because the Externs are static, all tests in the process share one MockExterns: tests should
+/// declare their own types rather than depending on any other state of the mock.
+///
+pub fn mock() -> &'static MockExterns {
+  INSTALL.call_once(|| externs::set_externs(Box::new(MOCK.clone())));
+  &MOCK
+}
+
+impl MockExterns {
+  pub fn new_type(&self, name: &str) -> TypeId {
+    self.add_type(name, None)
+  }
+
+  pub fn new_subtype(&self, name: &str, parent: TypeId) -> TypeId {
+    self.add_type(name, Some(parent))
+  }
+
+  fn add_type(&self, name: &str, parent: Option<TypeId>) -> TypeId {
+    let mut state = self.state.write().unwrap();
+    state.types.push(MockType {
+      name: name.to_string(),
+      parent: parent,
+    });
+    TypeId((state.types.len() - 1) as u64)
+  }
+
+  ///
+  /// A constraint satisfied by exactly the given types.
+  ///
+  pub fn exactly(&self, types: &[TypeId]) -> TypeConstraint {
+    self.constraint(false, types)
+  }
+
+  ///
+  /// A constraint satisfied by the given types and their subtypes.
+  ///
+  pub fn subclasses_of(&self, types: &[TypeId]) -> TypeConstraint {
+    self.constraint(true, types)
+  }
+
+  fn constraint(&self, subclasses: bool, types: &[TypeId]) -> TypeConstraint {
+    let value = self.alloc(Object::Constraint {
+      subclasses: subclasses,
+      types: types.to_vec(),
+    });
+    TypeConstraint(externs::key_for(value))
+  }
+
+  ///
+  /// An instance of the given type with the given fields, in declaration order.
+  ///
+  pub fn instance(&self, type_id: TypeId, fields: Vec<(&str, Value)>) -> Value {
+    let fields = fields
+      .into_iter()
+      .map(|(name, value)| (name.to_string(), to_id(value.handle())))
+      .collect();
+    self.alloc(Object::Instance(type_id, fields))
+  }
+
+  ///
+  /// The number of calls to `satisfied_by_type` or `satisfied_by_type_multi` which included the
+  /// given constraint: calls answered from the cache in `externs` are not counted.
+  ///
+  pub fn satisfied_by_type_calls(&self, constraint: &TypeConstraint) -> usize {
+    let id = to_id(externs::val_for(&constraint.0).handle());
+    let state = self.state.read().unwrap();
+    state.satisfied_by_type_calls.get(&id).cloned().unwrap_or(0)
+  }
+
+  ///
+  /// The number of times that the given Handle has been released via `drop_handles`.
+  ///
+  pub fn drop_handles_calls(&self, handle: Handle) -> usize {
+    let state = self.state.read().unwrap();
+    state.drop_handles_calls.get(&to_id(handle)).cloned().unwrap_or(0)
+  }
+
+  ///
+  /// The number of calls to `has_field` or `project` on the given value: projections answered
+  /// from the cache in `externs` are not counted.
+  ///
+  pub fn projection_calls(&self, value: &Value) -> usize {
+    let state = self.state.read().unwrap();
+    state.projection_calls.get(&to_id(value.handle())).cloned().unwrap_or(0)
+  }
+
+  pub fn function(&self, name: &str) -> Function {
+    Function(externs::key_for(self.alloc(Object::Function(name.to_string()))))
+  }
+
+  fn alloc(&self, object: Object) -> Value {
+    self.state.write().unwrap().alloc(object)
+  }
+}
+
+impl Externs for MockExterns {
+  fn log(&self, _level: u8, _msg: &str) {}
+
+  fn call(&self, func: &Value, args: &[Value], _timeout_ms: Option<u64>) -> Result<Value, Value> {
+    let mut state = self.state.write().unwrap();
+    let result = match state.get(func) {
+      &Object::Method(constraint_id, ref name) if name == "satisfied_by_type" &&
+                                                    args.len() == 1 => {
+        match state.get(&args[0]) {
+          &Object::Type(t) => {
+            Some(Object::Bool(
+              state.satisfied_by_type(&state.objects[constraint_id], t),
+            ))
+          }
+          _ => None,
+        }
+      }
+      &Object::Method(constraint_id, ref name) if name == "graph_str" && args.is_empty() => {
+        Some(Object::Str(state.to_str(constraint_id)))
+      }
+      _ => None,
+    };
+    match result {
+      Some(object) => Ok(state.alloc(object)),
+      None => {
+        let msg = format!("MockExterns cannot call {}.", state.to_str(to_id(func.handle())));
+        Err(state.alloc(Object::Exception(msg)))
+      }
+    }
+  }
+
+  fn eval(&self, python: &str) -> Result<Value, Value> {
+    let object = match python {
+      "None" => Object::None,
+      "True" => Object::Bool(true),
+      "False" => Object::Bool(false),
+      _ => Object::Exception(format!("MockExterns cannot eval `{}`.", python)),
+    };
+    let is_exception = match object {
+      Object::Exception(_) => true,
+      _ => false,
+    };
+    let value = self.alloc(object);
+    if is_exception { Err(value) } else { Ok(value) }
+  }
+
+  fn identify(&self, val: &Value) -> Ident {
+    let state = self.state.read().unwrap();
+    let id = to_id(val.handle());
+    Ident {
+      hash: state.hash(id),
+      value: self.clone_val(val),
+      type_id: state.type_of(&state.objects[id]),
+    }
+  }
+
+  fn equals(&self, val1: &Value, val2: &Value) -> bool {
+    let state = self.state.read().unwrap();
+    state.equals(to_id(val1.handle()), to_id(val2.handle()))
+  }
+
+  fn clone_val(&self, val: &Value) -> Value {
+    Value::from_handle(val.handle())
+  }
+
+  fn drop_handles(&self, handles: &[Handle]) {
+    // Objects live for the life of the process: drops are only counted.
+    let mut state = self.state.write().unwrap();
+    for &handle in handles {
+      *state.drop_handles_calls.entry(to_id(handle)).or_insert(0) += 1;
+    }
+  }
+
+  fn satisfied_by(&self, constraint: &Value, obj: &Value) -> bool {
+    let state = self.state.read().unwrap();
+    let cls = state.type_of(state.get(obj));
+    state.satisfied_by_type(state.get(constraint), cls)
+  }
+
+  fn satisfied_by_type(&self, constraint: &Value, cls: &TypeId) -> bool {
+    let mut state = self.state.write().unwrap();
+    *state
+      .satisfied_by_type_calls
+      .entry(to_id(constraint.handle()))
+      .or_insert(0) += 1;
+    state.satisfied_by_type(state.get(constraint), *cls)
+  }
+
+  fn satisfied_by_type_multi(&self, queries: &[(&Value, TypeId)]) -> Vec<bool> {
+    let mut state = self.state.write().unwrap();
+    let mut constraint_ids: Vec<usize> = queries
+      .iter()
+      .map(|&(constraint, _)| to_id(constraint.handle()))
+      .collect();
+    constraint_ids.sort();
+    constraint_ids.dedup();
+    for id in constraint_ids {
+      *state.satisfied_by_type_calls.entry(id).or_insert(0) += 1;
+    }
+    queries
+      .iter()
+      .map(|&(constraint, cls)| {
+        state.satisfied_by_type(state.get(constraint), cls)
+      })
+      .collect()
+  }
+
+  fn store_list(&self, values: &[&Value], merge: bool) -> Value {
+    let mut state = self.state.write().unwrap();
+    let mut ids = Vec::new();
+    for value in values {
+      let id = to_id(value.handle());
+      match &state.objects[id] {
+        &Object::List(ref items) if merge => ids.extend(items),
+        _ => ids.push(id),
+      }
+    }
+    state.alloc(Object::List(ids))
+  }
+
+  fn store_bytes(&self, bytes: &[u8]) -> Value {
+    self.alloc(Object::Str(String::from_utf8_lossy(bytes).into_owned()))
+  }
+
+  fn store_i32(&self, val: i32) -> Value {
+    self.alloc(Object::Int(val))
+  }
+
+  fn project(&self, value: &Value, field: &str, _type_id: &TypeId) -> Value {
+    let mut state = self.state.write().unwrap();
+    *state.projection_calls.entry(to_id(value.handle())).or_insert(0) += 1;
+    state.project(value, field)
+  }
+
+  fn has_field(&self, value: &Value, field: &str) -> bool {
+    let mut state = self.state.write().unwrap();
+    let id = to_id(value.handle());
+    *state.projection_calls.entry(id).or_insert(0) += 1;
+    state.has_field(id, field)
+  }
+
+  fn project_ignoring_type(&self, value: &Value, field: &str) -> Value {
+    self.state.write().unwrap().project(value, field)
+  }
+
+  fn project_multi(&self, value: &Value, field: &str) -> Vec<Value> {
+    let mut state = self.state.write().unwrap();
+    let projected = state.project(value, field);
+    match state.get(&projected) {
+      &Object::List(ref ids) => {
+        ids
+          .iter()
+          .map(|&id| Value::from_handle(to_handle(id)))
+          .collect()
+      }
+      o => panic!("Field `{}` is not a list: {:?}", field, o),
+    }
+  }
+
+  fn project_type_ids(&self, value: &Value, field: &str) -> Result<Vec<TypeId>, String> {
+    let mut state = self.state.write().unwrap();
+    let projected = state.project(value, field);
+    match state.get(&projected) {
+      &Object::List(ref ids) => {
+        ids
+          .iter()
+          .map(|&id| match &state.objects[id] {
+            &Object::Type(type_id) => Ok(type_id),
+            o => Err(format!("Field `{}` contains a non-type: {:?}", field, o)),
+          })
+          .collect()
+      }
+      o => Err(format!("Field `{}` is not a list: {:?}", field, o)),
+    }
+  }
+
+  fn project_str(&self, value: &Value, field: &str) -> String {
+    let mut state = self.state.write().unwrap();
+    let projected = state.project(value, field);
+    state.to_str(to_id(projected.handle()))
+  }
+
+  fn instantiate(&self, type_id: &TypeId, args: &[Value]) -> Result<Value, Value> {
+    let fields = args
+      .iter()
+      .enumerate()
+      .map(|(i, arg)| (format!("{}", i), to_id(arg.handle())))
+      .collect();
+    Ok(self.alloc(Object::Instance(*type_id, fields)))
+  }
+
+  fn type_to_str(&self, type_id: TypeId) -> String {
+    self.state.read().unwrap().types[type_id.0 as usize].name.clone()
+  }
+
+  fn val_to_str(&self, val: &Value) -> String {
+    self.state.read().unwrap().to_str(to_id(val.handle()))
+  }
+
+  fn digest(&self, val: &Value) -> String {
+    // Mock values have no code or addresses, so they are identified by their renderings.
+    self.val_to_str(val)
+  }
+
+  fn create_exception(&self, msg: &str) -> Value {
+    self.alloc(Object::Exception(msg.to_string()))
+  }
+}
@@ -5,24 +5,26 @@ extern crate bazel_protos;
 extern crate tempdir;
 
 use std::error::Error;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::future::{self, Future};
-use ordermap::OrderMap;
 use tempdir::TempDir;
 
 use boxfuture::{Boxable, BoxFuture};
 use context::Context;
-use core::{Failure, Key, Noop, TypeConstraint, Value, Variants, throw};
+use core::{Failure, FNV, Key, Noop, TypeConstraint, TypeId, Value, Variants, throw};
 use externs;
 use fs::{self, Dir, File, FileContent, Link, PathGlobs, PathStat, StoreFileByDigest, VFS};
+use graph::EntryId;
 use process_execution as process_executor;
 use hashing;
 use rule_graph;
-use selectors::{self, Selector};
+use selectors::{self, Cardinality, OnMissing, Selector};
 use tasks;
 
 
@@ -38,15 +40,63 @@ fn err<O: Send + 'static>(failure: Failure) -> NodeFuture<O> {
 
 ///
 /// A helper to indicate that the value represented by the Failure was required, and thus
-/// fatal if not present.
+/// fatal if not present. The description of the required dependency is only rendered if
+/// it was missing.
 ///
-fn was_required(failure: Failure) -> Failure {
+fn was_required<F: FnOnce() -> String>(failure: Failure, describe: F) -> Failure {
   match failure {
-    Failure::Noop(noop) => throw(&format!("No source of required dependency: {:?}", noop)),
+    Failure::Noop(noop) => {
+      let root_cause = noop.root_cause();
+      if root_cause == &noop {
+        throw(&format!(
+          "No source of required dependency {}: {:?}",
+          describe(),
+          noop
+        ))
+      } else {
+        throw(&format!(
+          "No source of required dependency {}: {:?}\nRoot cause: {:?}",
+          describe(),
+          noop,
+          root_cause
+        ))
+      }
+    }
     f => f,
   }
 }
 
+///
+/// Renders a description of a Select for the given subject and product, matching the format
+/// used by `NodeKey::format` for a Select without variants.
+///
+fn select_str(subject: &Key, product: &TypeConstraint) -> String {
+  format!(
+    "Select({}, {})",
+    externs::key_to_str(subject),
+    externs::key_to_str(&product.0)
+  )
+}
+
+///
+/// Renders the given Variants as a suffix for a Node description, or an empty string if there
+/// are no Variants.
+///
+fn variants_str(variants: &Variants) -> String {
+  if variants.0.is_empty() {
+    return "".to_string();
+  }
+  format!(
+    ", variants=[{}]",
+    variants
+      .0
+      .iter()
+      .map(|&(ref k, ref v)| format!("{}={}", k, v))
+      .collect::<Vec<_>>()
+      .join(", ")
+  )
+}
+
 pub trait GetNode {
   fn get<N: Node>(&self, node: N) -> NodeFuture<N::Output>;
 }
@@ -68,6 +118,7 @@ impl VFS<Failure> for Context {
     Failure::Throw(
       externs::create_exception(msg),
       "<pants native internals>".to_string(),
+      Vec::new(),
     )
   }
 }
@@ -93,6 +144,11 @@ pub trait Node: Into<NodeKey> {
   fn run(self, context: Context) -> NodeFuture<Self::Output>;
 }
 
+///
+/// The maximum depth of nested HasProducts values that will be searched for a product.
+///
+const MAX_HAS_PRODUCTS_DEPTH: usize = 8;
+
 ///
 /// A Node that selects a product for a subject.
 ///
@@ -149,50 +205,143 @@ impl Select {
     &self.selector.product
   }
 
-  fn select_literal_single<'a>(
+  ///
+  /// If the candidate satisfies the product (and has the name of one of the acceptable variant
+  /// values, if there are any), returns the rank of the match: lower ranks are preferred.
+  ///
+  fn select_literal_single(
     &self,
-    candidate: &'a Value,
-    variant_value: &Option<String>,
-  ) -> bool {
+    candidate: &Value,
+    variant_values: &Option<Vec<String>>,
+  ) -> Option<usize> {
     if !externs::satisfied_by(&self.selector.product, candidate) {
-      return false;
+      return None;
     }
-    return match variant_value {
-      &Some(ref vv) if externs::project_str(candidate, "name") != *vv =>
-        // There is a variant value, and it doesn't match.
-        false,
-      _ =>
-        true,
-    };
+    Select::variant_rank(candidate, variant_values)
+  }
+
+  ///
+  /// Returns the rank of the given candidate (which satisfies the product) among the acceptable
+  /// variant values, if any.
+  ///
+  fn variant_rank(candidate: &Value, variant_values: &Option<Vec<String>>) -> Option<usize> {
+    match variant_values {
+      &Some(ref vvs) => {
+        // The rank is the priority of the variant value that the candidate matched.
+        let name = Select::field_name(candidate);
+        vvs.iter().position(|vv| *vv == name)
+      }
+      &None => Some(0),
+    }
+  }
+
+  ///
+  /// Retains only the matches with the lowest (ie, most preferred) rank.
+  ///
+  fn preferred<T>(matches: Vec<(usize, T)>) -> Vec<T> {
+    let best = matches.iter().map(|&(rank, _)| rank).min();
+    matches
+      .into_iter()
+      .filter(|&(rank, _)| Some(rank) == best)
+      .map(|(_, m)| m)
+      .collect()
   }
 
   ///
   /// Looks for has-a or is-a relationships between the given value and the requested product.
+  /// The has-a search descends through nested HasProducts values breadth first, up to
+  /// MAX_HAS_PRODUCTS_DEPTH levels, and stops at the shallowest level containing a match.
   ///
-  /// Returns the resulting product value, or None if no match was made.
+  /// Returns the resulting product value and the rank of its match, or None if no match was made.
+  /// Of multiple matches at the same level, only those with the preferred rank are kept: they are
+  /// merged if the product is mergeable (according to `tasks`), and are otherwise a conflict.
   ///
   fn select_literal(
     &self,
-    context: &Context,
+    has_products: &TypeConstraint,
+    tasks: &tasks::Tasks,
     candidate: Value,
-    variant_value: &Option<String>,
-  ) -> Option<Value> {
+    variant_values: &Option<Vec<String>>,
+  ) -> Result<Option<(usize, Value)>, Failure> {
     // Check whether the subject is-a instance of the product.
-    if self.select_literal_single(&candidate, variant_value) {
-      return Some(candidate);
+    if let Some(rank) = self.select_literal_single(&candidate, variant_values) {
+      return Ok(Some((rank, candidate)));
     }
 
-    // Else, check whether it has-a instance of the product.
-    // TODO: returning only the first literal configuration of a given type/variant. Need to
-    // define mergeability for products.
-    if externs::satisfied_by(&context.core.types.has_products, &candidate) {
-      for child in externs::project_multi(&candidate, "products") {
-        if self.select_literal_single(&child, variant_value) {
-          return Some(child);
+    // Else, check whether it has-a instance of the product. The type checks for each level are
+    // made in batches, since each check that is not already cached calls back into python.
+    let mut visited: HashSet<Key> = HashSet::new();
+    let mut containers = vec![(externs::key_for(candidate.clone()), candidate)];
+    for _ in 0..MAX_HAS_PRODUCTS_DEPTH {
+      let container_checks: Vec<_> = containers
+        .iter()
+        .map(|&(ref key, _)| (*has_products, *key.type_id()))
+        .collect();
+      let mut children = Vec::new();
+      for ((_, container), is_container) in
+        containers.into_iter().zip(
+          externs::satisfied_by_types(&container_checks),
+        )
+      {
+        if !is_container {
+          continue;
+        }
+        for child in Select::field_products(&container) {
+          let key = externs::key_for(child.clone());
+          if visited.insert(key) {
+            children.push((key, child));
+          }
         }
       }
+
+      let product_checks: Vec<_> = children
+        .iter()
+        .map(|&(ref key, _)| (*self.product(), *key.type_id()))
+        .collect();
+      let mut matches = Vec::new();
+      let mut nested = Vec::new();
+      for ((key, child), is_product) in
+        children.into_iter().zip(
+          externs::satisfied_by_types(&product_checks),
+        )
+      {
+        let rank = if is_product {
+          Select::variant_rank(&child, variant_values)
+        } else {
+          None
+        };
+        match rank {
+          Some(rank) => matches.push((rank, child)),
+          None => nested.push((key, child)),
+        }
+      }
+
+      let rank = matches.iter().map(|&(rank, _)| rank).min().unwrap_or(0);
+      let mut matches = Select::preferred(matches);
+      if matches.len() == 1 {
+        return Ok(matches.pop().map(|m| (rank, m)));
+      } else if matches.len() > 1 {
+        if tasks.is_mergeable(self.product()) {
+          return Ok(Some(
+            (rank, externs::store_list(matches.iter().collect(), true)),
+          ));
+        }
+        return Err(throw(&format!(
+          "Conflicting values configured for subject {} and type {}:\n{}",
+          externs::key_to_str(&self.subject),
+          externs::key_to_str(&self.product().0),
+          matches
+            .iter()
+            .map(|value| format!("  {}", externs::val_to_str(value)))
+            .collect::<Vec<_>>()
+            .join("\n")
+        )));
+      } else if nested.is_empty() {
+        break;
+      }
+      containers = nested;
     }
-    None
+    Ok(None)
   }
 
   ///
@@ -202,24 +351,29 @@ impl Select {
     &self,
     context: Context,
     results: Vec<Result<Value, Failure>>,
-    variant_value: &Option<String>,
+    producers: &[Option<EntryId>],
+    variant_values: &Option<Vec<String>>,
   ) -> Result<Value, Failure> {
     let mut matches = Vec::new();
-    let mut max_noop = Noop::NoTask;
-    for result in results {
+    let mut attempted = Vec::new();
+    for (index, result) in results.into_iter().enumerate() {
       match result {
         Ok(value) => {
-          if let Some(v) = self.select_literal(&context, value, variant_value) {
-            matches.push(v);
+          if let Some((rank, v)) = self.select_literal(
+            &context.core.types.has_products,
+            &context.core.tasks,
+            value,
+            variant_values,
+          )?
+          {
+            matches.push((rank, (index, v)));
           }
         }
         Err(err) => {
           match err {
             Failure::Noop(noop) => {
-              // Record the highest priority Noop value.
-              if noop > max_noop {
-                max_noop = noop;
-              }
+              // Record why the candidate did not produce a value.
+              attempted.push((self.producer_str(&context, index), noop));
               continue;
             }
             i @ Failure::Invalidated => return Err(i),
@@ -229,20 +383,147 @@ impl Select {
       }
     }
 
+    if self.selector.cardinality == Cardinality::Many && !matches.is_empty() {
+      // All of the successful values were requested.
+      Select::record_producers(&context, producers, matches.iter().map(|&(_, (i, _))| i));
+      return Ok(externs::store_list(
+        matches.iter().map(|&(_, (_, ref v))| v).collect(),
+        false,
+      ));
+    }
+
+    // Of the successful values, only those matching the most preferred variant value compete.
+    let mut matches = Select::preferred(matches);
+
     if matches.len() > 1 {
-      // TODO: Multiple successful tasks are not currently supported. We could allow for this
-      // by adding support for "mergeable" products. see:
+      // Multiple successful tasks are only supported for "mergeable" products. see:
       //   https://github.com/pantsbuild/pants/issues/2526
-      return Err(throw("Conflicting values produced for subject and type."));
+      if context.core.tasks.is_mergeable(self.product()) {
+        Select::record_producers(&context, producers, matches.iter().map(|&(i, _)| i));
+        return Ok(externs::store_list(
+          matches.iter().map(|&(_, ref v)| v).collect(),
+          true,
+        ));
+      }
+      return Err(throw(&format!(
+        "Conflicting values produced for subject {} and type {}:\n{}",
+        externs::key_to_str(&self.subject),
+        externs::key_to_str(&self.product().0),
+        matches
+          .iter()
+          .map(|&(index, ref value)| {
+            format!(
+              "  {} (produced by {})",
+              externs::val_to_str(value),
+              self.producer_str(&context, index)
+            )
+          })
+          .collect::<Vec<_>>()
+          .join("\n")
+      )));
     }
 
     match matches.pop() {
-      Some(matched) =>
+      Some((index, matched)) => {
         // Exactly one value was available.
-        Ok(matched),
-      None =>
-        // Propagate the highest priority Noop value.
-        Err(Failure::Noop(max_noop)),
+        Select::record_producers(&context, producers, Some(index));
+        Ok(matched)
+      }
+      None => Err(Failure::Noop(Select::attempted_noop(attempted))),
+    }
+  }
+
+  ///
+  /// Records the producers (if any: intrinsics and singletons are not Nodes) of the results at
+  /// the given indexes of the results of `gen_nodes` as the producers of the selected value.
+  ///
+  fn record_producers<I: IntoIterator<Item = usize>>(
+    context: &Context,
+    producers: &[Option<EntryId>],
+    indexes: I,
+  ) {
+    for index in indexes {
+      if let Some(&Some(producer_id)) = producers.get(index) {
+        context.record_producer(producer_id);
+      }
+    }
+  }
+
+  ///
+  /// Given the reasons that each candidate did not produce a value, returns the highest priority
+  /// Noop value, or if no candidate had a more specific reason, all of the reasons.
+  ///
+  fn attempted_noop(attempted: Vec<(String, Noop)>) -> Noop {
+    let max_noop = match attempted.iter().map(|&(_, ref noop)| noop).max() {
+      Some(&Noop::NoTask(_)) |
+      Some(&Noop::MissingInputs(_)) |
+      None => None,
+      Some(noop) => Some(noop.clone()),
+    };
+    max_noop.unwrap_or_else(|| Noop::NoTask(attempted))
+  }
+
+  ///
+  /// Requests the configured Task nodes one at a time in the order of `Tasks::gen_tasks` (by
+  /// priority, then registration order), and returns the first value that is selected. Later
+  /// candidates are not requested once an earlier candidate has produced a value.
+  ///
+  fn select_first_match(
+    self,
+    context: Context,
+    variant_values: Option<Vec<String>>,
+  ) -> NodeFuture<Value> {
+    let candidates: VecDeque<_> = self.gen_nodes(&context).into_iter().enumerate().collect();
+    future::loop_fn(
+      (self, candidates, Vec::new()),
+      move |(select, mut candidates, mut attempted)| match candidates.pop_front() {
+        Some((index, (producer, candidate))) => {
+          let context = context.clone();
+          let variant_values = variant_values.clone();
+          candidate
+            .then(move |result| match result {
+              Ok(value) => {
+                match select.select_literal(
+                  &context.core.types.has_products,
+                  &context.core.tasks,
+                  value,
+                  &variant_values,
+                )? {
+                  Some((_, v)) => {
+                    if let Some(producer_id) = producer {
+                      context.record_producer(producer_id);
+                    }
+                    Ok(future::Loop::Break(v))
+                  }
+                  None => Ok(future::Loop::Continue((select, candidates, attempted))),
+                }
+              }
+              Err(Failure::Noop(noop)) => {
+                attempted.push((select.producer_str(&context, index), noop));
+                Ok(future::Loop::Continue((select, candidates, attempted)))
+              }
+              Err(failure) => Err(failure),
+            })
+            .to_boxed()
+        }
+        None => err(Failure::Noop(Select::attempted_noop(attempted))),
+      },
+    ).to_boxed()
+  }
+
+  ///
+  /// Describes the producer of the result at the given index of the results of `gen_nodes`.
+  ///
+  fn producer_str(&self, context: &Context, index: usize) -> String {
+    match self.entries.get(index) {
+      Some(entry @ &rule_graph::Entry::InnerEntry(_)) => {
+        let task = context.core.rule_graph.task_for_inner(entry);
+        match task.doc_summary() {
+          Some(summary) => format!("{} ({})", task.display_name(), summary),
+          None => task.display_name(),
+        }
+      }
+      _ => "<intrinsic>".to_string(),
     }
   }
 
@@ -272,33 +553,43 @@ impl Select {
   /// Return Futures for each Task/Node that might be able to compute the given product for the
   /// given subject and variants.
   ///
-  fn gen_nodes(&self, context: &Context) -> Vec<NodeFuture<Value>> {
+  /// The order of the candidates is deterministic: an intrinsic for the product takes precedence
+  /// over a singleton, which takes precedence over Tasks, which are returned in the registration
+  /// order preserved by the rule graph.
+  ///
+  fn gen_nodes(&self, context: &Context) -> Vec<(Option<EntryId>, NodeFuture<Value>)> {
     // TODO: These `product==` hooks are hacky.
     if self.product() == &context.core.types.snapshot {
       // If the requested product is a Snapshot, execute a Snapshot Node and then lower to a Value
       // for this caller.
       let context = context.clone();
       vec![
-        self
-          .get_snapshot(&context)
-          .map(move |snapshot| {
-            Snapshot::store_snapshot(&context, &snapshot)
-          })
-          .to_boxed(),
+        (
+          None,
+          self
+            .get_snapshot(&context)
+            .map(move |snapshot| {
+              Snapshot::store_snapshot(&context, &snapshot)
+            })
+            .to_boxed(),
+        ),
       ]
     } else if self.product() == &context.core.types.files_content {
       // If the requested product is FilesContent, request a Snapshot and lower it as FilesContent.
       let context = context.clone();
       vec![
-        self
-          .get_snapshot(&context)
-          .and_then(
-            move |snapshot|
-            // Request the file contents of the Snapshot, and then store them.
-            snapshot.contents(context.core.store.clone()).map_err(|e| throw(&e))
-              .map(move |files_content| Snapshot::store_files_content(&context, &files_content))
-          )
-          .to_boxed(),
+        (
+          None,
+          self
+            .get_snapshot(&context)
+            .and_then(
+              move |snapshot|
+              // Request the file contents of the Snapshot, and then store them.
+              snapshot.contents(context.core.store.clone()).map_err(|e| throw(&e))
+                .map(move |files_content| Snapshot::store_files_content(&context, &files_content))
+            )
+            .to_boxed(),
+        ),
       ]
     } else if self.product() == &context.core.types.process_result {
       let value = externs::val_for(&self.subject);
@@ -340,67 +631,175 @@ impl Select {
       // TODO: request the Node that invokes the process, rather than invoke directly
       let result = process_executor::local::run_command_locally(request, tmpdir.path()).unwrap();
       vec![
-        future::ok(externs::unsafe_call(
-          &context.core.types.construct_process_result,
-          &[
-            externs::store_bytes(&result.stdout),
-            externs::store_bytes(&result.stderr),
-            externs::store_i32(result.exit_code),
-          ],
-        )).to_boxed(),
+        (
+          None,
+          future::ok(externs::unsafe_call(
+            &context.core.types.construct_process_result,
+            &[
+              externs::store_bytes(&result.stdout),
+              externs::store_bytes(&result.stderr),
+              externs::store_i32(result.exit_code),
+            ],
+          )).to_boxed(),
+        ),
       ]
     } else if let Some(&(_, ref value)) = context.core.tasks.gen_singleton(self.product()) {
-      vec![future::ok(value.clone()).to_boxed()]
+      vec![(None, future::ok(value.clone()).to_boxed())]
     } else {
       self
         .entries
         .iter()
         .map(|entry| {
           let task = context.core.rule_graph.task_for_inner(entry);
-          context.get(Task {
+          let (entry_id, task_future) = context.get_with_id(Task {
             subject: self.subject.clone(),
             product: self.product().clone(),
             variants: self.variants.clone(),
             task: task,
             entry: entry.clone(),
-          })
+          });
+          (Some(entry_id), task_future)
         })
-        .collect::<Vec<NodeFuture<Value>>>()
+        .collect::<Vec<_>>()
     }
   }
-}
 
-// TODO: This is a Node only because it is used as a root in the graph, but it should never be
-// requested using context.get
-impl Node for Select {
-  type Output = Value;
+  ///
+  /// Projects the name of the given candidate, which is matched against configured variant values.
+  ///
+  fn field_name(candidate: &Value) -> String {
+    externs::project_str(candidate, "name")
+  }
 
-  fn run(self, context: Context) -> NodeFuture<Value> {
-    // TODO add back support for variants https://github.com/pantsbuild/pants/issues/4020
+  ///
+  /// Projects the products held by the given HasProducts candidate, in declaration order. An
+  /// empty list causes selection to fall through to the configured tasks.
+  ///
+  fn field_products(candidate: &Value) -> Vec<Value> {
+    externs::project_multi(candidate, "products")
+  }
 
-    // If there is a variant_key, see whether it has been configured; if not, no match.
-    let variant_value: Option<String> = match self.selector.variant_key {
+  ///
+  /// Projects the variants configured on the given Variants value, which are provided as a flat
+  /// list of alternating keys and values (with a key repeated once per value, in priority order).
+  /// A Variants value that does not configure any defaults results in empty Variants.
+  ///
+  fn field_variants(context: &Context, variants_value: &Value) -> Result<Variants, Failure> {
+    let is_string = |value: &Value| {
+      let type_id = *externs::key_for(value.clone()).type_id();
+      type_id == context.core.types.string || type_id == context.core.types.bytes
+    };
+    let mut pairs = Vec::new();
+    for pair in externs::project_multi(variants_value, "default_pairs").chunks(2) {
+      let (key, value) = (&pair[0], &pair[1]);
+      if !is_string(key) || !is_string(value) {
+        return Err(throw(&format!(
+          "Variant keys and values must be strings, but {} configured `{}` for `{}`.",
+          externs::val_to_str(variants_value),
+          externs::val_to_str(value),
+          externs::val_to_str(key)
+        )));
+      }
+      pairs.push((externs::val_to_str(key), externs::val_to_str(value)));
+    }
+    Ok(Variants::new(pairs))
+  }
+
+  ///
+  /// Computes the variants that should be used to select for our subject: if the subject is an
+  /// Address with configured Variants, those are merged over the variants that were inherited
+  /// from our requester.
+  ///
+  fn merged_variants(&self, context: &Context) -> NodeFuture<Variants> {
+    let has_variants = context.core.types.has_variants.clone();
+    if self.product() == &has_variants ||
+      !externs::satisfied_by(&context.core.types.address, &externs::val_for(&self.subject))
+    {
+      return ok(self.variants.clone());
+    }
+
+    let edges = context.core.rule_graph.find_root_edges(
+      self.subject.type_id().clone(),
+      Selector::Select(selectors::Select::without_variant(has_variants)),
+    );
+    match edges {
+      Some(ref edges) => {
+        let inherited = self.variants.clone();
+        let context = context.clone();
+        // NB: The lookup is selected directly with the inherited variants (rather than `run`),
+        // so that it never triggers a variants lookup of its own, even if the Variants for the
+        // subject are computed by a Task.
+        Select::new(
+          has_variants,
+          self.subject.clone(),
+          self.variants.clone(),
+          edges,
+        ).select(context.clone())
+          .then(move |variants_res| match variants_res {
+            Ok(variants_value) => {
+              Select::field_variants(&context, &variants_value)
+                .map(|configured| inherited.merge(configured))
+            }
+            Err(Failure::Noop(_)) => Ok(inherited),
+            Err(failure) => Err(failure),
+          })
+          .to_boxed()
+      }
+      None => ok(self.variants.clone()),
+    }
+  }
+
+  ///
+  /// Selects the product for the subject using the given (fully merged) variants: the same
+  /// variants are used to find the variant value, to select literals, and for the Task nodes
+  /// that are generated to compute the product.
+  ///
+  fn select(self, context: Context) -> NodeFuture<Value> {
+    // If there is a variant_key, see whether it has been configured (or has a default); if not,
+    // no match. A key may be configured with multiple acceptable values, in priority order.
+    let variant_values: Option<Vec<String>> = match self.selector.variant_key {
       Some(ref variant_key) => {
-        let variant_value = self.variants.find(variant_key);
-        if variant_value.is_none() {
-          return err(Failure::Noop(Noop::NoVariant));
+        let mut variant_values = self.variants.find_all(variant_key);
+        if variant_values.is_empty() {
+          variant_values.extend(
+            self.selector.default_variant_value.as_ref().map(|v| v.as_str()),
+          );
+        }
+        if variant_values.is_empty() {
+          return err(Failure::Noop(Noop::NoVariant(variant_key.clone())));
         }
-        variant_value.map(|v| v.to_string())
+        Some(variant_values.into_iter().map(|v| v.to_string()).collect())
       }
       None => None,
     };
 
     // If the Subject "is a" or "has a" Product, then we're done.
-    if let Some(literal_value) =
-      self.select_literal(&context, externs::val_for(&self.subject), &variant_value)
-    {
-      return ok(literal_value);
+    match self.select_literal(
+      &context.core.types.has_products,
+      &context.core.tasks,
+      externs::val_for(&self.subject),
+      &variant_values,
+    ) {
+      Ok(Some((_, literal_value))) => {
+        return match self.selector.cardinality {
+          Cardinality::One => ok(literal_value),
+          Cardinality::Many => ok(externs::store_list(vec![&literal_value], false)),
+        };
+      }
+      Ok(None) => {}
+      Err(failure) => return err(failure),
     }
 
     // Else, attempt to use the configured tasks to compute the value.
+    if self.selector.cardinality == Cardinality::One &&
+      context.core.tasks.is_first_match(self.product())
+    {
+      return self.select_first_match(context, variant_values);
+    }
+    let (producers, node_futures): (Vec<_>, Vec<_>) =
+      self.gen_nodes(&context).into_iter().unzip();
     let deps_future = future::join_all(
-      self
-        .gen_nodes(&context)
+      node_futures
         .into_iter()
         .map(|node_future| {
           // Don't fail the join if one fails.
@@ -409,19 +808,37 @@ impl Node for Select {
         .collect::<Vec<_>>(),
     );
 
-    let variant_value = variant_value.map(|s| s.to_string());
     deps_future
       .and_then(move |dep_results| {
         future::result(self.choose_task_result(
           context,
           dep_results,
-          &variant_value,
+          &producers,
+          &variant_values,
         ))
       })
       .to_boxed()
   }
 }
 
+// TODO: This is a Node only because it is used as a root in the graph, but it should never be
+// requested using context.get
+impl Node for Select {
+  type Output = Value;
+
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    self
+      .merged_variants(&context)
+      .and_then(move |variants| {
+        Select {
+          variants: variants,
+          ..self
+        }.select(context)
+      })
+      .to_boxed()
+  }
+}
+
 impl From<Select> for NodeKey {
   fn from(n: Select) -> Self {
     NodeKey::Select(n)
@@ -476,27 +893,50 @@ impl SelectDependencies {
     }
   }
 
-  fn get_dep(&self, context: &Context, dep_subject: Value) -> NodeFuture<Value> {
+  ///
+  /// Selects the product for the given dependency (as the given field type: see
+  /// `match_field_types`), or None if the dependency can't provide it and the selector skips
+  /// missing dependencies.
+  ///
+  fn get_dep(
+    &self,
+    context: &Context,
+    dep_subject_key: Key,
+    field_type: TypeId,
+  ) -> NodeFuture<Option<Value>> {
     // TODO: This method needs to consider whether the `dep_subject` is an Address,
     // and if so, attempt to parse Variants there. See:
     //   https://github.com/pantsbuild/pants/issues/4020
 
-    let dep_subject_key = externs::key_for(dep_subject);
+    let product = self.selector.product;
+    let subject = self.subject.clone();
+    let dep_product = self.selector.dep_product;
+    let on_missing = self.selector.on_missing;
     Select {
       selector: selectors::Select::without_variant(self.selector.product),
       subject: dep_subject_key,
       variants: self.variants.clone(),
-      // NB: We're filtering out all of the entries for field types other than
-      //    dep_subject's since none of them will match.
+      // NB: We're filtering out all of the entries for field types other than the one that
+      //    dep_subject matched, since none of them will match.
       entries: self
         .product_entries
         .clone()
         .into_iter()
-        .filter(|e| {
-          e.matches_subject_type(dep_subject_key.type_id().clone())
-        })
+        .filter(|e| e.matches_subject_type(field_type))
         .collect(),
     }.run(context.clone())
+      .then(move |dep_res| match dep_res {
+        Ok(value) => Ok(Some(value)),
+        Err(Failure::Noop(_)) if on_missing == OnMissing::Skip => Ok(None),
+        Err(failure) => Err(was_required(failure, || {
+          format!(
+            "{} (a dependency of {})",
+            select_str(&dep_subject_key, &product),
+            select_str(&subject, &dep_product)
+          )
+        })),
+      })
+      .to_boxed()
   }
 }
 
@@ -512,20 +952,49 @@ impl SelectDependencies {
       .then(move |dep_product_res| {
         match dep_product_res {
           Ok(dep_product) => {
-            // The product and its dependency list are available: project them.
+            // The product and its dependency list are available: project them, requesting each
+            // dependency subject only once (in the order in which it first appears).
+            let dep_subjects =
+              match project_multi_field(&dep_product, &self.selector.field, &self.subject) {
+                Ok(dep_subjects) => dep_subjects,
+                Err(failure) => return err(failure),
+              };
+            let mut seen: HashSet<Key, FNV> = HashSet::default();
+            let mut skipped_self = false;
+            let dep_subject_keys: Vec<Key> = dep_subjects
+              .into_iter()
+              .map(|dep_subject| externs::key_for(dep_subject))
+              .filter(|dep_subject_key| {
+                if self.selector.skip_self && dep_subject_key == &self.subject {
+                  skipped_self = true;
+                  return false;
+                }
+                seen.insert(dep_subject_key.clone())
+              })
+              .collect();
+            if skipped_self {
+              // Record the skip for the Node that requested the dependencies.
+              context.record_diagnostic(format!(
+                "Skipped {} in its own `{}` field.",
+                externs::key_to_str(&self.subject),
+                self.selector.field
+              ));
+            }
             let deps = future::join_all(
-              externs::project_multi(&dep_product, &self.selector.field)
-                .into_iter()
-                .map(|dep_subject| self.get_dep(&context, dep_subject))
+              filter_field_types(
+                &self.selector.field_types,
+                &self.selector.field_type_constraints,
+                dep_subject_keys,
+              ).into_iter()
+                .map(|(dep_subject_key, field_type)| {
+                  self.get_dep(&context, dep_subject_key, field_type)
+                })
                 .collect::<Vec<_>>(),
             );
             deps
-              .then(move |dep_values_res| {
+              .map(|dep_values| {
                 // Finally, store the resulting values.
-                match dep_values_res {
-                  Ok(dep_values) => Ok(externs::store_list(dep_values.iter().collect(), false)),
-                  Err(failure) => Err(was_required(failure)),
-                }
+                externs::store_list(dep_values.iter().filter_map(|v| v.as_ref()).collect(), false)
               })
               .to_boxed()
           }
@@ -543,6 +1012,9 @@ impl SelectDependencies {
 ///
 /// A node that recursively selects the dependencies of requested type and merge them.
 ///
+/// Each dependency subject is expanded only once, and the value produced by this Node contains
+/// the products in the pre-order in which their subjects were first discovered.
+///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct SelectTransitive {
   pub subject: Key,
@@ -592,40 +1064,148 @@ impl SelectTransitive {
     &self,
     context: &Context,
     subject_key: Key,
+    field_type: TypeId,
   ) -> NodeFuture<(Key, Value, Vec<Value>)> {
     let field_name = self.selector.field.to_owned();
     Select {
       selector: selectors::Select::without_variant(self.selector.product),
       subject: subject_key,
       variants: self.variants.clone(),
-      // NB: We're filtering out all of the entries for field types other than
-      //     subject_key's since none of them will match.
+      // NB: We're filtering out all of the entries for field types other than the one that
+      //     subject_key matched, since none of them will match.
       entries: self
         .product_entries
         .clone()
         .into_iter()
-        .filter(|e| e.matches_subject_type(subject_key.type_id().clone()))
+        .filter(|e| e.matches_subject_type(field_type))
         .collect(),
     }.run(context.clone())
-      .map(move |product| {
-        let deps = externs::project_multi(&product, &field_name);
-        (subject_key, product, deps)
+      .then(move |product_res| match product_res {
+        Ok(product) => {
+          let deps = project_multi_field(&product, &field_name, &subject_key)?;
+          Ok((subject_key, product, deps))
+        }
+        Err(Failure::Noop(noop)) => Err(throw(&format!(
+          "No source of transitive dependency {}: {:?}",
+          externs::key_to_str(&subject_key),
+          noop
+        ))),
+        Err(failure) => Err(failure),
       })
       .to_boxed()
   }
 }
 
+///
+/// Projects the given field of a product computed for the given subject as a list, failing if
+/// the product does not have the field at all (as opposed to the field being empty).
+///
+fn project_multi_field(product: &Value, field: &str, subject: &Key) -> Result<Vec<Value>, Failure> {
+  if !externs::has_field(product, field) {
+    return Err(throw(&format!(
+      "The {} computed for subject {} does not have a `{}` field.",
+      externs::type_to_str(*externs::key_for(product.clone()).type_id()),
+      externs::key_to_str(subject),
+      field
+    )));
+  }
+  Ok(externs::project_multi(product, field))
+}
+
+///
+/// Returns, for each of the given dependency subjects, the field type that it should be selected
+/// as: its own type if that is one of the field types, and otherwise the first of the field types
+/// that it is a subclass of (as determined by the corresponding `field_type_constraints`). Subjects
+/// which match none of the field types are skipped, and so are matched to None. If no field types
+/// were given, every subject is selected as its own type.
+///
+fn match_field_types(
+  field_types: &[TypeId],
+  field_type_constraints: &[TypeConstraint],
+  dep_subjects: &[Key],
+) -> Vec<Option<TypeId>> {
+  if field_types.is_empty() {
+    return dep_subjects.iter().map(|key| Some(*key.type_id())).collect();
+  }
+  // Check all subjects which are not exactly of a field type in one batch.
+  let checks: Vec<(TypeConstraint, TypeId)> = dep_subjects
+    .iter()
+    .map(|key| *key.type_id())
+    .filter(|type_id| !field_types.contains(type_id))
+    .flat_map(|type_id| {
+      field_type_constraints.iter().map(
+        move |&constraint| (constraint, type_id),
+      )
+    })
+    .collect();
+  let mut satisfied = externs::satisfied_by_types(&checks).into_iter();
+  dep_subjects
+    .iter()
+    .map(|key| {
+      let type_id = *key.type_id();
+      if field_types.contains(&type_id) {
+        return Some(type_id);
+      }
+      let row: Vec<bool> = satisfied.by_ref().take(field_types.len()).collect();
+      row.iter().position(|&s| s).map(|i| field_types[i])
+    })
+    .collect()
+}
+
+///
+/// Filters the given dependency subjects to those which match one of the given field types,
+/// paired with the field type that each matched: see `match_field_types`.
+///
+fn filter_field_types(
+  field_types: &[TypeId],
+  field_type_constraints: &[TypeConstraint],
+  dep_subjects: Vec<Key>,
+) -> Vec<(Key, TypeId)> {
+  let matched = match_field_types(field_types, field_type_constraints, &dep_subjects);
+  dep_subjects
+    .into_iter()
+    .zip(matched)
+    .filter_map(|(key, field_type)| field_type.map(|field_type| (key, field_type)))
+    .collect()
+}
+
 ///
 /// Track states when processing `SelectTransitive` iteratively.
 ///
 #[derive(Debug)]
 struct TransitiveExpansion {
-  // Subjects to be processed.
-  todo: HashSet<Key>,
+  // The direct dependencies of the subject, in declaration order.
+  roots: Vec<Key>,
 
-  // Mapping from processed subject `Key` to its product.
+  // Subjects to be processed, and the field types that they matched.
+  todo: HashMap<Key, TypeId, FNV>,
+
+  // Mapping from processed subject `Key` to its product and the `Key`s of its dependencies.
   // Products will be collected at the end of iterations.
-  outputs: OrderMap<Key, Value>,
+  outputs: HashMap<Key, (Value, Vec<Key>), FNV>,
+}
+
+impl TransitiveExpansion {
+  ///
+  /// Collects the products of all processed subjects, deduplicated, in the pre-order in which
+  /// they are first discovered from the roots. Because the rounds of expansion complete in
+  /// arbitrary order, this is what makes the output deterministic.
+  ///
+  fn ordered_outputs(&self) -> Vec<&Value> {
+    let mut visited: HashSet<&Key, FNV> = HashSet::default();
+    let mut ordered = Vec::with_capacity(self.outputs.len());
+    let mut stack: Vec<&Key> = self.roots.iter().rev().collect();
+    while let Some(key) = stack.pop() {
+      if !visited.insert(key) {
+        continue;
+      }
+      if let Some(&(ref product, ref deps)) = self.outputs.get(key) {
+        ordered.push(product);
+        stack.extend(deps.iter().rev());
+      }
+    }
+    ordered
+  }
 }
 
 impl SelectTransitive {
@@ -640,14 +1220,24 @@ impl SelectTransitive {
       .then(move |dep_product_res| {
         match dep_product_res {
           Ok(dep_product) => {
-            let subject_keys = externs::project_multi(&dep_product, &self.selector.field)
-              .into_iter()
-              .map(|subject| externs::key_for(subject))
-              .collect();
+            let dep_subjects =
+              match project_multi_field(&dep_product, &self.selector.field, &self.subject) {
+                Ok(dep_subjects) => dep_subjects,
+                Err(failure) => return err(failure),
+              };
+            let subjects = filter_field_types(
+              &self.selector.field_types,
+              &self.selector.field_type_constraints,
+              dep_subjects
+                .into_iter()
+                .map(|subject| externs::key_for(subject))
+                .collect(),
+            );
 
             let init = TransitiveExpansion {
-              todo: subject_keys,
-              outputs: OrderMap::default(),
+              roots: subjects.iter().map(|&(key, _)| key).collect(),
+              todo: subjects.into_iter().collect(),
+              outputs: HashMap::default(),
             };
 
             future::loop_fn(init, move |mut expansion| {
@@ -655,15 +1245,28 @@ impl SelectTransitive {
                 expansion
                   .todo
                   .drain()
-                  .map(|subject_key| self.expand_transitive(&context, subject_key))
+                  .map(|(subject_key, field_type)| {
+                    self.expand_transitive(&context, subject_key, field_type)
+                  })
                   .collect::<Vec<_>>()
               });
 
+              let field_types = self.selector.field_types.clone();
+              let field_type_constraints = self.selector.field_type_constraints.clone();
               round.map(move |finished_items| {
                 let mut todo_candidates = Vec::new();
                 for (subject_key, product, more_deps) in finished_items.into_iter() {
-                  expansion.outputs.insert(subject_key, product);
+                  let more_deps = filter_field_types(
+                    &field_types,
+                    &field_type_constraints,
+                    more_deps
+                      .into_iter()
+                      .map(|dep| externs::key_for(dep))
+                      .collect(),
+                  );
+                  let more_dep_keys = more_deps.iter().map(|&(key, _)| key).collect();
                   todo_candidates.extend(more_deps);
+                  expansion.outputs.insert(subject_key, (product, more_dep_keys));
                 }
 
                 // NB enclose with {} to limit the borrowing scope.
@@ -672,8 +1275,7 @@ impl SelectTransitive {
                   expansion.todo.extend(
                     todo_candidates
                       .into_iter()
-                      .map(|dep| externs::key_for(dep))
-                      .filter(|dep_key| !outputs.contains_key(dep_key))
+                      .filter(|&(ref dep_key, _)| !outputs.contains_key(dep_key))
                       .collect::<Vec<_>>(),
                   );
                 }
@@ -685,7 +1287,7 @@ impl SelectTransitive {
                 }
               })
             }).map(|expansion| {
-              externs::store_list(expansion.outputs.values().collect::<Vec<_>>(), false)
+              externs::store_list(expansion.ordered_outputs(), false)
             })
               .to_boxed()
           }
@@ -701,7 +1303,8 @@ pub struct SelectProjection {
   subject: Key,
   variants: Variants,
   selector: selectors::SelectProjection,
-  input_product_entries: rule_graph::Entries,
+  // A Select for each alternative input product, in declared order.
+  inputs: Vec<Select>,
   projected_entries: rule_graph::Entries,
 }
 
@@ -712,12 +1315,23 @@ impl SelectProjection {
     variants: Variants,
     edges: &rule_graph::RuleEdges,
   ) -> SelectProjection {
-    let dep_p_entries = edges.entries_for(&rule_graph::SelectKey::NestedSelect(
-      Selector::SelectProjection(selector.clone()),
-      selectors::Select::without_variant(
-        selector.clone().input_product,
-      ),
-    ));
+    let inputs = selector
+      .input_products
+      .iter()
+      .map(|input_product| {
+        let select = selectors::Select::without_variant(*input_product);
+        let entries = edges.entries_for(&rule_graph::SelectKey::NestedSelect(
+          Selector::SelectProjection(selector.clone()),
+          select.clone(),
+        ));
+        Select {
+          selector: select,
+          subject: subject.clone(),
+          variants: variants.clone(),
+          entries: entries,
+        }
+      })
+      .collect();
     let p_entries = edges.entries_for(&rule_graph::SelectKey::ProjectedNestedSelect(
       Selector::SelectProjection(selector.clone()),
       selector.projected_subject.clone(),
@@ -729,36 +1343,83 @@ impl SelectProjection {
       subject: subject,
       variants: variants,
       selector: selector.clone(),
-      input_product_entries: dep_p_entries,
+      inputs: inputs,
       projected_entries: p_entries,
     }
   }
 }
 
 impl SelectProjection {
+  ///
+  /// Projects the configured field(s) of the input product to construct the projected subject,
+  /// and validates that the result is of the declared projected type.
+  ///
+  fn project(&self, dep_product: &Value) -> Result<Key, Failure> {
+    let fields = &self.selector.fields;
+    let dep_product_key = externs::key_for(dep_product.clone());
+    let projected_subject = if fields.len() == 1 {
+      // Projections of a single field are cached by `externs::project_key`.
+      externs::project_key(
+        &dep_product_key,
+        &fields[0],
+        &self.selector.projected_subject,
+      ).ok_or_else(|| self.missing_field(&dep_product_key, &fields[0]))?
+    } else {
+      for field in fields {
+        if !externs::has_field(dep_product, field) {
+          return Err(self.missing_field(&dep_product_key, field));
+        }
+      }
+      externs::key_for(externs::instantiate(
+        &self.selector.projected_subject,
+        &fields
+          .iter()
+          .map(|field| externs::project_ignoring_type(dep_product, field))
+          .collect::<Vec<_>>(),
+      )?)
+    };
+    if projected_subject.type_id() != &self.selector.projected_subject {
+      return Err(throw(&format!(
+        "Projecting {:?} produced a value of type {}, rather than the declared type {}.",
+        fields,
+        externs::type_to_str(*projected_subject.type_id()),
+        externs::type_to_str(self.selector.projected_subject),
+      )));
+    }
+    Ok(projected_subject)
+  }
+
+  fn missing_field(&self, dep_product: &Key, field: &str) -> Failure {
+    throw(&format!(
+      "The {} computed for subject {} does not have a `{}` field to project.",
+      externs::type_to_str(*dep_product.type_id()),
+      externs::key_to_str(&self.subject),
+      field
+    ))
+  }
+
   fn run(self, context: Context) -> NodeFuture<Value> {
-    // Request the product we need to compute the subject.
-    Select {
-      selector: selectors::Select {
-        product: self.selector.input_product,
-        variant_key: None,
-      },
-      subject: self.subject.clone(),
-      variants: self.variants.clone(),
-      entries: self.input_product_entries.clone(),
-    }.run(context.clone())
+    // Request the product we need to compute the subject: if there are alternative input
+    // products, the first of them which can be computed is used.
+    let input = if self.inputs.len() == 1 {
+      self.inputs[0].clone().run(context.clone())
+    } else {
+      SelectUnion { alternatives: self.inputs.clone() }.run(context.clone())
+    };
+    input
       .then(move |dep_product_res| {
         match dep_product_res {
           Ok(dep_product) => {
-            // And then project the relevant field.
-            let projected_subject = externs::project(
-              &dep_product,
-              &self.selector.field,
-              &self.selector.projected_subject,
-            );
+            // And then project the relevant field(s).
+            let projected_subject = match self.project(&dep_product) {
+              Ok(projected_subject) => projected_subject,
+              Err(failure) => return err(failure),
+            };
+            let product = self.selector.product;
+            let on_missing = self.selector.on_missing;
             Select {
               selector: selectors::Select::without_variant(self.selector.product),
-              subject: externs::key_for(projected_subject),
+              subject: projected_subject,
               variants: self.variants.clone(),
               // NB: Unlike SelectDependencies and SelectTransitive, we don't need to filter by
               // subject here, because there is only one projected type.
@@ -768,7 +1429,11 @@ impl SelectProjection {
                 // If the output product is available, return it.
                 match output_res {
                   Ok(output) => Ok(output),
-                  Err(failure) => Err(was_required(failure)),
+                  Err(noop @ Failure::Noop(_)) if on_missing == OnMissing::Skip => Err(noop),
+                  Err(failure) => Err(was_required(
+                    failure,
+                    || select_str(&projected_subject, &product),
+                  )),
                 }
               })
               .to_boxed()
@@ -780,6 +1445,115 @@ impl SelectProjection {
   }
 }
 
+///
+/// Selects the first of a series of alternative products that can be computed for the subject.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SelectUnion {
+  // A Select for each alternative product, in declared order.
+  alternatives: Vec<Select>,
+}
+
+impl SelectUnion {
+  fn new(
+    selector: selectors::SelectUnion,
+    subject: Key,
+    variants: Variants,
+    edges: &rule_graph::RuleEdges,
+  ) -> SelectUnion {
+    let alternatives = selector
+      .products
+      .iter()
+      .map(|product| {
+        let select = selectors::Select::without_variant(*product);
+        let entries = edges.entries_for(&rule_graph::SelectKey::NestedSelect(
+          Selector::SelectUnion(selector.clone()),
+          select.clone(),
+        ));
+        Select {
+          selector: select,
+          subject: subject.clone(),
+          variants: variants.clone(),
+          entries: entries,
+        }
+      })
+      .collect();
+    SelectUnion { alternatives: alternatives }
+  }
+
+  ///
+  /// Attempts each alternative in order, returning the first value produced. Noops only if all
+  /// alternatives Noop, and propagates any other failure immediately.
+  ///
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    let alternatives: VecDeque<Select> = self.alternatives.into_iter().collect();
+    future::loop_fn(
+      (alternatives, Vec::new()),
+      move |(mut alternatives, mut attempted)| match alternatives.pop_front() {
+        Some(alternative) => {
+          let description = select_str(&alternative.subject, alternative.product());
+          alternative
+            .run(context.clone())
+            .then(move |result| match result {
+              Ok(value) => Ok(future::Loop::Break(value)),
+              Err(Failure::Noop(noop)) => {
+                attempted.push((description, noop));
+                Ok(future::Loop::Continue((alternatives, attempted)))
+              }
+              Err(failure) => Err(failure),
+            })
+            .to_boxed()
+        }
+        None => err(Failure::Noop(Noop::NoTask(attempted))),
+      },
+    ).to_boxed()
+  }
+}
+
+///
+/// Selects the subject itself, without consulting any Tasks.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SelectSubject {
+  subject: Key,
+  selector: selectors::SelectSubject,
+}
+
+impl SelectSubject {
+  fn run(self, _: Context) -> NodeFuture<Value> {
+    let value = externs::val_for(&self.subject);
+    if externs::satisfied_by(&self.selector.product, &value) {
+      ok(value)
+    } else {
+      err(throw(&format!(
+        "Subject {} did not satisfy {}",
+        externs::key_to_str(&self.subject),
+        externs::key_to_str(&self.selector.product.0)
+      )))
+    }
+  }
+}
+
+///
+/// Selects the value configured for a variant key in the (already merged) Variants of the
+/// subject.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SelectVariant {
+  subject: Key,
+  variants: Variants,
+  selector: selectors::SelectVariant,
+}
+
+impl SelectVariant {
+  fn run(self, _: Context) -> NodeFuture<Value> {
+    match self.variants.find(&self.selector.variant_key) {
+      Some(value) => ok(externs::store_bytes(value.as_bytes())),
+      None => err(Failure::Noop(Noop::NoVariant(self.selector.variant_key))),
+    }
+  }
+}
+
 ///
 /// A Node that represents executing a process.
 ///
@@ -1071,7 +1845,8 @@ pub struct Task {
   subject: Key,
   product: TypeConstraint,
   variants: Variants,
-  task: tasks::Task,
+  // Shared with the Tasks registry and RuleGraph: Hash and Eq are those of the Task itself.
+  task: Arc<tasks::Task>,
   entry: rule_graph::Entry,
 }
 
@@ -1084,8 +1859,32 @@ impl Task {
       .expect("edges for task exist.");
     match selector {
       Selector::Select(s) => {
+        let optional = s.optional;
+        let key_none = context.core.tasks.key_none().clone();
         Select::new_with_selector(s, self.subject.clone(), self.variants.clone(), edges)
           .run(context.clone())
+          .then(move |res| match res {
+            Err(Failure::Noop(_)) if optional => Ok(externs::val_for(&key_none)),
+            r => r,
+          })
+          .to_boxed()
+      }
+      Selector::SelectUnion(s) => {
+        SelectUnion::new(s, self.subject.clone(), self.variants.clone(), edges)
+          .run(context.clone())
+      }
+      Selector::SelectSubject(s) => {
+        SelectSubject {
+          subject: self.subject.clone(),
+          selector: s,
+        }.run(context.clone())
+      }
+      Selector::SelectVariant(s) => {
+        SelectVariant {
+          subject: self.subject.clone(),
+          variants: self.variants.clone(),
+          selector: s,
+        }.run(context.clone())
       }
       Selector::SelectDependencies(s) => {
         SelectDependencies::new(s, self.subject.clone(), self.variants.clone(), edges)
@@ -1101,6 +1900,45 @@ impl Task {
       }
     }
   }
+
+  ///
+  /// The Externs are responsible for enforcing the timeout (if any) of a Task's function while
+  /// it runs: once the call has completed, a call that exceeded the timeout fails.
+  ///
+  fn check_timeout(task: &tasks::Task, elapsed: Duration) -> Result<(), Failure> {
+    let elapsed_ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+    match task.timeout_ms {
+      Some(timeout_ms) if elapsed_ms > timeout_ms => Err(throw(&format!(
+        "{} took {}ms, which exceeded its timeout of {}ms.",
+        task.display_name(),
+        elapsed_ms,
+        timeout_ms,
+      ))),
+      _ => Ok(()),
+    }
+  }
+
+  ///
+  /// Validates that the given result of the Task's function satisfies the Task's product (or
+  /// satisfies `has_products`, in which case Select will examine its products).
+  ///
+  fn check_result(
+    has_products: &TypeConstraint,
+    task: &tasks::Task,
+    result: Value,
+  ) -> Result<Value, Failure> {
+    if externs::satisfied_by(&task.product, &result) || externs::satisfied_by(has_products, &result)
+    {
+      Ok(result)
+    } else {
+      Err(throw(&format!(
+        "{} returned a value of type {}, which does not satisfy its declared product {}.",
+        task.display_name(),
+        externs::type_to_str(externs::identify(&result).type_id),
+        externs::key_to_str(&task.product.0),
+      )))
+    }
+  }
 }
 
 impl Node for Task {
@@ -1112,15 +1950,46 @@ impl Node for Task {
         .task
         .clause
         .iter()
-        .map(|selector| self.get(&context, selector.clone()))
+        .map(|selector| {
+          // Don't fail the join if one fails: we report all of the missing inputs at once.
+          self.get(&context, selector.clone()).then(
+            |r| future::ok::<_, Failure>(r),
+          )
+        })
         .collect::<Vec<_>>(),
     );
 
     let task = self.task.clone();
+    let subject = self.subject.clone();
     deps
-      .then(move |deps_result| match deps_result {
-        Ok(deps) => externs::call(&externs::val_for(&task.func.0), &deps),
-        Err(err) => Err(err),
+      .and_then(move |dep_results| {
+        let mut deps = Vec::with_capacity(dep_results.len() + 1);
+        if task.include_subject {
+          deps.push(externs::val_for(&subject));
+        }
+        let mut missing = Vec::new();
+        for (index, (selector, dep_result)) in task.clause.iter().zip(dep_results).enumerate() {
+          match dep_result {
+            Ok(dep) => deps.push(dep),
+            Err(Failure::Noop(noop)) => {
+              missing.push((index, rule_graph::selector_str(selector), noop))
+            }
+            Err(failure) => return Err(failure),
+          }
+        }
+        if !missing.is_empty() {
+          return Err(Failure::Noop(Noop::MissingInputs(missing)));
+        }
+        let start = Instant::now();
+        let result =
+          externs::call_with_timeout(&externs::val_for(&task.func.0), &deps, task.timeout_ms);
+        // A call that exceeded its budget fails as such, even if the function itself raised (as
+        // it will have if the Externs interrupted the call).
+        Task::check_timeout(&task, start.elapsed())
+          .and_then(|()| result)
+          .and_then(|result| {
+            Task::check_result(&context.core.types.has_products, &task, result)
+          })
       })
       .to_boxed()
   }
@@ -1132,6 +2001,11 @@ impl From<Task> for NodeKey {
   }
 }
 
+///
+/// The number of kinds of NodeKey: see `NodeKey::kind_index`.
+///
+pub const NODE_KIND_COUNT: usize = 7;
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum NodeKey {
   DigestFile(DigestFile),
@@ -1144,6 +2018,52 @@ pub enum NodeKey {
 }
 
 impl NodeKey {
+  ///
+  /// Describes this NodeKey without calling out to python (unlike `format`), by rendering Keys as
+  /// their type and value ids. Useful in panics and logging, where python may not be available.
+  ///
+  pub fn describe(&self) -> String {
+    fn keystr(key: &Key) -> String {
+      format!("{}:{}", key.type_id().0, key.id())
+    }
+    let variants = self
+      .variants()
+      .map(|v| format!(", {} variants", v.0.len()))
+      .unwrap_or_else(|| "".to_string());
+    match self {
+      &NodeKey::DigestFile(ref s) => format!("DigestFile({:?})", s.0),
+      &NodeKey::ExecuteProcess(ref s) => format!("ExecuteProcess({:?})", s.0),
+      &NodeKey::ReadLink(ref s) => format!("ReadLink({:?})", s.0),
+      &NodeKey::Scandir(ref s) => format!("Scandir({:?})", s.0),
+      &NodeKey::Select(ref s) => {
+        format!(
+          "Select({}, ={}{})",
+          keystr(&s.subject),
+          s.selector.product.0.id(),
+          variants
+        )
+      }
+      &NodeKey::Task(ref s) => {
+        format!(
+          "Task({}, {}, ={}, {} clauses{})",
+          s.task.func.0.id(),
+          keystr(&s.subject),
+          s.product.0.id(),
+          s.task.clause.len(),
+          variants
+        )
+      }
+      &NodeKey::Snapshot(ref s) => {
+        format!(
+          "Snapshot({}, ={}{})",
+          keystr(&s.subject),
+          s.product.0.id(),
+          variants
+        )
+      }
+    }
+  }
+
   pub fn format(&self) -> String {
     fn keystr(key: &Key) -> String {
       externs::key_to_str(&key)
@@ -1151,6 +2071,9 @@ impl NodeKey {
     fn typstr(tc: &TypeConstraint) -> String {
       externs::key_to_str(&tc.0)
     }
+    let variants = self.variants().map(|v| variants_str(v)).unwrap_or_else(
+      || "".to_string(),
+    );
     match self {
       &NodeKey::DigestFile(ref s) => format!("DigestFile({:?})", s.0),
       &NodeKey::ExecuteProcess(ref s) => format!("ExecuteProcess({:?}", s.0),
@@ -1158,20 +2081,108 @@ impl NodeKey {
       &NodeKey::Scandir(ref s) => format!("Scandir({:?})", s.0),
       &NodeKey::Select(ref s) => {
         format!(
-          "Select({}, {})",
+          "Select({}, {}{}{})",
           keystr(&s.subject),
-          typstr(&s.selector.product)
+          typstr(&s.selector.product),
+          if s.selector.cardinality == Cardinality::Many {
+            ", many=True"
+          } else {
+            ""
+          },
+          variants
         )
       }
       &NodeKey::Task(ref s) => {
         format!(
-          "Task({}, {}, {})",
-          externs::key_to_str(&s.task.func.0),
+          "Task({}, {}, {}{})",
+          s.task.display_name(),
           keystr(&s.subject),
-          typstr(&s.product)
+          typstr(&s.product),
+          variants
+        )
+      }
+      &NodeKey::Snapshot(ref s) => format!("Snapshot({}{})", keystr(&s.subject), variants),
+    }
+  }
+
+  ///
+  /// Like `format`, but additionally spells out the clause of Task nodes, and the selector of
+  /// other Nodes that are computed for a subject.
+  ///
+  pub fn format_verbose(&self) -> String {
+    match self {
+      &NodeKey::Task(ref s) => {
+        format!(
+          "Task({}, {}, {}{}, clause=({}))",
+          s.task.display_name(),
+          externs::key_to_str(&s.subject),
+          externs::key_to_str(&s.product.0),
+          variants_str(&s.variants),
+          s.task
+            .clause
+            .iter()
+            .map(|selector| rule_graph::selector_str(selector))
+            .collect::<Vec<_>>()
+            .join(", ")
         )
       }
-      &NodeKey::Snapshot(ref s) => format!("Snapshot({})", keystr(&s.subject)),
+      n => {
+        match n.selector() {
+          Some(ref selector) => format!("{} via {}", n.format(), rule_graph::selector_str(selector)),
+          None => n.format(),
+        }
+      }
+    }
+  }
+
+  ///
+  /// The Variants of this Node, if it is computed for a subject.
+  ///
+  pub fn variants(&self) -> Option<&Variants> {
+    match self {
+      &NodeKey::Select(ref s) => Some(&s.variants),
+      &NodeKey::Snapshot(ref s) => Some(&s.variants),
+      &NodeKey::Task(ref s) => Some(&s.variants),
+      &NodeKey::DigestFile(..) |
+      &NodeKey::ExecuteProcess(..) |
+      &NodeKey::ReadLink(..) |
+      &NodeKey::Scandir(..) => None,
+    }
+  }
+
+  ///
+  /// The selector that this Node satisfies, if it is computed for a subject. For Task and
+  /// Snapshot nodes, this is a Select for the product that they compute.
+  ///
+  pub fn selector(&self) -> Option<Selector> {
+    match self {
+      &NodeKey::Select(ref s) => Some(Selector::Select(s.selector.clone())),
+      &NodeKey::Snapshot(ref s) => Some(Selector::Select(
+        selectors::Select::without_variant(s.product),
+      )),
+      &NodeKey::Task(ref s) => Some(Selector::Select(
+        selectors::Select::without_variant(s.product),
+      )),
+      &NodeKey::DigestFile(..) |
+      &NodeKey::ExecuteProcess(..) |
+      &NodeKey::ReadLink(..) |
+      &NodeKey::Scandir(..) => None,
+    }
+  }
+
+  ///
+  /// The index of the kind of Node this is, for use in stats. NB: The order of the kinds must
+  /// match `_NODE_KINDS` in scheduler.py.
+  ///
+  pub fn kind_index(&self) -> usize {
+    match self {
+      &NodeKey::DigestFile(..) => 0,
+      &NodeKey::ExecuteProcess(..) => 1,
+      &NodeKey::ReadLink(..) => 2,
+      &NodeKey::Scandir(..) => 3,
+      &NodeKey::Select(..) => 4,
+      &NodeKey::Snapshot(..) => 5,
+      &NodeKey::Task(..) => 6,
     }
   }
 
@@ -1209,6 +2220,17 @@ impl NodeKey {
       &NodeKey::Task { .. } => None,
     }
   }
+
+  ///
+  /// Returns false if the result of this NodeKey may not be reused across executions, meaning that
+  /// it must re-run (along with anything that depends on it) in each execution.
+  ///
+  pub fn is_cacheable(&self) -> bool {
+    match self {
+      &NodeKey::Task(ref s) => s.task.cacheable,
+      _ => true,
+    }
+  }
 }
 
 impl Node for NodeKey {
@@ -1387,3 +2409,358 @@ impl TryFrom<NodeResult> for DirectoryListing {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use core::{Failure, Key, Noop, TypeConstraint, TypeId, Value};
+  use externs;
+  use mock_externs::{MockExterns, mock};
+  use selectors;
+  use tasks::{self, Tasks};
+
+  use super::{Select, Task, match_field_types};
+
+  struct Fixture {
+    mock: &'static MockExterns,
+    product_type: TypeId,
+    product: TypeConstraint,
+    has_products: TypeConstraint,
+    has_products_type: TypeId,
+    tasks: Tasks,
+  }
+
+  ///
+  /// Declares a product type with a subtype, and a HasProducts type. Since the Externs are shared
+  /// between tests, each test declares its own types.
+  ///
+  fn fixture() -> Fixture {
+    let mock = mock();
+    let product_type = mock.new_type("Product");
+    let has_products_type = mock.new_type("HasProducts");
+    let key_none = externs::key_for(externs::eval("None").unwrap());
+    let string_type = *externs::key_for(externs::store_bytes(b"")).type_id();
+    Fixture {
+      mock: mock,
+      product_type: product_type,
+      product: mock.subclasses_of(&[product_type]),
+      has_products: mock.subclasses_of(&[has_products_type]),
+      has_products_type: has_products_type,
+      tasks: Tasks::new(key_none, vec![string_type]),
+    }
+  }
+
+  impl Fixture {
+    fn select(&self) -> Select {
+      Select {
+        subject: externs::key_for(externs::store_i32(0)),
+        variants: Default::default(),
+        selector: selectors::Select::without_variant(self.product),
+        entries: Vec::new(),
+      }
+    }
+
+    fn named(&self, type_id: TypeId, name: &str) -> Value {
+      self
+        .mock
+        .instance(type_id, vec![("name", externs::store_bytes(name.as_bytes()))])
+    }
+
+    fn has_products(&self, products: Vec<Value>) -> Value {
+      let products = externs::store_list(products.iter().collect(), false);
+      self
+        .mock
+        .instance(self.has_products_type, vec![("products", products)])
+    }
+
+    fn select_literal(&self, candidate: Value) -> Result<Option<(usize, Value)>, Failure> {
+      self
+        .select()
+        .select_literal(&self.has_products, &self.tasks, candidate, &None)
+    }
+
+    fn select_literal_variant(
+      &self,
+      candidate: Value,
+      variant_values: &[&str],
+    ) -> Result<Option<(usize, Value)>, Failure> {
+      let variant_values = Some(variant_values.iter().map(|vv| vv.to_string()).collect());
+      self.select().select_literal(
+        &self.has_products,
+        &self.tasks,
+        candidate,
+        &variant_values,
+      )
+    }
+
+    fn task(&self, timeout_ms: Option<u64>) -> tasks::Task {
+      tasks::Task {
+        product: self.product,
+        clause: Vec::new(),
+        func: self.mock.function("produce"),
+        cacheable: true,
+        include_subject: false,
+        priority: 0,
+        timeout_ms: timeout_ms,
+        name: Some("produce_product".to_string()),
+        doc: None,
+      }
+    }
+  }
+
+  fn throw_str(result: Result<Value, Failure>) -> String {
+    match result {
+      Err(Failure::Throw(exc, _, _)) => externs::val_to_str(&exc),
+      Err(f) => panic!("Expected a Throw, got {:?}", f),
+      Ok(v) => panic!("Expected a Throw, got {:?}", v),
+    }
+  }
+
+  fn literal_str(result: Result<Option<(usize, Value)>, Failure>) -> Option<(usize, String)> {
+    result.unwrap().map(
+      |(rank, v)| (rank, externs::val_to_str(&v)),
+    )
+  }
+
+  #[test]
+  fn select_literal_single_is_a() {
+    let f = fixture();
+    let subtype = f.mock.new_subtype("ProductSubtype", f.product_type);
+    let other = f.mock.new_type("Other");
+    let select = f.select();
+
+    assert_eq!(Some(0), select.select_literal_single(&f.named(f.product_type, "a"), &None));
+    assert_eq!(Some(0), select.select_literal_single(&f.named(subtype, "a"), &None));
+    assert_eq!(None, select.select_literal_single(&f.named(other, "a"), &None));
+  }
+
+  #[test]
+  fn select_literal_single_variant_rank() {
+    let f = fixture();
+    let select = f.select();
+    let variant_values = Some(vec!["b".to_string(), "a".to_string()]);
+
+    let rank = |name: &str| {
+      select.select_literal_single(&f.named(f.product_type, name), &variant_values)
+    };
+    assert_eq!(Some(0), rank("b"));
+    assert_eq!(Some(1), rank("a"));
+    assert_eq!(None, rank("c"));
+  }
+
+  #[test]
+  fn select_literal_variant_name() {
+    let f = fixture();
+    // The candidates have the same type, and differ only by name.
+    let select = |name: &str| {
+      literal_str(f.select_literal_variant(f.named(f.product_type, name), &["b"]))
+    };
+
+    assert_eq!(None, select("a"));
+    assert_eq!(Some((0, "Product(name=b)".to_string())), select("b"));
+  }
+
+  #[test]
+  fn select_literal_has_a() {
+    let f = fixture();
+    let other = f.mock.new_type("Other");
+    let candidate = f.has_products(vec![f.named(other, "x"), f.named(f.product_type, "a")]);
+
+    assert_eq!(
+      Some((0, "Product(name=a)".to_string())),
+      literal_str(f.select_literal(candidate))
+    );
+  }
+
+  #[test]
+  fn select_literal_has_a_variant() {
+    let f = fixture();
+    let candidate =
+      f.has_products(vec![f.named(f.product_type, "a"), f.named(f.product_type, "b")]);
+
+    // Without a variant the products would conflict, but only one of them matches the variant.
+    assert_eq!(
+      Some((0, "Product(name=b)".to_string())),
+      literal_str(f.select_literal_variant(candidate, &["b"]))
+    );
+  }
+
+  #[test]
+  fn select_literal_empty_has_a() {
+    let f = fixture();
+
+    // An empty products field falls through (to the configured tasks).
+    assert_eq!(None, literal_str(f.select_literal(f.has_products(vec![]))));
+  }
+
+  #[test]
+  fn select_literal_nested_has_a() {
+    let f = fixture();
+    let inner = f.has_products(vec![f.named(f.product_type, "a")]);
+    let candidate = f.has_products(vec![inner]);
+
+    assert_eq!(
+      Some((0, "Product(name=a)".to_string())),
+      literal_str(f.select_literal(candidate))
+    );
+  }
+
+  #[test]
+  fn select_literal_batches_type_checks() {
+    let f = fixture();
+    let other = f.mock.new_type("Other");
+    let candidate = f.has_products(vec![
+      f.named(other, "x"),
+      f.named(other, "y"),
+      f.named(f.product_type, "a"),
+    ]);
+
+    assert_eq!(
+      Some((0, "Product(name=a)".to_string())),
+      literal_str(f.select_literal(candidate))
+    );
+    // The candidate itself is checked by value, and then its products by type in one batch.
+    assert_eq!(1, f.mock.satisfied_by_type_calls(&f.product));
+  }
+
+  #[test]
+  fn select_literal_no_match() {
+    let f = fixture();
+    let other = f.mock.new_type("Other");
+
+    assert_eq!(None, literal_str(f.select_literal(f.named(other, "x"))));
+  }
+
+  #[test]
+  fn select_literal_conflict() {
+    let f = fixture();
+    let candidate =
+      f.has_products(vec![f.named(f.product_type, "a"), f.named(f.product_type, "b")]);
+
+    let msg = throw_str(f.select_literal(candidate).map(|_| externs::store_i32(0)));
+    let expected = "Conflicting values configured for subject 0 and type SubclassesOf(Product):";
+    assert!(msg.starts_with(expected), msg);
+  }
+
+  #[test]
+  fn select_literal_mergeable() {
+    let mut f = fixture();
+    let a = externs::store_list(vec![&f.named(f.product_type, "a")], false);
+    let b = externs::store_list(vec![&f.named(f.product_type, "b")], false);
+    // Lists are only selected when they satisfy the product.
+    f.product = f.mock.exactly(&[*externs::identify(&a).type_id]);
+    f.tasks.mergeable_add(f.product).unwrap();
+
+    assert_eq!(
+      Some((0, "[Product(name=a), Product(name=b)]".to_string())),
+      literal_str(f.select_literal(f.has_products(vec![a, b])))
+    );
+  }
+
+  #[test]
+  fn preferred_keeps_lowest_rank() {
+    assert_eq!(vec!["b", "d"], Select::preferred(vec![(1, "a"), (0, "b"), (2, "c"), (0, "d")]));
+    assert_eq!(Vec::<&str>::new(), Select::preferred(Vec::<(usize, &str)>::new()));
+  }
+
+  #[test]
+  fn attempted_noop() {
+    let no_task = ("a".to_string(), Noop::NoTask(vec![]));
+    let cycle = ("b".to_string(), Noop::Cycle);
+
+    assert_eq!(Noop::Cycle, Select::attempted_noop(vec![no_task.clone(), cycle]));
+    assert_eq!(
+      Noop::NoTask(vec![no_task.clone()]),
+      Select::attempted_noop(vec![no_task.clone()])
+    );
+    assert_eq!(Noop::NoTask(vec![]), Select::attempted_noop(vec![]));
+  }
+
+  #[test]
+  fn task_check_result() {
+    let f = fixture();
+    let other = f.mock.new_type("Other");
+    let task = f.task(None);
+
+    assert!(Task::check_result(&f.has_products, &task, f.named(f.product_type, "a")).is_ok());
+    assert!(Task::check_result(&f.has_products, &task, f.has_products(vec![])).is_ok());
+    assert_eq!(
+      "produce_product returned a value of type Other, which does not satisfy its declared \
+       product SubclassesOf(Product).",
+      throw_str(Task::check_result(&f.has_products, &task, f.named(other, "x")))
+    );
+  }
+
+  #[test]
+  fn task_check_timeout() {
+    let f = fixture();
+
+    assert!(Task::check_timeout(&f.task(None), Duration::from_secs(60)).is_ok());
+    assert!(Task::check_timeout(&f.task(Some(100)), Duration::from_millis(100)).is_ok());
+    assert_eq!(
+      "produce_product took 101ms, which exceeded its timeout of 100ms.",
+      throw_str(
+        Task::check_timeout(&f.task(Some(100)), Duration::from_millis(101))
+          .map(|()| externs::store_i32(0)),
+      )
+    );
+  }
+
+  #[test]
+  fn keys_are_interned_by_value() {
+    let f = fixture();
+    let key = |name: &str| -> Key { externs::key_for(externs::store_bytes(name.as_bytes())) };
+
+    assert_eq!(key("a"), key("a"));
+    assert!(key("a") != key("b"));
+    // Instances are equal by identity.
+    let instance_key = || externs::key_for(f.named(f.product_type, "a"));
+    assert!(instance_key() != instance_key());
+  }
+
+  #[test]
+  fn match_field_types_of_mixed_subjects() {
+    let f = fixture();
+    let other = f.mock.new_type("Other");
+    let sub = f.mock.new_subtype("SubProduct", f.product_type);
+    let field_types = vec![f.product_type];
+    let constraints = vec![f.mock.subclasses_of(&[f.product_type])];
+    let keys: Vec<Key> = vec![
+      f.named(f.product_type, "a"),
+      f.named(other, "b"),
+      f.named(sub, "c"),
+    ].into_iter()
+      .map(|value| externs::key_for(value))
+      .collect();
+
+    // Subclasses of a field type are selected as the field type: other types are skipped.
+    assert_eq!(
+      vec![Some(f.product_type), None, Some(f.product_type)],
+      match_field_types(&field_types, &constraints, &keys)
+    );
+    // Without field types, every subject is selected as its own type.
+    assert_eq!(
+      vec![Some(f.product_type), Some(other), Some(sub)],
+      match_field_types(&[], &[], &keys)
+    );
+  }
+
+  #[test]
+  fn match_field_types_prefers_exact_types() {
+    let f = fixture();
+    let sub = f.mock.new_subtype("SubProduct", f.product_type);
+    let field_types = vec![f.product_type, sub];
+    let constraints = vec![
+      f.mock.subclasses_of(&[f.product_type]),
+      f.mock.subclasses_of(&[sub]),
+    ];
+    let keys = vec![externs::key_for(f.named(sub, "a"))];
+
+    // Although it is a subclass of the first field type, the subject is exactly the second.
+    assert_eq!(
+      vec![Some(sub)],
+      match_field_types(&field_types, &constraints, &keys)
+    );
+  }
+}
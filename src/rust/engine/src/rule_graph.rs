@@ -6,10 +6,11 @@ use std::collections::{hash_map, HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 use std::fmt;
 use std::io;
+use std::sync::Arc;
 
 use core::{ANY_TYPE, Function, Key, TypeConstraint, TypeId, Value};
 use externs;
-use selectors::{Select, SelectDependencies, SelectTransitive, Selector};
+use selectors::{Cardinality, OnMissing, Select, SelectDependencies, SelectTransitive, Selector};
 use tasks::{Task, Tasks};
 
 #[derive(Eq, Hash, PartialEq, Clone, Debug)]
@@ -25,7 +26,7 @@ pub enum Entry {
   Unreachable {
     // NB: unreachable is an error type, it might be better to name it error, but currently
     //     unreachable is the only error entry type.
-    rule: Task,
+    rule: Arc<Task>,
     reason: Diagnostic,
   },
 }
@@ -57,7 +58,7 @@ impl From<RootEntry> for Entry {
 #[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub struct InnerEntry {
   subject_type: TypeId,
-  rule: Task,
+  rule: Arc<Task>,
 }
 
 impl From<InnerEntry> for Entry {
@@ -67,7 +68,7 @@ impl From<InnerEntry> for Entry {
 }
 
 impl Entry {
-  fn new_inner(subject_type: TypeId, rule: &Task) -> Entry {
+  fn new_inner(subject_type: TypeId, rule: &Arc<Task>) -> Entry {
     Entry::InnerEntry(InnerEntry {
       subject_type: subject_type,
       rule: rule.clone(),
@@ -78,7 +79,7 @@ impl Entry {
     Entry::SubjectIsProduct { subject_type: subject_type }
   }
 
-  fn new_unreachable(rule: &Task) -> Entry {
+  fn new_unreachable(rule: &Arc<Task>) -> Entry {
     Entry::Unreachable {
       rule: rule.clone(),
       reason: Diagnostic {
@@ -125,7 +126,7 @@ impl Entry {
     }
   }
 
-  fn rule(&self) -> &Task {
+  fn rule(&self) -> &Arc<Task> {
     match self {
       &Entry::InnerEntry(ref inner) => &inner.rule,
       &Entry::Unreachable { ref rule, .. } => rule,
@@ -269,15 +270,14 @@ impl<'t> GraphMaker<'t> {
       .keys()
       .map(|f| f.rule().clone())
       .collect();
-    let unreachable_rules: HashSet<_> = self
+    let unreachable_rules: Vec<&Arc<Task>> = self
       .tasks
       .all_tasks()
-      .iter()
-      .filter(|r| !rules_in_graph.contains(r))
+      .into_iter()
+      .filter(|r| !rules_in_graph.contains(*r))
       .filter(|r| {
-        !unfulfillable_discovered_during_construction.contains(r)
+        !unfulfillable_discovered_during_construction.contains(*r)
       })
-      .map(|&r| r)
       .collect();
 
     for rule in unreachable_rules {
@@ -320,9 +320,14 @@ impl<'t> GraphMaker<'t> {
         }
       }
       let mut was_unfulfillable = false;
+      let clause: &[Selector] = match entry {
+        Entry::InnerEntry(InnerEntry { ref rule, .. }) => &rule.clause,
+        Entry::Root(RootEntry { ref clause, .. }) => clause,
+        _ => &[],
+      };
       match entry {
-        Entry::InnerEntry(InnerEntry { rule: Task { ref clause, .. }, .. }) |
-        Entry::Root(RootEntry { ref clause, .. }) => {
+        Entry::InnerEntry(_) |
+        Entry::Root(_) => {
           for selector in clause {
             match selector {
               &Selector::Select(ref select) => {
@@ -330,6 +335,10 @@ impl<'t> GraphMaker<'t> {
                 let rules_or_literals_for_selector =
                   rhs_for_select(&self.tasks, entry.subject_type(), &select);
                 if rules_or_literals_for_selector.is_empty() {
+                  if select.optional {
+                    // An optional Select with no matches will always provide None.
+                    continue;
+                  }
                   mark_unfulfillable(
                     &mut unfulfillable_rules,
                     &entry,
@@ -349,6 +358,59 @@ impl<'t> GraphMaker<'t> {
                   rules_or_literals_for_selector,
                 );
               }
+              &Selector::SelectUnion(ref union) => {
+                let alternatives: Vec<(Select, Entries)> = union
+                  .products
+                  .iter()
+                  .map(|product| {
+                    let select = Select::without_variant(*product);
+                    let rules_or_literals =
+                      rhs_for_select(&self.tasks, entry.subject_type(), &select);
+                    (select, rules_or_literals)
+                  })
+                  .filter(|&(_, ref rules_or_literals)| !rules_or_literals.is_empty())
+                  .collect();
+                if alternatives.is_empty() {
+                  mark_unfulfillable(
+                    &mut unfulfillable_rules,
+                    &entry,
+                    entry.subject_type(),
+                    format!("no matches for {}", selector_str(selector)),
+                  );
+                  was_unfulfillable = true;
+                  continue;
+                }
+                for (select, rules_or_literals) in alternatives {
+                  add_rules_to_graph(
+                    &mut rules_to_traverse,
+                    &mut rule_dependency_edges,
+                    &mut unfulfillable_rules,
+                    &mut root_rule_dependency_edges,
+                    &entry,
+                    SelectKey::NestedSelect(selector.clone(), select),
+                    rules_or_literals,
+                  );
+                }
+              }
+              &Selector::SelectSubject(ref s) => {
+                // NB: The subject is provided directly, so there are no rules to add.
+                if !externs::satisfied_by_type(&s.product, &entry.subject_type()) {
+                  mark_unfulfillable(
+                    &mut unfulfillable_rules,
+                    &entry,
+                    entry.subject_type(),
+                    format!(
+                      "subject of type {} does not satisfy {}",
+                      type_str(entry.subject_type()),
+                      selector_str(selector)
+                    ),
+                  );
+                  was_unfulfillable = true;
+                }
+              }
+              &Selector::SelectVariant(_) => {
+                // NB: Variants are provided by the subject's Select, so there are no rules to add.
+              }
               &Selector::SelectDependencies(SelectDependencies {
                                               ref product,
                                               ref dep_product,
@@ -386,10 +448,7 @@ impl<'t> GraphMaker<'t> {
                   let rules_for_field_subjects = rhs_for_select(
                     &self.tasks,
                     field_type.clone(),
-                    &Select {
-                      product: *product,
-                      variant_key: None,
-                    },
+                    &Select::without_variant(*product),
                   );
                   rules_for_dependencies.extend(rules_for_field_subjects);
                 }
@@ -434,23 +493,32 @@ impl<'t> GraphMaker<'t> {
                 );
               }
               &Selector::SelectProjection(ref select) => {
-                let initial_selector = select.input_product;
-                let initial_rules_or_literals = rhs_for_select(
-                  &self.tasks,
-                  entry.subject_type(),
-                  &Select {
-                    product: initial_selector,
-                    variant_key: None,
-                  },
-                );
-                if initial_rules_or_literals.is_empty() {
+                let initial_alternatives: Vec<(Select, Entries)> = select
+                  .input_products
+                  .iter()
+                  .map(|input_product| {
+                    let initial_select = Select::without_variant(*input_product);
+                    let rules_or_literals =
+                      rhs_for_select(&self.tasks, entry.subject_type(), &initial_select);
+                    (initial_select, rules_or_literals)
+                  })
+                  .filter(|&(_, ref rules_or_literals)| !rules_or_literals.is_empty())
+                  .collect();
+                if initial_alternatives.is_empty() {
                   mark_unfulfillable(
                     &mut unfulfillable_rules,
                     &entry,
                     entry.subject_type(),
                     format!(
                       "no matches for {} when resolving {}",
-                      selector_str(&Selector::Select(Select::without_variant(initial_selector))),
+                      select
+                        .input_products
+                        .iter()
+                        .map(|&input_product| {
+                          selector_str(&Selector::Select(Select::without_variant(input_product)))
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" or "),
                       selector_str(selector)
                     ),
                   );
@@ -478,18 +546,17 @@ impl<'t> GraphMaker<'t> {
                   continue;
 
                 }
-                add_rules_to_graph(
-                  &mut rules_to_traverse,
-                  &mut rule_dependency_edges,
-                  &mut unfulfillable_rules,
-                  &mut root_rule_dependency_edges,
-                  &entry,
-                  SelectKey::NestedSelect(
-                    selector.clone(),
-                    Select::without_variant(initial_selector),
-                  ),
-                  initial_rules_or_literals,
-                );
+                for (initial_select, rules_or_literals) in initial_alternatives {
+                  add_rules_to_graph(
+                    &mut rules_to_traverse,
+                    &mut rule_dependency_edges,
+                    &mut unfulfillable_rules,
+                    &mut root_rule_dependency_edges,
+                    &entry,
+                    SelectKey::NestedSelect(selector.clone(), initial_select),
+                    rules_or_literals,
+                  );
+                }
                 add_rules_to_graph(
                   &mut rules_to_traverse,
                   &mut rule_dependency_edges,
@@ -605,21 +672,15 @@ impl<'t> GraphMaker<'t> {
     subject_type: &TypeId,
     product_type: &TypeConstraint,
   ) -> Option<RootEntry> {
-    self
-      .tasks
-      .gen_tasks(product_type)
-      .and_then(|tasks| if !tasks.is_empty() { Some(tasks) } else { None })
-      .map(|_| {
-        RootEntry {
-          subject_type: subject_type.clone(),
-          clause: vec![
-            Selector::Select(Select {
-              product: product_type.clone(),
-              variant_key: None,
-            }),
-          ],
-        }
-      })
+    if self.tasks.gen_tasks(product_type).is_empty() {
+      return None;
+    }
+    Some(RootEntry {
+      subject_type: subject_type.clone(),
+      clause: vec![
+        Selector::Select(Select::without_variant(product_type.clone())),
+      ],
+    })
   }
 }
 
@@ -661,7 +722,7 @@ fn to_val_from_func(func: &Function) -> Value {
   externs::val_for(&func.0)
 }
 
-fn function_str(func: &Function) -> String {
+pub fn function_str(func: &Function) -> String {
   let as_val = to_val_from_func(func);
   val_name(&as_val)
 }
@@ -682,11 +743,60 @@ fn val_name(val: &Value) -> String {
 pub fn selector_str(selector: &Selector) -> String {
   match selector {
     &Selector::Select(ref s) => {
-      format!("Select({})", type_constraint_str(s.product)).to_string() // TODO variant key
+      // A Select with a variant key is rendered like the python SelectVariant that it represents.
+      let variant = match s.variant_key {
+        Some(ref variant_key) => {
+          format!(
+            ", '{}'{}",
+            variant_key,
+            match s.default_variant_value {
+              Some(ref default) => format!(", default_value='{}'", default),
+              None => "".to_string(),
+            }
+          )
+        }
+        None => "".to_string(),
+      };
+      format!(
+        "{}({}{}{}{})",
+        if s.variant_key.is_some() {
+          "SelectVariant"
+        } else {
+          "Select"
+        },
+        type_constraint_str(s.product),
+        variant,
+        if s.optional { ", optional=True" } else { "" },
+        if s.cardinality == Cardinality::Many {
+          ", many=True"
+        } else {
+          ""
+        }
+      )
+    }
+    &Selector::SelectUnion(ref s) => {
+      format!(
+        "SelectUnion({})",
+        s.products
+          .iter()
+          .map(|&p| type_constraint_str(p))
+          .collect::<Vec<_>>()
+          .join(", ")
+      )
+    }
+    &Selector::SelectSubject(ref s) => {
+      format!("SelectSubject({})", type_constraint_str(s.product))
+    }
+    &Selector::SelectVariant(ref s) => {
+      format!(
+        "SelectVariant({}, '{}')",
+        type_constraint_str(s.product),
+        s.variant_key
+      )
     }
     &Selector::SelectDependencies(ref s) => {
       format!(
-        "{}({}, {}, {}field_types=({},))",
+        "{}({}, {}, {}field_types=({},){}{})",
         "SelectDependencies",
         type_constraint_str(s.product),
         type_constraint_str(s.dep_product),
@@ -699,7 +809,13 @@ pub fn selector_str(selector: &Selector) -> String {
           .iter()
           .map(|&f| type_str(f))
           .collect::<Vec<String>>()
-          .join(", ")
+          .join(", "),
+        if s.on_missing == OnMissing::Skip {
+          ", skip_missing=True"
+        } else {
+          ""
+        },
+        if s.skip_self { "" } else { ", skip_self=False" }
       )
     }
     &Selector::SelectTransitive(ref s) => {
@@ -721,12 +837,37 @@ pub fn selector_str(selector: &Selector) -> String {
       )
     }
     &Selector::SelectProjection(ref s) => {
-      format!("SelectProjection({}, {}, '{}', {})",
-                                                  type_constraint_str(s.product),
-                                                  type_str(s.projected_subject),
-                                                  s.field,
-                                                  type_constraint_str(s.input_product),
-    )
+      let fields = s.fields
+        .iter()
+        .map(|f| format!("'{}'", f))
+        .collect::<Vec<_>>();
+      format!(
+        "SelectProjection({}, {}, {}, {}{})",
+        type_constraint_str(s.product),
+        type_str(s.projected_subject),
+        if fields.len() == 1 {
+          fields[0].clone()
+        } else {
+          format!("({})", fields.join(", "))
+        },
+        if s.input_products.len() == 1 {
+          type_constraint_str(s.input_products[0])
+        } else {
+          format!(
+            "({})",
+            s.input_products
+              .iter()
+              .map(|&p| type_constraint_str(p))
+              .collect::<Vec<_>>()
+              .join(", ")
+          )
+        },
+        if s.on_missing == OnMissing::Skip {
+          ", skip_missing=True"
+        } else {
+          ""
+        }
+      )
     }
   }
 }
@@ -769,7 +910,7 @@ fn entry_str(entry: &Entry) -> String {
   }
 }
 
-fn task_display(task: &Task) -> String {
+pub fn task_display(task: &Task) -> String {
   let product = type_constraint_str(task.product);
   let mut clause_portion = task
     .clause
@@ -782,8 +923,7 @@ fn task_display(task: &Task) -> String {
   } else {
     clause_portion = format!("({})", clause_portion)
   }
-  let function_name = function_str(&&task.func);
-  format!("({}, {}, {})", product, clause_portion, function_name).to_string()
+  format!("({}, {}, {})", product, clause_portion, task.display_name()).to_string()
 }
 
 impl RuleGraph {
@@ -802,7 +942,7 @@ impl RuleGraph {
     self.root_dependencies.get(&root).map(|e| e.clone())
   }
 
-  pub fn task_for_inner(&self, entry: &Entry) -> Task {
+  pub fn task_for_inner(&self, entry: &Entry) -> Arc<Task> {
     if let &Entry::InnerEntry(ref inner) = entry {
       inner.rule.clone()
     } else {
@@ -832,9 +972,41 @@ impl RuleGraph {
     }
   }
 
+  ///
+  /// Checks that the product of each of the given goals has at least one producer for at least
+  /// one of the root subject types.
+  ///
+  pub fn validate_goals(&self, goals: &[(String, TypeConstraint)]) -> Result<(), String> {
+    let msgs: Vec<String> = goals
+      .iter()
+      .filter(|&&(_, product)| {
+        !self.root_subject_types.iter().any(|&subject_type| {
+          let selector = Selector::Select(Select::without_variant(product));
+          self
+            .find_root_edges(subject_type, selector)
+            .map(|edges| !edges.is_empty())
+            .unwrap_or(false)
+        })
+      })
+      .map(|&(ref name, product)| {
+        format!(
+          "no task for product used by goal \"{}\": {}",
+          name,
+          type_constraint_str(product)
+        )
+      })
+      .collect();
+    if msgs.is_empty() {
+      Ok(())
+    } else {
+      Err(msgs.join("\n"))
+    }
+  }
+
   fn build_error_msg(&self) -> String {
     // TODO the rule display is really unfriendly right now. Next up should be to improve it.
-    let mut collated_errors: HashMap<Task, HashMap<String, HashSet<TypeId>>> = HashMap::new();
+    let mut collated_errors: HashMap<Arc<Task>, HashMap<String, HashSet<TypeId>>> =
+      HashMap::new();
 
     let used_rules: HashSet<_> = self
       .rule_dependency_edges
@@ -1076,15 +1248,11 @@ fn rhs_for_select(tasks: &Tasks, subject_type: TypeId, select: &Select) -> Entri
   } else if let Some(&(ref key, _)) = tasks.gen_singleton(&select.product) {
     vec![Entry::new_singleton(key.clone(), select.product.clone())]
   } else {
-    match tasks.gen_tasks(&select.product) {
-      Some(ref matching_tasks) => {
-        matching_tasks
-          .iter()
-          .map(|t| Entry::new_inner(subject_type, t))
-          .collect()
-      }
-      None => vec![],
-    }
+    tasks
+      .gen_tasks(&select.product)
+      .into_iter()
+      .map(|t| Entry::new_inner(subject_type, t))
+      .collect()
   }
 }
 
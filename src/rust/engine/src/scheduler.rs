@@ -151,6 +151,13 @@ impl Scheduler {
     &mut self,
     request: &'e ExecutionRequest,
   ) -> Vec<(&'e Key, &'e TypeConstraint, RootResult)> {
+    // Uncacheable Nodes may not be reused from a previous execution: re-run them, and clear the
+    // dependents of any whose results changed.
+    let invalidated = self.core.graph.rerun_uncacheable(&self.core);
+    if invalidated > 0 {
+      debug!("Cleared {} dependents of changed uncacheable nodes.", invalidated);
+    }
+
     // Bootstrap tasks for the roots, and then wait for all of them.
     debug!("Launching {} roots.", request.roots.len());
 
@@ -7,6 +7,23 @@ use core::{Field, TypeConstraint, TypeId};
 pub struct Select {
   pub product: TypeConstraint,
   pub variant_key: Option<String>,
+  // The value to match if the variant_key is not configured in the subject's variants.
+  pub default_variant_value: Option<String>,
+  // If true, a missing product is provided to the Task as None rather than causing it to Noop.
+  pub optional: bool,
+  pub cardinality: Cardinality,
+}
+
+///
+/// How many values a Select may produce for its product.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Cardinality {
+  // Exactly one value must be produced: multiple values are a conflict (unless the product is
+  // mergeable).
+  One,
+  // At least one value must be produced: all values are selected, as a list.
+  Many,
 }
 
 impl Select {
@@ -14,16 +31,55 @@ impl Select {
     Select {
       product: product,
       variant_key: None,
+      default_variant_value: None,
+      optional: false,
+      cardinality: Cardinality::One,
     }
   }
 }
 
+///
+/// Selects the value configured for `variant_key` in the Variants of the subject, rather than
+/// a product of the subject.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SelectVariant {
+  pub product: TypeConstraint,
+  pub variant_key: String,
+}
+
+///
+/// Selects the subject itself, which must satisfy the given product constraint.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SelectSubject {
+  pub product: TypeConstraint,
+}
+
+///
+/// What a SelectDependencies does when one of its dependencies cannot provide the product, or
+/// what a SelectProjection does when its projected subject cannot provide the product.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OnMissing {
+  // Fail the selection.
+  Throw,
+  // Omit the dependency from the selected list (or for a projection, Noop).
+  Skip,
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct SelectDependencies {
   pub product: TypeConstraint,
   pub dep_product: TypeConstraint,
   pub field: Field,
+  // The types of the members of the field to select (or all members, if empty), along with
+  // constraints satisfied by each of those types and their subclasses, in the same order.
   pub field_types: Vec<TypeId>,
+  pub field_type_constraints: Vec<TypeConstraint>,
+  pub on_missing: OnMissing,
+  // If true, the subject is skipped if it appears in its own dependency list.
+  pub skip_self: bool,
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -31,7 +87,9 @@ pub struct SelectTransitive {
   pub product: TypeConstraint,
   pub dep_product: TypeConstraint,
   pub field: Field,
+  // As for SelectDependencies.
   pub field_types: Vec<TypeId>,
+  pub field_type_constraints: Vec<TypeConstraint>,
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -41,13 +99,31 @@ pub struct SelectProjection {
   // needs to construct an instance of the type if the result doesn't match, we use
   // a concrete type here.
   pub projected_subject: TypeId,
-  pub field: Field,
-  pub input_product: TypeConstraint,
+  // The fields to project from the input product: a single field is used as the projected
+  // subject if it is already of the projected type, while multiple fields are passed to the
+  // constructor of the projected type.
+  pub fields: Vec<Field>,
+  // Alternative products to project the fields from, attempted in declared order: the first
+  // product which can be computed for the subject is used.
+  pub input_products: Vec<TypeConstraint>,
+  pub on_missing: OnMissing,
+}
+
+///
+/// Selects the first of the given alternative products (in declared order) that can be
+/// computed for the subject.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SelectUnion {
+  pub products: Vec<TypeConstraint>,
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Selector {
   Select(Select),
+  SelectSubject(SelectSubject),
+  SelectVariant(SelectVariant),
+  SelectUnion(SelectUnion),
   SelectDependencies(SelectDependencies),
   SelectTransitive(SelectTransitive),
   SelectProjection(SelectProjection),
@@ -1,19 +1,233 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::cmp;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 
-use core::{Field, Function, FNV, Key, TypeConstraint, TypeId, Value};
-use externs;
-use selectors::{Selector, Select, SelectDependencies, SelectProjection, SelectTransitive};
+use core::{ANY_TYPE, Field, Function, FNV, Key, TypeConstraint, TypeId, Value};
+use externs::{self, SelectorRecord, TaskRecord, SELECTOR_RECORD_SELECT,
+              SELECTOR_RECORD_SELECT_SUBJECT};
+use hashing;
+use rule_graph;
+use selectors::{Cardinality, OnMissing, Selector, Select, SelectDependencies, SelectProjection,
+                SelectSubject, SelectTransitive, SelectUnion, SelectVariant};
 
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct Task {
   pub product: TypeConstraint,
   pub clause: Vec<Selector>,
   pub func: Function,
   pub cacheable: bool,
+  // If true, the subject is passed to the function before the values selected by the clause.
+  pub include_subject: bool,
+  // Tasks with a higher priority are attempted before other Tasks for the same product. Priority
+  // does not participate in equality, so re-registering a Task with a different priority is
+  // still a double-registration.
+  pub priority: i32,
+  // An optional budget for a single call of the function, which is passed to the Externs to
+  // enforce: like priority, it does not participate in equality.
+  pub timeout_ms: Option<u64>,
+  // An optional human-readable name, used only for display: it does not participate in equality.
+  pub name: Option<String>,
+  // Optional documentation (usually the docstring of the function), used only for display: it
+  // does not participate in equality.
+  pub doc: Option<String>,
+}
+
+impl Task {
+  ///
+  /// The name of this Task for use in diagnostics: the name it was registered with, if any, and
+  /// otherwise the name of its function.
+  ///
+  pub fn display_name(&self) -> String {
+    self
+      .name
+      .clone()
+      .unwrap_or_else(|| rule_graph::function_str(&self.func))
+  }
+
+  ///
+  /// The first non-empty line of the documentation of this Task, if any.
+  ///
+  pub fn doc_summary(&self) -> Option<&str> {
+    self.doc.as_ref().and_then(|doc| {
+      doc.lines().map(|line| line.trim()).find(|line| !line.is_empty())
+    })
+  }
+}
+
+impl PartialEq for Task {
+  fn eq(&self, other: &Task) -> bool {
+    self.product == other.product && self.clause == other.clause && self.func == other.func &&
+      self.cacheable == other.cacheable && self.include_subject == other.include_subject
+  }
+}
+
+impl Eq for Task {}
+
+impl Hash for Task {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.product.hash(state);
+    self.clause.hash(state);
+    self.func.hash(state);
+    self.cacheable.hash(state);
+    self.include_subject.hash(state);
+  }
+}
+
+///
+/// The options of a Task which is begun by `builder_begin` or `task_begin`: see the fields of Task
+/// and PreparingTask for their meanings.
+///
+#[derive(Clone, Debug)]
+pub struct TaskOptions {
+  pub cacheable: bool,
+  pub include_subject: bool,
+  pub priority: i32,
+  pub allow_empty: bool,
+  pub allow_self_select: bool,
+  pub shadow: bool,
+  pub timeout_ms: Option<u64>,
+  pub name: Option<String>,
+  pub doc: Option<String>,
+}
+
+///
+/// Identifies a Task which is in the process of being built. Ids are never reused.
+///
+pub type TaskBuilderId = u64;
+
+///
+/// The TaskBuilderId that refers to the Task begun by the legacy `task_begin` method, rather than
+/// to a Task begun by `builder_begin`.
+///
+pub const LEGACY_TASK_BUILDER: TaskBuilderId = 0;
+
+///
+/// An error in the registration of a Task or Singleton: these are returned rather than panicking,
+/// because the registration methods are called across the C api.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TaskError {
+  // A Task was begun before the previous Task was ended.
+  NotEnded,
+  // A clause was added to, or an end was requested for, a Task that was never begun.
+  NotBegun,
+  // A clause was added to, or a finish was requested for, a TaskBuilderId which was never begun
+  // or which was already finished.
+  UnknownBuilder(TaskBuilderId),
+  // An equal Task or a Singleton was already registered for the product, or a Task and a
+  // Singleton were both registered for the product.
+  DoubleRegistered(String),
+  // A clause or Singleton was malformed.
+  Invalid(String),
+  // The registry was mutated after it was frozen.
+  Frozen,
+}
+
+impl fmt::Display for TaskError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &TaskError::NotEnded => {
+        write!(
+          f,
+          "Must `end()` the previous task creation before beginning a new one!"
+        )
+      }
+      &TaskError::NotBegun => {
+        write!(
+          f,
+          "Must `begin()` a task creation before adding clauses or ending it!"
+        )
+      }
+      &TaskError::UnknownBuilder(id) => {
+        write!(
+          f,
+          "No task is being built with id {}: it was never begun, or was already finished.",
+          id
+        )
+      }
+      &TaskError::Frozen => {
+        write!(
+          f,
+          "The task registry is frozen: rules may not be registered or removed once a Scheduler \
+           has been created for it."
+        )
+      }
+      &TaskError::DoubleRegistered(ref msg) |
+      &TaskError::Invalid(ref msg) => write!(f, "{}", msg),
+    }
+  }
+}
+
+///
+/// A cache from requested products which were not registered exactly, to the registered products
+/// that are compatible with them. A clone is empty, because the registrations of a cloned Tasks
+/// may diverge from the original.
+///
+struct Resolutions(Mutex<HashMap<TypeConstraint, Vec<TypeConstraint>, FNV>>);
+
+impl Clone for Resolutions {
+  fn clone(&self) -> Resolutions {
+    Resolutions(Mutex::new(Default::default()))
+  }
+}
+
+///
+/// A Task which is in the process of being built, along with the options for its registration
+/// which are not part of the Task itself.
+///
+#[derive(Clone)]
+struct PreparingTask {
+  task: Task,
+  // Whether the Task may have an empty clause.
+  allow_empty: bool,
+  // Whether the Task may select its own product for the same subject.
+  allow_self_select: bool,
+  // Whether the Task replaces (rather than joins) the Tasks already registered for its product.
+  shadow: bool,
+  // An error which occurred while the Task was begun, which is returned when it is finished.
+  error: Option<TaskError>,
+}
+
+///
+/// Counts describing the size of a Tasks registry, for diagnosing slow Scheduler construction
+/// and bloated rule sets. Intrinsics are not counted separately, because they are registered as
+/// placeholder Tasks like any other.
+///
+#[repr(C)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TasksStats {
+  // Products with at least one registered Task.
+  pub products: u64,
+  pub tasks: u64,
+  // The total number of Selectors in the clauses of all Tasks, and the longest single clause.
+  pub selectors: u64,
+  pub max_clause_len: u64,
+  pub singletons: u64,
+  pub aliases: u64,
+  // Tasks which were replaced by a shadowing registration.
+  pub shadowed: u64,
+  pub mergeable: u64,
+  pub first_match: u64,
+  // Requested products which have been resolved to compatible registered products so far.
+  pub resolutions: u64,
+  // Tasks which have been begun but not yet finished.
+  pub preparing: u64,
+}
+
+///
+/// Indexes which are computed once, when a Tasks registry is frozen.
+///
+#[derive(Clone)]
+struct FrozenIndexes {
+  fingerprint: hashing::Digest,
+  all_products: Vec<TypeConstraint>,
 }
 
 ///
@@ -24,162 +238,1770 @@ pub struct Task {
 pub struct Tasks {
   // Singleton Values to be returned for a given TypeConstraint.
   singletons: HashMap<TypeConstraint, (Key, Value), FNV>,
-  // any-subject, selector -> list of tasks implementing it
-  tasks: HashMap<TypeConstraint, Vec<Task>, FNV>,
-  // Used during the construction of the tasks map.
-  preparing: Option<Task>,
+  // any-subject, selector -> list of tasks implementing it. Tasks are shared with the RuleGraph
+  // and with the Task Nodes that run them, so that their clauses are never copied.
+  tasks: HashMap<TypeConstraint, Vec<Arc<Task>>, FNV>,
+  // Products which are aliases for the Tasks of another product. Alias chains are acyclic.
+  aliases: HashMap<TypeConstraint, TypeConstraint, FNV>,
+  // Tasks which were replaced by a shadowing registration for their product, in the order in
+  // which they were replaced. These are only reported: they are never run.
+  shadowed: HashMap<TypeConstraint, Vec<Arc<Task>>, FNV>,
+  // Products for which the values produced by multiple tasks may be merged.
+  mergeable: HashSet<TypeConstraint, FNV>,
+  // Products for which the first task (in the order of `gen_tasks`) to produce a value wins.
+  first_match: HashSet<TypeConstraint, FNV>,
+  // Externally visible goal names (as Keys for python strings), and the products that they
+  // request at the roots.
+  goals: HashMap<Key, TypeConstraint, FNV>,
+  // The Key provided to Tasks for optional Selects which could not be satisfied.
+  key_none: Key,
+  // The types of the strings which may be used as variant keys.
+  string_types: Vec<TypeId>,
+  // The types of each product that a singleton or Task was registered for, which determine the
+  // requested products that it is compatible with.
+  product_types: HashMap<TypeConstraint, Vec<TypeId>, FNV>,
+  // Cached resolutions of requested products to compatible registered products.
+  resolutions: Resolutions,
+  // Tasks which are in the process of being built, and the id of the next builder.
+  preparing: HashMap<TaskBuilderId, PreparingTask, FNV>,
+  next_builder_id: TaskBuilderId,
+  // The builder begun by the legacy `task_begin` method, if any.
+  legacy_builder: Option<TaskBuilderId>,
+  // Once frozen, the registry may no longer be mutated, and its indexes are fixed.
+  frozen: Option<FrozenIndexes>,
 }
 
 ///
-/// Defines a stateful lifecycle for defining tasks via the C api. Call in order:
-///   1. task_begin() - once per task
+/// Defines a lifecycle for defining tasks via the C api. For each task, call in order:
+///   1. builder_begin() - once per task, returning a TaskBuilderId
 ///   2. add_*() - zero or more times per task to add input clauses
-///   3. task_end() - once per task
+///   3. builder_finish() - once per task
+///
+/// Any number of tasks may be in the process of being built at once.
 ///
-/// Also has a one-shot method for adding a singleton (which has no Selectors):
+/// During migration, the legacy sequence of task_begin(), add_*() with the LEGACY_TASK_BUILDER
+/// id, and task_end() is supported for building one task at a time.
+///
+/// Also has one-shot methods for adding a singleton (which has no Selectors), and for marking a
+/// product as mergeable or first-match:
 ///   1. singleton_add()
+///   2. mergeable_add()
+///   3. first_match_add()
+///
+/// Each of the fallible steps returns a TaskError on misuse, which the C api converts into an
+/// exception for the caller.
 ///
-/// (This protocol was original defined in a Builder, but that complicated the C lifecycle.)
+/// Finally, call freeze() before the first execution: after that point, all of the mutating
+/// methods return TaskError::Frozen.
 ///
 impl Tasks {
-  pub fn new() -> Tasks {
+  pub fn new(key_none: Key, string_types: Vec<TypeId>) -> Tasks {
     Tasks {
       singletons: Default::default(),
       tasks: Default::default(),
-      preparing: None,
+      aliases: Default::default(),
+      shadowed: Default::default(),
+      mergeable: Default::default(),
+      first_match: Default::default(),
+      goals: Default::default(),
+      key_none: key_none,
+      string_types: string_types,
+      product_types: Default::default(),
+      resolutions: Resolutions(Mutex::new(Default::default())),
+      preparing: Default::default(),
+      next_builder_id: LEGACY_TASK_BUILDER + 1,
+      legacy_builder: None,
+      frozen: None,
     }
   }
 
+  ///
+  /// Freezes the registry, after which all methods that would change the results of `gen_tasks`
+  /// or `gen_singleton` return TaskError::Frozen. The fingerprint and the list of all products are
+  /// computed once, here. Fails if any Task is still being built, since it could never be
+  /// finished. Freezing twice is a no-op.
+  ///
+  pub fn freeze(&mut self) -> Result<(), TaskError> {
+    if self.frozen.is_some() {
+      return Ok(());
+    }
+    if !self.preparing.is_empty() {
+      return Err(TaskError::Invalid(format!(
+        "Cannot freeze the task registry while {} task(s) are still being built.",
+        self.preparing.len()
+      )));
+    }
+    self.frozen = Some(FrozenIndexes {
+      fingerprint: self.compute_fingerprint(),
+      all_products: self.compute_all_products(),
+    });
+    Ok(())
+  }
+
+  pub fn is_frozen(&self) -> bool {
+    self.frozen.is_some()
+  }
+
+  fn check_not_frozen(&self) -> Result<(), TaskError> {
+    if self.is_frozen() {
+      Err(TaskError::Frozen)
+    } else {
+      Ok(())
+    }
+  }
+
+  pub fn key_none(&self) -> &Key {
+    &self.key_none
+  }
+
   pub fn all_product_types(&self) -> HashSet<TypeConstraint> {
     self
       .singletons
       .keys()
       .chain(self.tasks.keys())
+      .chain(self.aliases.keys())
       .cloned()
       .collect::<HashSet<_>>()
   }
 
-  pub fn all_tasks(&self) -> Vec<&Task> {
-    self.tasks.values().flat_map(|tasks| tasks).collect()
+  ///
+  /// Returns every product that the registry can provide (via Tasks, including the placeholder
+  /// Tasks registered for intrinsics, singletons or aliases) exactly once, sorted by their rendered
+  /// names so that the order is stable between runs.
+  ///
+  pub fn all_products(&self) -> Vec<TypeConstraint> {
+    match self.frozen {
+      Some(ref frozen) => frozen.all_products.clone(),
+      None => self.compute_all_products(),
+    }
+  }
+
+  fn compute_all_products(&self) -> Vec<TypeConstraint> {
+    let mut products: Vec<(String, TypeConstraint)> = self
+      .all_product_types()
+      .into_iter()
+      .map(|product| (externs::key_to_str(&product.0), product))
+      .collect();
+    // Distinct constraints may render identically, so ties are broken by their (interned) ids.
+    products.sort_by(|a, b| (&a.0, (a.1).0.id()).cmp(&(&b.0, (b.1).0.id())));
+    products.into_iter().map(|(_, product)| product).collect()
+  }
+
+  ///
+  /// Returns all registered Tasks, grouped by product (in a stable order) and in registration
+  /// order within each product.
+  ///
+  pub fn all_tasks(&self) -> Vec<&Arc<Task>> {
+    let mut products: Vec<&TypeConstraint> = self.tasks.keys().collect();
+    products.sort_by_key(|product| product.0.id());
+    products
+      .into_iter()
+      .flat_map(|product| &self.tasks[product])
+      .collect()
+  }
+
+  ///
+  /// Fingerprints the registered singletons, Tasks, goals, aliases, mergeable and first-match
+  /// products, along with the Key provided for unsatisfied optional Selects and the string types,
+  /// such that equivalent registrations (in any order) have equal Digests. Tasks are identified by
+  /// their product, clause, function digest (see `externs::digest`), flags, priority and timeout,
+  /// and singletons by the digest of their values, rather than by interned ids or reprs, which are
+  /// not stable between runs.
+  /// Shadowed Tasks can never run, so they do not affect the fingerprint.
+  ///
+  pub fn fingerprint(&self) -> hashing::Digest {
+    match self.frozen {
+      Some(ref frozen) => frozen.fingerprint,
+      None => self.compute_fingerprint(),
+    }
   }
 
+  fn compute_fingerprint(&self) -> hashing::Digest {
+    let mut entries: Vec<String> = Vec::new();
+    entries.push(format!("key_none {}", externs::key_to_str(&self.key_none)));
+    for &string_type in &self.string_types {
+      entries.push(format!("string_type {}", externs::type_to_str(string_type)));
+    }
+    for (product, &(_, ref value)) in &self.singletons {
+      entries.push(format!(
+        "singleton {} = {} digest={}",
+        externs::key_to_str(&product.0),
+        externs::type_to_str(externs::identify(value).type_id),
+        externs::digest(value)
+      ));
+    }
+    for task in self.tasks.values().flat_map(|tasks| tasks) {
+      // Names are only for display, so they do not affect the fingerprint.
+      let unnamed = Task {
+        name: None,
+        doc: None,
+        ..(**task).clone()
+      };
+      entries.push(format!(
+        "task {} digest={} cacheable={} include_subject={} priority={} timeout_ms={:?}",
+        rule_graph::task_display(&unnamed),
+        externs::function_digest(&task.func),
+        task.cacheable,
+        task.include_subject,
+        task.priority,
+        task.timeout_ms
+      ));
+    }
+    for (name, product) in &self.goals {
+      entries.push(format!(
+        "goal {} = {}",
+        externs::key_to_str(name),
+        externs::key_to_str(&product.0)
+      ));
+    }
+    for (alias, product) in &self.aliases {
+      entries.push(format!(
+        "alias {} = {}",
+        externs::key_to_str(&alias.0),
+        externs::key_to_str(&product.0)
+      ));
+    }
+    for product in &self.mergeable {
+      entries.push(format!("mergeable {}", externs::key_to_str(&product.0)));
+    }
+    for product in &self.first_match {
+      entries.push(format!("first_match {}", externs::key_to_str(&product.0)));
+    }
+    entries.sort();
+
+    let mut hasher = hashing::WriterHasher::new(io::sink());
+    let mut len = 0;
+    for entry in entries {
+      hasher
+        .write_all(entry.as_bytes())
+        .and_then(|()| hasher.write_all(b"\n"))
+        .expect("Writing to a sink cannot fail.");
+      len += entry.len() + 1;
+    }
+    hashing::Digest(hasher.finish(), len)
+  }
+
+  ///
+  /// Returns the registered products which are compatible with the given requested product (which
+  /// was not itself registered), in a stable order. A registered product is compatible if each of
+  /// its types satisfies the requested product. The types of each registered product are checked
+  /// in one batch via `externs::satisfied_by_types`, and resolutions are cached.
+  ///
+  fn resolve(&self, product: &TypeConstraint) -> Vec<TypeConstraint> {
+    if let Some(registered) = self.resolutions.0.lock().unwrap().get(product) {
+      return registered.clone();
+    }
+    // NB: Resolved without holding the lock, because it calls into python.
+    let candidates: Vec<(&TypeConstraint, &Vec<TypeId>)> = self
+      .singletons
+      .keys()
+      .chain(self.tasks.keys())
+      .map(|registered| (registered, &self.product_types[registered]))
+      .collect();
+    let checks: Vec<(TypeConstraint, TypeId)> = candidates
+      .iter()
+      .flat_map(|&(_, types)| types.iter().map(|&type_id| (*product, type_id)))
+      .collect();
+    let mut satisfied = externs::satisfied_by_types(&checks).into_iter();
+    let mut registered: Vec<TypeConstraint> = candidates
+      .into_iter()
+      .filter(|&(_, types)| {
+        // NB: Consumes the results for all of the types of the candidate, even once one fails.
+        satisfied.by_ref().take(types.len()).fold(
+          true,
+          |all, is_satisfied| all && is_satisfied,
+        )
+      })
+      .map(|(registered, _)| *registered)
+      .collect();
+    registered.sort_by_key(|registered| registered.0.id());
+    registered.dedup();
+    self.resolutions.0.lock().unwrap().insert(
+      product.clone(),
+      registered.clone(),
+    );
+    registered
+  }
+
+  ///
+  /// Returns the singleton for the given product, or if nothing (neither a singleton nor Tasks) was
+  /// registered for exactly the given product, the first singleton registered for a compatible
+  /// product.
+  ///
+  /// NB: Registrations for exactly the given product always shadow registrations for compatible
+  /// products. Because callers consult singletons before Tasks, falling back to a compatible
+  /// singleton while Tasks were registered for exactly the product would shadow those Tasks.
+  ///
   pub fn gen_singleton(&self, product: &TypeConstraint) -> Option<&(Key, Value)> {
-    self.singletons.get(product)
+    if let Some(singleton) = self.singletons.get(product) {
+      return Some(singleton);
+    }
+    if self.tasks.contains_key(self.dealias(product)) {
+      return None;
+    }
+    self
+      .resolve(product)
+      .iter()
+      .filter_map(|registered| self.singletons.get(registered))
+      .next()
+  }
+
+  ///
+  /// Returns the Tasks able to produce the given product, in descending order of priority and
+  /// then in registration order. Candidates are attempted (and reported in Noop and conflict
+  /// messages) in this order, and for first-match products the first Task to produce a value wins.
+  ///
+  /// If no Tasks were registered for exactly the given product, the Tasks registered for
+  /// compatible products are returned (grouped by product, in a stable order, before sorting by
+  /// priority). Compatible Tasks are never merged with the Tasks for exactly the product: see
+  /// `gen_singleton`.
+  ///
+  pub fn gen_tasks(&self, product: &TypeConstraint) -> Vec<&Arc<Task>> {
+    let registered = self.dealias(product);
+    let mut tasks: Vec<&Arc<Task>> = if let Some(tasks) = self.tasks.get(registered) {
+      tasks.iter().collect()
+    } else {
+      self
+        .resolve(product)
+        .iter()
+        .filter_map(|registered| self.tasks.get(registered))
+        .flat_map(|tasks| tasks)
+        .collect()
+    };
+    // NB: `sort_by` is stable, so registration order is preserved within a priority.
+    tasks.sort_by(|a, b| b.priority.cmp(&a.priority));
+    tasks
+  }
+
+  ///
+  /// Follows the chain of aliases (if any) from the given product to the product whose Tasks it
+  /// shares.
+  ///
+  fn dealias<'a>(&'a self, product: &'a TypeConstraint) -> &'a TypeConstraint {
+    let mut product = product;
+    while let Some(aliased) = self.aliases.get(product) {
+      product = aliased;
+    }
+    product
+  }
+
+  ///
+  /// Describes each candidate able to produce the given product, in the order in which they would
+  /// be attempted, along with whether the first clause of each Task could be satisfied for the
+  /// given subject type. Intended for debugging Selects which found no provider: see
+  /// `RuleGraph::validate` for a complete (transitive) analysis.
+  ///
+  /// NB: Intrinsics are selected by product in `Select::gen_nodes`, but their products also have
+  /// placeholder Tasks registered, which are reported here.
+  ///
+  pub fn gen_tasks_report(&self, subject_type: TypeId, product: &TypeConstraint) -> String {
+    let mut candidates = Vec::new();
+    if externs::satisfied_by_type(product, &subject_type) {
+      candidates.push("the subject itself".to_string());
+    }
+    if let Some(&(_, ref value)) = self.gen_singleton(product) {
+      candidates.push(format!("singleton {}", externs::val_to_str(value)));
+    }
+    for task in self.gen_tasks(product) {
+      let first_clause = match task.clause.first() {
+        Some(selector) if self.selector_satisfiable(subject_type, selector) => "is satisfiable",
+        Some(_) => "is not satisfiable",
+        None => "is empty",
+      };
+      let doc = match task.doc_summary() {
+        Some(summary) => format!(" ({})", summary),
+        None => "".to_string(),
+      };
+      candidates.push(format!(
+        "task {}: first clause {}{}",
+        rule_graph::task_display(task),
+        first_clause,
+        doc
+      ));
+    }
+    if candidates.is_empty() {
+      candidates.push("none".to_string());
+    }
+    format!(
+      "Candidates to compute {} for subject type {}:\n  {}",
+      externs::key_to_str(&product.0),
+      rule_graph::type_str(subject_type),
+      candidates.join("\n  ")
+    )
+  }
+
+  ///
+  /// True if the given selector could be satisfied for the given subject type by the subject
+  /// itself, or directly by a singleton or a Task (without considering the clauses of the Task).
+  ///
+  fn selector_satisfiable(&self, subject_type: TypeId, selector: &Selector) -> bool {
+    let provides = |product: &TypeConstraint| {
+      externs::satisfied_by_type(product, &subject_type) ||
+        self.singletons.contains_key(product) || self.tasks.contains_key(product) ||
+        self.aliases.contains_key(product)
+    };
+    match selector {
+      &Selector::Select(ref s) => provides(&s.product),
+      &Selector::SelectSubject(ref s) => externs::satisfied_by_type(&s.product, &subject_type),
+      // Variants are configured per subject, so they cannot be checked statically.
+      &Selector::SelectVariant(_) => true,
+      &Selector::SelectUnion(ref s) => s.products.iter().any(|p| provides(p)),
+      &Selector::SelectDependencies(ref s) => provides(&s.product),
+      &Selector::SelectTransitive(ref s) => provides(&s.product),
+      &Selector::SelectProjection(ref s) => s.input_products.iter().any(|p| provides(p)),
+    }
+  }
+
+  ///
+  /// Returns the Tasks (in the order of `all_tasks`) which could never run while computing the
+  /// given root products for the given root subject types, because no chain of clauses starting
+  /// from a root selects their product.
+  ///
+  /// This is a static analysis of the registered clauses: it follows subject changes through the
+  /// `field_types` of dependencies and the `projected_subject` of projections, but does not
+  /// consider whether clauses are satisfiable (see `RuleGraph::validate` for that).
+  ///
+  pub fn unreachable(
+    &self,
+    root_subject_types: &[TypeId],
+    root_products: &[TypeConstraint],
+  ) -> Vec<&Arc<Task>> {
+    let mut visited: HashSet<(TypeId, TypeConstraint)> = HashSet::new();
+    let mut to_visit: Vec<(TypeId, TypeConstraint)> = root_subject_types
+      .iter()
+      .flat_map(|&subject_type| {
+        root_products
+          .iter()
+          .map(move |&product| (subject_type, product))
+      })
+      .collect();
+    let mut reachable: HashSet<&Arc<Task>> = HashSet::new();
+
+    while let Some((subject_type, product)) = to_visit.pop() {
+      if !visited.insert((subject_type, product)) {
+        continue;
+      }
+      // As in `rhs_for_select`: the subject or a singleton are used in preference to any Task.
+      if externs::satisfied_by_type(&product, &subject_type) ||
+        self.gen_singleton(&product).is_some()
+      {
+        continue;
+      }
+      for task in self.gen_tasks(&product) {
+        reachable.insert(task);
+        for selector in &task.clause {
+          match selector {
+            &Selector::Select(ref s) => to_visit.push((subject_type, s.product)),
+            &Selector::SelectSubject(_) => {}
+            &Selector::SelectVariant(ref s) => to_visit.push((subject_type, s.product)),
+            &Selector::SelectUnion(ref s) => {
+              to_visit.extend(s.products.iter().map(|&p| (subject_type, p)))
+            }
+            &Selector::SelectDependencies(ref s) => {
+              to_visit.push((subject_type, s.dep_product));
+              to_visit.extend(s.field_types.iter().map(|&t| (t, s.product)));
+            }
+            &Selector::SelectTransitive(ref s) => {
+              to_visit.push((subject_type, s.dep_product));
+              to_visit.extend(s.field_types.iter().map(|&t| (t, s.dep_product)));
+              to_visit.extend(s.field_types.iter().map(|&t| (t, s.product)));
+            }
+            &Selector::SelectProjection(ref s) => {
+              to_visit.extend(s.input_products.iter().map(|&p| (subject_type, p)));
+              to_visit.push((s.projected_subject, s.product));
+            }
+          }
+        }
+      }
+    }
+
+    self
+      .all_tasks()
+      .into_iter()
+      .filter(|task| !reachable.contains(task))
+      .collect()
+  }
+
+  ///
+  /// Renders every registered singleton and Task (including the placeholder Tasks registered for
+  /// intrinsics), grouped by product. Products are sorted by their rendered names, and the Tasks
+  /// for each product are listed in the order in which they would be attempted, so that the
+  /// report is stable enough to diff between runs.
+  ///
+  pub fn report(&self) -> String {
+    let mut lines = Vec::new();
+    for product in self.all_products() {
+      let product_str = externs::key_to_str(&product.0);
+      let mut modes = Vec::new();
+      if self.is_mergeable(&product) {
+        modes.push(" (mergeable)");
+      }
+      if self.is_first_match(&product) {
+        modes.push(" (first match)");
+      }
+      lines.push(format!("{}{}:", product_str, modes.concat()));
+      if let Some(aliased) = self.aliases.get(&product) {
+        lines.push(format!("  alias of {}", externs::key_to_str(&aliased.0)));
+        continue;
+      }
+      if let Some(&(_, ref value)) = self.singletons.get(&product) {
+        lines.push(format!("  singleton {}", externs::val_to_str(value)));
+      }
+      for task in self.gen_tasks(&product) {
+        let timeout = match task.timeout_ms {
+          Some(timeout_ms) => format!(" timeout_ms={}", timeout_ms),
+          None => "".to_string(),
+        };
+        lines.push(format!(
+          "  task {} priority={} cacheable={} include_subject={}{}",
+          task.display_name(),
+          task.priority,
+          task.cacheable,
+          task.include_subject,
+          timeout
+        ));
+        if let Some(summary) = task.doc_summary() {
+          lines.push(format!("    doc: {}", summary));
+        }
+        for selector in &task.clause {
+          lines.push(format!("    {}", rule_graph::selector_str(selector)));
+        }
+      }
+      for task in self.shadowed.get(&product).into_iter().flat_map(|tasks| tasks) {
+        lines.push(format!("  shadowed {}", rule_graph::task_display(task)));
+      }
+    }
+    lines.join("\n")
+  }
+
+  ///
+  /// Counts the registered products, Tasks, Selectors and Singletons, and the sizes of the
+  /// secondary indexes. Unlike `report`, this makes no calls to extern functions.
+  ///
+  pub fn stats(&self) -> TasksStats {
+    let mut stats = TasksStats {
+      singletons: self.singletons.len() as u64,
+      aliases: self.aliases.len() as u64,
+      shadowed: self.shadowed.values().map(|tasks| tasks.len() as u64).sum(),
+      mergeable: self.mergeable.len() as u64,
+      first_match: self.first_match.len() as u64,
+      resolutions: self.resolutions.0.lock().unwrap().len() as u64,
+      preparing: self.preparing.len() as u64,
+      ..Default::default()
+    };
+    for tasks in self.tasks.values().filter(|tasks| !tasks.is_empty()) {
+      stats.products += 1;
+      for task in tasks {
+        let clause_len = task.clause.len() as u64;
+        stats.tasks += 1;
+        stats.selectors += clause_len;
+        stats.max_clause_len = cmp::max(stats.max_clause_len, clause_len);
+      }
+    }
+    stats
+  }
+
+  pub fn is_mergeable(&self, product: &TypeConstraint) -> bool {
+    self.mergeable.contains(product)
+  }
+
+  ///
+  /// Marks the given product as mergeable: when multiple tasks produce a value for it, the values
+  /// (which must be collections) are merged rather than treated as a conflict.
+  ///
+  pub fn mergeable_add(&mut self, product: TypeConstraint) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
+    self.mergeable.insert(product);
+    Ok(())
+  }
+
+  ///
+  /// Registers an externally visible name for a product which may be requested at the roots. A
+  /// goal name may be registered more than once, but only for the same product.
+  ///
+  pub fn goal_add(&mut self, name_key: Key, product: TypeConstraint) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
+    if let Some(existing) = self.goals.get(&name_key) {
+      if *existing == product {
+        return Ok(());
+      }
+      return Err(TaskError::DoubleRegistered(format!(
+        "The goal {} was registered for both {} and {}.",
+        externs::key_to_str(&name_key),
+        externs::key_to_str(&existing.0),
+        externs::key_to_str(&product.0),
+      )));
+    }
+    self.goals.insert(name_key, product);
+    Ok(())
+  }
+
+  pub fn goal_product(&self, name_key: &Key) -> Option<TypeConstraint> {
+    self.goals.get(name_key).cloned()
+  }
+
+  ///
+  /// Returns the names of all registered goals and their products, sorted by name.
+  ///
+  pub fn all_goals(&self) -> Vec<(String, TypeConstraint)> {
+    let mut goals: Vec<(String, TypeConstraint)> = self
+      .goals
+      .iter()
+      .map(|(name_key, product)| (externs::key_to_str(name_key), *product))
+      .collect();
+    goals.sort_by(|a, b| a.0.cmp(&b.0));
+    goals
   }
 
-  pub fn gen_tasks(&self, product: &TypeConstraint) -> Option<&Vec<Task>> {
-    self.tasks.get(product)
+  pub fn is_first_match(&self, product: &TypeConstraint) -> bool {
+    self.first_match.contains(product)
   }
 
-  pub fn singleton_add(&mut self, value: Value, product: TypeConstraint) {
+  ///
+  /// Marks the given product as first-match: rather than requiring exactly one task to produce a
+  /// value for it, tasks are attempted in order of priority and then in the order in which they
+  /// were registered, and the first value produced is used.
+  ///
+  pub fn first_match_add(&mut self, product: TypeConstraint) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
+    self.first_match.insert(product);
+    Ok(())
+  }
+
+  pub fn singleton_add(
+    &mut self,
+    value: Value,
+    product: TypeConstraint,
+  ) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
     if let Some(&(_, ref existing_value)) = self.singletons.get(&product) {
-      panic!(
-        "More than one singleton rule was installed for the product {:?}: {:?} vs {:?}",
-        product,
-        existing_value,
-        value,
-      );
+      return Err(TaskError::DoubleRegistered(format!(
+        "More than one singleton rule was installed for the product {}: {} vs {}",
+        externs::key_to_str(&product.0),
+        externs::val_to_str(existing_value),
+        externs::val_to_str(&value),
+      )));
     }
-    self.singletons.insert(product, (
-      externs::key_for(value.clone()),
-      value,
-    ));
+    if let Some(aliased) = self.aliases.get(&product) {
+      return Err(TaskError::DoubleRegistered(format!(
+        "A singleton rule was installed for the product {}, which is an alias of {}.",
+        externs::key_to_str(&product.0),
+        externs::key_to_str(&aliased.0),
+      )));
+    }
+    if let Some(tasks) = self.tasks.get(&product) {
+      // A singleton is the only provider of its product, so it would shadow these tasks.
+      return Err(TaskError::DoubleRegistered(format!(
+        "A singleton rule was installed for the product {}, which is also produced by: {}",
+        externs::key_to_str(&product.0),
+        tasks
+          .iter()
+          .map(|task| task.display_name())
+          .collect::<Vec<_>>()
+          .join(", "),
+      )));
+    }
+    let key = externs::key_for(value.clone());
+    if !externs::satisfied_by(&product, &value) {
+      return Err(TaskError::Invalid(format!(
+        "A singleton of type {} was installed for the product {}, which it does not satisfy.",
+        externs::type_to_str(*key.type_id()),
+        externs::key_to_str(&product.0),
+      )));
+    }
+    self.add_product_types(&product)?;
+    self.singletons.insert(product, (key, value));
+    self.resolutions.0.get_mut().unwrap().clear();
+    Ok(())
   }
 
   ///
-  /// The following methods define the Task registration lifecycle.
+  /// The following methods define the Task registration lifecycle. A Task may be begun on a frozen
+  /// registry (because `builder_begin` cannot fail), but it can never be finished.
   ///
-  pub fn task_begin(&mut self, func: Function, product: TypeConstraint) {
-    assert!(
-      self.preparing.is_none(),
-      "Must `end()` the previous task creation before beginning a new one!"
+  pub fn builder_begin(
+    &mut self,
+    func: Function,
+    product: TypeConstraint,
+    options: TaskOptions,
+  ) -> TaskBuilderId {
+    let id = self.next_builder_id;
+    self.next_builder_id += 1;
+    self.preparing.insert(
+      id,
+      PreparingTask {
+        task: Task {
+          cacheable: options.cacheable,
+          include_subject: options.include_subject,
+          priority: options.priority,
+          timeout_ms: options.timeout_ms,
+          product: product,
+          clause: Vec::new(),
+          func: func,
+          name: options.name,
+          doc: options.doc,
+        },
+        allow_empty: options.allow_empty,
+        allow_self_select: options.allow_self_select,
+        shadow: options.shadow,
+        error: None,
+      },
     );
+    id
+  }
 
-    self.preparing = Some(Task {
-      cacheable: true,
+  ///
+  /// Records an error for the given builder, which is returned when it is finished or replaced.
+  /// This allows a caller of `builder_begin` (which cannot fail) to report an error in the
+  /// description of the Task. Only the first error recorded for a builder is kept.
+  ///
+  pub fn builder_fail(&mut self, id: TaskBuilderId, error: TaskError) -> Result<(), TaskError> {
+    self
+      .preparing
+      .get_mut(&id)
+      .map(|preparing| if preparing.error.is_none() {
+        preparing.error = Some(error);
+      })
+      .ok_or(TaskError::UnknownBuilder(id))
+  }
+
+  ///
+  /// Begins a Task which may then be referred to as LEGACY_TASK_BUILDER: only one such Task may
+  /// be in the process of being built at a time.
+  ///
+  pub fn task_begin(
+    &mut self,
+    func: Function,
+    product: TypeConstraint,
+    options: TaskOptions,
+  ) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
+    if self.legacy_builder.is_some() {
+      return Err(TaskError::NotEnded);
+    }
+    self.legacy_builder = Some(self.builder_begin(func, product, options));
+    Ok(())
+  }
+
+  pub fn add_select(
+    &mut self,
+    id: TaskBuilderId,
+    product: TypeConstraint,
+    variant_key: Option<Key>,
+    default_variant_value: Option<String>,
+    optional: bool,
+    cardinality: Cardinality,
+  ) -> Result<(), TaskError> {
+    let variant_key = match variant_key {
+      Some(ref key) => Some(self.variant_key_str(key)?),
+      None => None,
+    };
+    self.clause(id, Selector::Select(Select {
       product: product,
-      clause: Vec::new(),
-      func: func,
-    });
+      variant_key: variant_key,
+      default_variant_value: default_variant_value,
+      optional: optional,
+      cardinality: cardinality,
+    }))
+  }
+
+  pub fn add_select_subject(
+    &mut self,
+    id: TaskBuilderId,
+    product: TypeConstraint,
+  ) -> Result<(), TaskError> {
+    self.clause(id, Selector::SelectSubject(SelectSubject { product: product }))
   }
 
-  pub fn add_select(&mut self, product: TypeConstraint, variant_key: Option<String>) {
-    self.clause(Selector::Select(Select {
+  pub fn add_select_variant(
+    &mut self,
+    id: TaskBuilderId,
+    product: TypeConstraint,
+    variant_key: Key,
+  ) -> Result<(), TaskError> {
+    let variant_key = self.variant_key_str(&variant_key)?;
+    self.clause(id, Selector::SelectVariant(SelectVariant {
       product: product,
       variant_key: variant_key,
-    }));
+    }))
+  }
+
+  pub fn add_select_union(
+    &mut self,
+    id: TaskBuilderId,
+    products: Vec<TypeConstraint>,
+  ) -> Result<(), TaskError> {
+    if products.is_empty() {
+      return Err(TaskError::Invalid(
+        "A union must select at least one product.".to_string(),
+      ));
+    }
+    self.clause(id, Selector::SelectUnion(SelectUnion { products: products }))
+  }
+
+  ///
+  /// Variant keys are provided across the C api as Keys. A key which is not a string could never
+  /// match a configured variant, so it is rejected when it is registered.
+  ///
+  fn variant_key_str(&self, variant_key: &Key) -> Result<String, TaskError> {
+    if !self.string_types.contains(variant_key.type_id()) {
+      return Err(TaskError::Invalid(format!(
+        "A variant key must be a string, but `{}` has type {}.",
+        externs::key_to_str(variant_key),
+        externs::type_to_str(*variant_key.type_id()),
+      )));
+    }
+    Ok(externs::key_to_str(variant_key))
+  }
+
+  ///
+  /// Field types are provided across the C api as python type ids, which are never zero: the zero
+  /// id is reserved for ANY_TYPE, which is not meaningful as the type of a dependency. A duplicated
+  /// id is a registration bug, and would make otherwise equal clauses compare unequal. Each field
+  /// type must be accompanied by the constraint used to match its subclasses.
+  ///
+  fn validate_field_types(
+    field_types: &[TypeId],
+    field_type_constraints: &[TypeConstraint],
+  ) -> Result<(), TaskError> {
+    if field_types.len() != field_type_constraints.len() {
+      return Err(TaskError::Invalid(format!(
+        "A dependencies selector has {} field_types, but {} field_type_constraints.",
+        field_types.len(),
+        field_type_constraints.len(),
+      )));
+    }
+    let mut seen = HashSet::new();
+    for field_type in field_types {
+      if *field_type == ANY_TYPE {
+        return Err(TaskError::Invalid(
+          "The field_types of a dependencies selector may not contain a zero TypeId.".to_string(),
+        ));
+      }
+      if !seen.insert(field_type) {
+        return Err(TaskError::Invalid(format!(
+          "The field_types of a dependencies selector contain {} more than once.",
+          rule_graph::type_str(*field_type)
+        )));
+      }
+    }
+    Ok(())
   }
 
   pub fn add_select_dependencies(
     &mut self,
+    id: TaskBuilderId,
     product: TypeConstraint,
     dep_product: TypeConstraint,
     field: Field,
     field_types: Vec<TypeId>,
-  ) {
-    self.clause(Selector::SelectDependencies(SelectDependencies {
+    field_type_constraints: Vec<TypeConstraint>,
+    on_missing: OnMissing,
+    skip_self: bool,
+  ) -> Result<(), TaskError> {
+    Self::validate_field_types(&field_types, &field_type_constraints)?;
+    self.clause(id, Selector::SelectDependencies(SelectDependencies {
       product: product,
       dep_product: dep_product,
       field: field,
       field_types: field_types,
-    }));
+      field_type_constraints: field_type_constraints,
+      on_missing: on_missing,
+      skip_self: skip_self,
+    }))
   }
 
   pub fn add_select_transitive(
     &mut self,
+    id: TaskBuilderId,
     product: TypeConstraint,
     dep_product: TypeConstraint,
     field: Field,
     field_types: Vec<TypeId>,
-  ) {
-    self.clause(Selector::SelectTransitive(SelectTransitive {
+    field_type_constraints: Vec<TypeConstraint>,
+  ) -> Result<(), TaskError> {
+    Self::validate_field_types(&field_types, &field_type_constraints)?;
+    self.clause(id, Selector::SelectTransitive(SelectTransitive {
       product: product,
       dep_product: dep_product,
       field: field,
       field_types: field_types,
-    }));
+      field_type_constraints: field_type_constraints,
+    }))
   }
 
   pub fn add_select_projection(
     &mut self,
+    id: TaskBuilderId,
     product: TypeConstraint,
     projected_subject: TypeId,
-    field: Field,
-    input_product: TypeConstraint,
-  ) {
-    self.clause(Selector::SelectProjection(SelectProjection {
+    fields: Vec<Field>,
+    input_products: Vec<TypeConstraint>,
+    on_missing: OnMissing,
+  ) -> Result<(), TaskError> {
+    if fields.is_empty() {
+      return Err(TaskError::Invalid(format!(
+        "A projection of {} must project at least one field.",
+        externs::type_to_str(projected_subject),
+      )));
+    }
+    if input_products.is_empty() {
+      return Err(TaskError::Invalid(format!(
+        "A projection of {} must project from at least one input product.",
+        externs::type_to_str(projected_subject),
+      )));
+    }
+    self.clause(id, Selector::SelectProjection(SelectProjection {
       product: product,
       projected_subject: projected_subject,
-      field: field,
-      input_product: input_product,
-    }));
+      fields: fields,
+      input_products: input_products,
+      on_missing: on_missing,
+    }))
   }
 
-  fn clause(&mut self, selector: Selector) {
+  fn clause(&mut self, id: TaskBuilderId, selector: Selector) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
+    let id = if id == LEGACY_TASK_BUILDER {
+      self.legacy_builder.ok_or(TaskError::NotBegun)?
+    } else {
+      id
+    };
     self
       .preparing
-      .as_mut()
-      .expect("Must `begin()` a task creation before adding clauses!")
-      .clause
-      .push(selector);
+      .get_mut(&id)
+      .map(|preparing| preparing.task.clause.push(selector))
+      .ok_or(TaskError::UnknownBuilder(id))
+  }
+
+  ///
+  /// Ends the Task begun by the legacy `task_begin` method.
+  ///
+  pub fn task_end(&mut self) -> Result<(), TaskError> {
+    let id = self.legacy_builder.take().ok_or(TaskError::NotBegun)?;
+    self.builder_finish(id)
+  }
+
+  ///
+  /// Discards the Task begun by the legacy `task_begin` method, so that another may be begun, and
+  /// returns true if there was one.
+  ///
+  pub fn task_abort(&mut self) -> bool {
+    self.builder_abort(LEGACY_TASK_BUILDER)
+  }
+
+  ///
+  /// Discards the Task being built by the given builder (which may be LEGACY_TASK_BUILDER), and
+  /// returns true if there was one. This allows a caller that fails partway through describing a
+  /// Task to recover and continue registering other Tasks.
+  ///
+  pub fn builder_abort(&mut self, id: TaskBuilderId) -> bool {
+    let id = if id == LEGACY_TASK_BUILDER {
+      match self.legacy_builder.take() {
+        Some(id) => id,
+        None => return false,
+      }
+    } else {
+      id
+    };
+    self.preparing.remove(&id).is_some()
+  }
+
+  ///
+  /// A Task with an empty clause is almost always a registration bug (its selectors were never
+  /// added), which would otherwise surface as a confusing error when the function is called with
+  /// the wrong number of arguments. Such Tasks must opt in via `allow_empty`.
+  ///
+  /// Likewise, a Task which selects its own product for the same subject (rather than via a
+  /// dependency traversal or projection onto another subject), including as one of the products
+  /// of a union, would be chosen to satisfy its own Select, and would wait on itself forever. Such
+  /// Tasks must opt in via `allow_self_select`.
+  ///
+  fn check_clause(
+    task: &Task,
+    allow_empty: bool,
+    allow_self_select: bool,
+  ) -> Result<(), TaskError> {
+    if task.clause.is_empty() && !allow_empty {
+      return Err(TaskError::Invalid(format!(
+        "The task {} for {} has an empty clause: if it takes no arguments, it must be registered \
+         with allow_empty.",
+        rule_graph::function_str(&task.func),
+        externs::key_to_str(&task.product.0),
+      )));
+    }
+    if allow_self_select {
+      return Ok(());
+    }
+    for (index, selector) in task.clause.iter().enumerate() {
+      let selects_own_product = match selector {
+        &Selector::Select(ref select) => select.product == task.product,
+        &Selector::SelectVariant(ref select) => select.product == task.product,
+        &Selector::SelectUnion(ref select) => select.products.contains(&task.product),
+        _ => false,
+      };
+      if selects_own_product {
+        return Err(TaskError::Invalid(format!(
+          "The task {} for {} selects its own product for the same subject at clause index {} \
+           ({}), so it would wait on itself forever: if this is intended, it must be registered \
+           with allow_self_select.",
+          rule_graph::function_str(&task.func),
+          externs::key_to_str(&task.product.0),
+          index,
+          rule_graph::selector_str(selector),
+        )));
+      }
+    }
+    Ok(())
   }
 
-  pub fn task_end(&mut self) {
+  ///
+  /// Checks that neither an equal Task, nor a Task with the same function but a different clause,
+  /// is among the given Tasks registered for the product of the given Task. The latter usually
+  /// indicates that two plugins are registering conflicting versions of a rule.
+  ///
+  fn check_not_registered<'a, I>(registered: I, task: &Task) -> Result<(), TaskError>
+  where
+    I: Iterator<Item = &'a Arc<Task>>,
+  {
+    for existing in registered {
+      let problem = if **existing == *task {
+        "was double-registered"
+      } else if existing.func == task.func && existing.clause != task.clause {
+        "was registered twice with different clauses"
+      } else {
+        continue;
+      };
+      return Err(TaskError::DoubleRegistered(format!(
+        "The task {} {} for {}:\n  existing: {}\n  new: {}",
+        rule_graph::function_str(&task.func),
+        problem,
+        externs::key_to_str(&task.product.0),
+        rule_graph::task_display(existing),
+        rule_graph::task_display(task),
+      )));
+    }
+    Ok(())
+  }
+
+  ///
+  /// Records the types of the given product, if it was not already registered. The types are
+  /// fetched once (rather than on each resolution) because they cannot change, and so that a
+  /// product whose types cannot be determined fails to register rather than to resolve.
+  ///
+  fn add_product_types(&mut self, product: &TypeConstraint) -> Result<(), TaskError> {
+    if self.product_types.contains_key(product) {
+      return Ok(());
+    }
+    let types = externs::constraint_types(product).map_err(|e| {
+      TaskError::Invalid(format!(
+        "Failed to determine the types of the product {}: {}",
+        externs::key_to_str(&product.0),
+        e
+      ))
+    })?;
+    self.product_types.insert(*product, types);
+    Ok(())
+  }
+
+  ///
+  /// Checks that the product of the given Task is not already provided by a singleton, and is not
+  /// an alias.
+  ///
+  fn check_not_provided(&self, task: &Task) -> Result<(), TaskError> {
+    if let Some(&(_, ref value)) = self.singletons.get(&task.product) {
+      return Err(TaskError::DoubleRegistered(format!(
+        "{} was registered for {}, which is already provided by the singleton {}.",
+        rule_graph::task_display(task),
+        externs::key_to_str(&task.product.0),
+        externs::val_to_str(value),
+      )));
+    }
+    if let Some(aliased) = self.aliases.get(&task.product) {
+      return Err(TaskError::DoubleRegistered(format!(
+        "{} was registered for {}, which is an alias of {}.",
+        rule_graph::task_display(task),
+        externs::key_to_str(&task.product.0),
+        externs::key_to_str(&aliased.0),
+      )));
+    }
+    Ok(())
+  }
+
+  ///
+  /// Finishes the given builder by registering its Task. If the Task was begun with `shadow`, the
+  /// Tasks previously registered for its product are replaced rather than joined, and are recorded
+  /// as shadowed; shadowing an equal Task is a no-op.
+  ///
+  pub fn builder_finish(&mut self, id: TaskBuilderId) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
     // Move the task from `preparing` to the Tasks map
-    let mut task = self.preparing.take().expect(
-      "Must `begin()` a task creation before ending it!",
-    );
+    let PreparingTask {
+      mut task,
+      allow_empty,
+      allow_self_select,
+      shadow,
+      error,
+    } = self.preparing.remove(&id).ok_or(
+      TaskError::UnknownBuilder(id),
+    )?;
+    if let Some(error) = error {
+      return Err(error);
+    }
+    Self::check_clause(&task, allow_empty, allow_self_select)?;
+    self.check_not_provided(&task)?;
+    self.add_product_types(&task.product)?;
+    if !shadow {
+      let registered = self.tasks.get(&task.product).into_iter().flat_map(
+        |tasks| tasks.iter(),
+      );
+      Self::check_not_registered(registered, &task)?;
+    }
+    task.clause.shrink_to_fit();
+    self.register(Arc::new(task), shadow);
+    self.resolutions.0.get_mut().unwrap().clear();
+    Ok(())
+  }
+
+  ///
+  /// Registers the given (already checked) Task. If it shadows, the Tasks previously registered
+  /// for its product are replaced rather than joined, and are recorded as shadowed.
+  ///
+  fn register(&mut self, task: Arc<Task>, shadow: bool) {
     let tasks = self.tasks.entry(task.product.clone()).or_insert_with(
       || Vec::new(),
     );
-    assert!(
-      !tasks.contains(&task),
-      "{:?} was double-registered for {:?}: {:?}",
-      task,
-      task.product,
-      tasks,
-    );
-    task.clause.shrink_to_fit();
+    if shadow {
+      let shadowed = self.shadowed.entry(task.product.clone()).or_insert_with(
+        || Vec::new(),
+      );
+      shadowed.extend(tasks.drain(..).filter(|existing| **existing != *task));
+    }
     tasks.push(task);
   }
+
+  ///
+  /// Finishes the given builder by replacing the Task with the given function that was registered
+  /// for the same product: the replacement takes the position of the original in registration
+  /// order. Whether the builder was begun with `shadow` is ignored.
+  ///
+  pub fn builder_replace(
+    &mut self,
+    id: TaskBuilderId,
+    replaced: &Function,
+  ) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
+    let PreparingTask {
+      mut task,
+      allow_empty,
+      allow_self_select,
+      error,
+      ..
+    } = self.preparing.remove(&id).ok_or(
+      TaskError::UnknownBuilder(id),
+    )?;
+    if let Some(error) = error {
+      return Err(error);
+    }
+    Self::check_clause(&task, allow_empty, allow_self_select)?;
+    let tasks = self.tasks.get_mut(&task.product).ok_or_else(|| {
+      TaskError::Invalid(format!(
+        "No task was registered for {} to be replaced by {}.",
+        externs::key_to_str(&task.product.0),
+        rule_graph::task_display(&task),
+      ))
+    })?;
+    let index = tasks
+      .iter()
+      .position(|existing| existing.func == *replaced)
+      .ok_or_else(|| {
+        TaskError::Invalid(format!(
+          "No task with function {} was registered for {} to be replaced by {}.",
+          rule_graph::function_str(replaced),
+          externs::key_to_str(&task.product.0),
+          rule_graph::task_display(&task),
+        ))
+      })?;
+    Self::check_not_registered(
+      tasks
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != index)
+        .map(|(_, existing)| existing),
+      &task,
+    )?;
+    task.clause.shrink_to_fit();
+    tasks[index] = Arc::new(task);
+    self.resolutions.0.get_mut().unwrap().clear();
+    Ok(())
+  }
+
+  ///
+  /// Makes lookups of Tasks for the given alias resolve to the Tasks registered (now or later) for
+  /// the given product, which may itself be an alias. An alias may not also have Tasks or a
+  /// singleton of its own, and may not (transitively) alias itself.
+  ///
+  pub fn task_alias(
+    &mut self,
+    product: TypeConstraint,
+    alias: TypeConstraint,
+  ) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
+    if let Some(existing) = self.aliases.get(&alias) {
+      return Err(TaskError::DoubleRegistered(format!(
+        "{} was already registered as an alias of {}.",
+        externs::key_to_str(&alias.0),
+        externs::key_to_str(&existing.0),
+      )));
+    }
+    if self.tasks.contains_key(&alias) || self.singletons.contains_key(&alias) {
+      return Err(TaskError::DoubleRegistered(format!(
+        "{} cannot be registered as an alias of {}, because it is already produced by rules of \
+         its own.",
+        externs::key_to_str(&alias.0),
+        externs::key_to_str(&product.0),
+      )));
+    }
+    if *self.dealias(&product) == alias {
+      return Err(TaskError::Invalid(format!(
+        "Registering {} as an alias of {} would create a cycle of aliases.",
+        externs::key_to_str(&alias.0),
+        externs::key_to_str(&product.0),
+      )));
+    }
+    self.aliases.insert(alias, product);
+    self.resolutions.0.get_mut().unwrap().clear();
+    Ok(())
+  }
+
+  ///
+  /// Removes the Tasks with the given function that were registered for the given product, and
+  /// returns true if there were any.
+  ///
+  pub fn task_remove(
+    &mut self,
+    func: &Function,
+    product: &TypeConstraint,
+  ) -> Result<bool, TaskError> {
+    self.check_not_frozen()?;
+    let (removed, now_empty) = match self.tasks.get_mut(product) {
+      Some(tasks) => {
+        let before = tasks.len();
+        tasks.retain(|task| task.func != *func);
+        (tasks.len() < before, tasks.is_empty())
+      }
+      None => return Ok(false),
+    };
+    if now_empty {
+      self.tasks.remove(product);
+    }
+    if removed {
+      self.resolutions.0.get_mut().unwrap().clear();
+    }
+    Ok(removed)
+  }
+
+  ///
+  /// Decodes the Tasks described by the given bulk registration records, where the clause of each
+  /// Task is made up of the next `clause_len` selector records, and its name and doc are the next
+  /// strings. Each decoded Task is checked as it would be by `builder_finish`, but not against the
+  /// registry, and is returned along with whether it shadows the Tasks registered before it.
+  ///
+  fn decode_bulk(
+    task_records: &[TaskRecord],
+    selector_records: &[SelectorRecord],
+    strings: &[String],
+  ) -> Result<Vec<(Task, bool)>, TaskError> {
+    let mut decoded = Vec::with_capacity(task_records.len());
+    let mut offset = 0;
+    let mut string_offset = 0;
+    for (index, record) in task_records.iter().enumerate() {
+      let remaining = selector_records.len() - offset;
+      if record.clause_len > remaining as u64 {
+        return Err(TaskError::Invalid(format!(
+          "Task record {} has a clause of length {}, but only {} of {} selector records remain.",
+          index,
+          record.clause_len,
+          remaining,
+          selector_records.len(),
+        )));
+      }
+      let end = offset + record.clause_len as usize;
+      let mut clause = Vec::with_capacity(end - offset);
+      for selector in &selector_records[offset..end] {
+        clause.push(match selector.kind {
+          SELECTOR_RECORD_SELECT => {
+            Selector::Select(Select {
+              product: selector.product,
+              variant_key: None,
+              default_variant_value: None,
+              optional: selector.optional,
+              cardinality: if selector.many {
+                Cardinality::Many
+              } else {
+                Cardinality::One
+              },
+            })
+          }
+          SELECTOR_RECORD_SELECT_SUBJECT => {
+            Selector::SelectSubject(SelectSubject { product: selector.product })
+          }
+          kind => {
+            return Err(TaskError::Invalid(format!(
+              "Task record {} has a selector record of unknown kind {}.",
+              index,
+              kind
+            )))
+          }
+        });
+      }
+      offset = end;
+      let name = Self::bulk_string(strings, &mut string_offset, record.has_name, index, "name")?;
+      let doc = Self::bulk_string(strings, &mut string_offset, record.has_doc, index, "doc")?;
+      let task = Task {
+        product: record.product,
+        clause: clause,
+        func: record.func,
+        cacheable: record.cacheable,
+        include_subject: record.include_subject,
+        priority: record.priority,
+        timeout_ms: if record.has_timeout {
+          Some(record.timeout_ms)
+        } else {
+          None
+        },
+        name: name,
+        doc: doc,
+      };
+      Self::check_clause(&task, record.allow_empty, record.allow_self_select)?;
+      decoded.push((task, record.shadow));
+    }
+    if offset != selector_records.len() {
+      return Err(TaskError::Invalid(format!(
+        "{} of {} selector records were not part of the clause of any task record.",
+        selector_records.len() - offset,
+        selector_records.len(),
+      )));
+    }
+    if string_offset != strings.len() {
+      return Err(TaskError::Invalid(format!(
+        "{} of {} strings were not the name or doc of any task record.",
+        strings.len() - string_offset,
+        strings.len(),
+      )));
+    }
+    Ok(decoded)
+  }
+
+  ///
+  /// If the given task record has the given string, consumes and returns the next of the strings
+  /// of the bulk registration.
+  ///
+  fn bulk_string(
+    strings: &[String],
+    offset: &mut usize,
+    present: bool,
+    index: usize,
+    what: &str,
+  ) -> Result<Option<String>, TaskError> {
+    if !present {
+      return Ok(None);
+    }
+    let string = strings.get(*offset).ok_or_else(|| {
+      TaskError::Invalid(format!(
+        "Task record {} has a {}, but all {} strings were already consumed.",
+        index,
+        what,
+        strings.len(),
+      ))
+    })?;
+    *offset += 1;
+    Ok(Some(string.clone()))
+  }
+
+  ///
+  /// Registers the Tasks described by the given records in one call: see `decode_bulk`. All of the
+  /// Tasks are decoded and validated (against the registry, and against one another) before any
+  /// are registered, so registration is all or nothing: on error, none of the described Tasks are
+  /// registered. Tasks which shadow are registered as they would be by `builder_finish`, in order.
+  ///
+  pub fn bulk_add(
+    &mut self,
+    task_records: &[TaskRecord],
+    selector_records: &[SelectorRecord],
+    strings: &[String],
+  ) -> Result<(), TaskError> {
+    self.check_not_frozen()?;
+    let decoded: Vec<(Arc<Task>, bool)> =
+      Self::decode_bulk(task_records, selector_records, strings)?
+        .into_iter()
+        .map(|(task, shadow)| (Arc::new(task), shadow))
+        .collect();
+    for (index, &(ref task, shadow)) in decoded.iter().enumerate() {
+      self.check_not_provided(task)?;
+      if shadow {
+        continue;
+      }
+      // A Task is compared with those that it would join: the earlier Tasks of the batch for its
+      // product, back to the last which shadows, and otherwise the registered Tasks as well.
+      let earlier: Vec<(&Arc<Task>, bool)> = decoded[..index]
+        .iter()
+        .filter(|&&(ref earlier, _)| earlier.product == task.product)
+        .map(|&(ref earlier, shadow)| (earlier, shadow))
+        .collect();
+      let last_shadow = earlier.iter().rposition(|&(_, shadow)| shadow);
+      let registered = match last_shadow {
+        Some(_) => None,
+        None => self.tasks.get(&task.product),
+      };
+      let batched = earlier[last_shadow.unwrap_or(0)..].iter().map(
+        |&(earlier, _)| earlier,
+      );
+      Self::check_not_registered(
+        registered.into_iter().flat_map(|tasks| tasks.iter()).chain(batched),
+        task,
+      )?;
+    }
+    for &(ref task, _) in &decoded {
+      self.add_product_types(&task.product)?;
+    }
+
+    for (task, shadow) in decoded {
+      self.register(task, shadow);
+    }
+    self.resolutions.0.get_mut().unwrap().clear();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use core::{Function, TypeConstraint};
+  use externs::{self, SelectorRecord, TaskRecord, SELECTOR_RECORD_SELECT,
+                SELECTOR_RECORD_SELECT_SUBJECT};
+  use mock_externs::mock;
+  use selectors::{Cardinality, Select, SelectSubject, Selector};
+
+  use super::{TaskError, TaskOptions, Tasks};
+
+  fn task_record(func: Function, product: TypeConstraint, clause_len: u64) -> TaskRecord {
+    TaskRecord {
+      func: func,
+      product: product,
+      cacheable: true,
+      include_subject: false,
+      priority: 0,
+      allow_empty: clause_len == 0,
+      allow_self_select: false,
+      shadow: false,
+      has_timeout: false,
+      timeout_ms: 0,
+      has_name: false,
+      has_doc: false,
+      clause_len: clause_len,
+    }
+  }
+
+  fn selector_record(kind: u8, product: TypeConstraint) -> SelectorRecord {
+    SelectorRecord {
+      kind: kind,
+      product: product,
+      optional: false,
+      many: true,
+    }
+  }
+
+  fn new_tasks() -> Tasks {
+    let string_type = *externs::key_for(externs::store_bytes(b"")).type_id();
+    Tasks::new(
+      externs::key_for(externs::eval("None").unwrap()),
+      vec![string_type],
+    )
+  }
+
+  fn invalid_msg<T>(result: Result<T, TaskError>) -> String {
+    match result {
+      Err(TaskError::Invalid(msg)) => msg,
+      Err(e) => panic!("Expected an Invalid error, got {:?}", e),
+      Ok(_) => panic!("Expected an Invalid error."),
+    }
+  }
+
+  #[test]
+  fn decode_bulk_splits_clauses() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let b = mock.exactly(&[mock.new_type("B")]);
+    let (produce_a, produce_b) = (mock.function("produce_a"), mock.function("produce_b"));
+
+    let decoded = Tasks::decode_bulk(
+      &[task_record(produce_a, a, 2), task_record(produce_b, b, 0)],
+      &[
+        selector_record(SELECTOR_RECORD_SELECT, b),
+        selector_record(SELECTOR_RECORD_SELECT_SUBJECT, b),
+      ],
+      &[],
+    ).unwrap();
+
+    assert_eq!(2, decoded.len());
+    assert_eq!(produce_a, decoded[0].0.func);
+    assert_eq!(
+      vec![
+        Selector::Select(Select {
+          product: b,
+          variant_key: None,
+          default_variant_value: None,
+          optional: false,
+          cardinality: Cardinality::Many,
+        }),
+        Selector::SelectSubject(SelectSubject { product: b }),
+      ],
+      decoded[0].0.clause
+    );
+    assert_eq!(produce_b, decoded[1].0.func);
+    assert!(decoded[1].0.clause.is_empty());
+  }
+
+  #[test]
+  fn decode_bulk_reads_names_and_docs() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let mut named = task_record(mock.function("named"), a, 0);
+    named.has_name = true;
+    named.has_doc = true;
+    let mut documented = task_record(mock.function("documented"), a, 0);
+    documented.has_doc = true;
+    let strings = vec![
+      "name".to_string(),
+      "Named doc.".to_string(),
+      "Documented doc.".to_string(),
+    ];
+
+    let decoded = Tasks::decode_bulk(
+      &[named.clone(), task_record(mock.function("bare"), a, 0), documented],
+      &[],
+      &strings,
+    ).unwrap();
+    let names_and_docs = decoded
+      .iter()
+      .map(|&(ref task, _)| (task.name.clone(), task.doc.clone()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      vec![
+        (Some("name".to_string()), Some("Named doc.".to_string())),
+        (None, None),
+        (None, Some("Documented doc.".to_string())),
+      ],
+      names_and_docs
+    );
+
+    let missing = invalid_msg(Tasks::decode_bulk(&[named.clone()], &[], &strings[..1]));
+    assert!(missing.contains("Task record 0 has a doc, but all 1 strings were already consumed"));
+
+    let unused = invalid_msg(Tasks::decode_bulk(&[named], &[], &strings));
+    assert!(unused.contains("1 of 3 strings were not the name or doc of any task record"));
+  }
+
+  #[test]
+  fn decode_bulk_bounds_checks_clauses() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let produce = mock.function("produce");
+    let selectors = [selector_record(SELECTOR_RECORD_SELECT_SUBJECT, a)];
+
+    let overrun = invalid_msg(Tasks::decode_bulk(&[task_record(produce, a, 2)], &selectors, &[]));
+    assert!(overrun.contains("clause of length 2, but only 1 of 1 selector records remain"));
+
+    let unused = invalid_msg(Tasks::decode_bulk(&[task_record(produce, a, 0)], &selectors, &[]));
+    assert!(unused.contains("1 of 1 selector records were not part of the clause"));
+  }
+
+  #[test]
+  fn decode_bulk_rejects_unknown_kinds() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let msg = invalid_msg(Tasks::decode_bulk(
+      &[task_record(mock.function("produce"), a, 1)],
+      &[selector_record(7, a)],
+      &[],
+    ));
+    assert!(msg.contains("selector record of unknown kind 7"));
+  }
+
+  #[test]
+  fn decode_bulk_checks_clauses() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let mut record = task_record(mock.function("produce"), a, 0);
+    record.allow_empty = false;
+    let msg = invalid_msg(Tasks::decode_bulk(&[record], &[], &[]));
+    assert!(msg.contains("has an empty clause"));
+  }
+
+  fn options(allow_self_select: bool) -> TaskOptions {
+    TaskOptions {
+      cacheable: true,
+      include_subject: false,
+      priority: 0,
+      allow_empty: false,
+      allow_self_select: allow_self_select,
+      shadow: false,
+      timeout_ms: None,
+      name: None,
+      doc: None,
+    }
+  }
+
+  #[test]
+  fn builder_fail_is_returned_on_finish() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let b = mock.exactly(&[mock.new_type("B")]);
+    let mut tasks = new_tasks();
+
+    let id = tasks.builder_begin(mock.function("produce"), a, options(false));
+    tasks.builder_fail(id, TaskError::Invalid("first".to_string())).unwrap();
+    tasks.builder_fail(id, TaskError::Invalid("second".to_string())).unwrap();
+    tasks.add_select_subject(id, b).unwrap();
+    assert_eq!("first", invalid_msg(tasks.builder_finish(id)));
+    assert_eq!(0, tasks.stats().tasks);
+    assert_eq!(Err(TaskError::UnknownBuilder(id)), tasks.builder_fail(id, TaskError::Frozen));
+  }
+
+  #[test]
+  fn union_of_own_product_is_a_self_select() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let b = mock.exactly(&[mock.new_type("B")]);
+    let produce = mock.function("produce");
+    let mut tasks = new_tasks();
+
+    let id = tasks.builder_begin(produce, a, options(false));
+    tasks.add_select_union(id, vec![b, a]).unwrap();
+    let msg = invalid_msg(tasks.builder_finish(id));
+    assert!(msg.contains("selects its own product for the same subject at clause index 0"));
+    assert_eq!(0, tasks.stats().tasks);
+
+    // A union which does not contain the product is not a self select...
+    let id = tasks.builder_begin(produce, a, options(false));
+    tasks.add_select_union(id, vec![b]).unwrap();
+    tasks.builder_finish(id).unwrap();
+
+    // ...and one which does may opt in.
+    let id = tasks.builder_begin(mock.function("produce_self"), a, options(true));
+    tasks.add_select_union(id, vec![b, a]).unwrap();
+    tasks.builder_finish(id).unwrap();
+    assert_eq!(2, tasks.stats().tasks);
+  }
+
+  #[test]
+  fn bulk_add_is_all_or_nothing() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let b = mock.exactly(&[mock.new_type("B")]);
+    let produce = mock.function("produce");
+    let mut tasks = new_tasks();
+
+    // The second record duplicates the first, so neither may be registered.
+    let result = tasks.bulk_add(
+      &[task_record(produce, a, 1), task_record(produce, a, 1)],
+      &[
+        selector_record(SELECTOR_RECORD_SELECT_SUBJECT, b),
+        selector_record(SELECTOR_RECORD_SELECT_SUBJECT, b),
+      ],
+      &[],
+    );
+    match result {
+      Err(TaskError::DoubleRegistered(_)) => {}
+      r => panic!("Expected a DoubleRegistered error, got {:?}", r),
+    }
+    assert_eq!(0, tasks.stats().tasks);
+
+    tasks
+      .bulk_add(
+        &[task_record(produce, a, 1)],
+        &[selector_record(SELECTOR_RECORD_SELECT_SUBJECT, b)],
+        &[],
+      )
+      .unwrap();
+    assert_eq!(1, tasks.stats().tasks);
+  }
+
+  #[test]
+  fn bulk_add_shadows_in_order() {
+    let mock = mock();
+    let a = mock.exactly(&[mock.new_type("A")]);
+    let b = mock.exactly(&[mock.new_type("B")]);
+    let (first, second) = (mock.function("first"), mock.function("second"));
+    let mut tasks = new_tasks();
+    tasks
+      .bulk_add(
+        &[task_record(first, a, 1)],
+        &[selector_record(SELECTOR_RECORD_SELECT_SUBJECT, b)],
+        &[],
+      )
+      .unwrap();
+
+    // The shadowing record replaces the registered Task, so the Task after it may be registered
+    // with the same function and clause as the replaced Task.
+    let mut shadowing = task_record(second, a, 1);
+    shadowing.shadow = true;
+    tasks
+      .bulk_add(
+        &[shadowing, task_record(first, a, 1)],
+        &[
+          selector_record(SELECTOR_RECORD_SELECT_SUBJECT, b),
+          selector_record(SELECTOR_RECORD_SELECT_SUBJECT, b),
+        ],
+        &[],
+      )
+      .unwrap();
+    let stats = tasks.stats();
+    assert_eq!((2, 1), (stats.tasks, stats.shadowed));
+    assert_eq!(
+      vec![second, first],
+      tasks.gen_tasks(&a).iter().map(|task| task.func).collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn resolve_batches_compatible_products() {
+    let mock = mock();
+    let base = mock.new_type("Base");
+    let sub = mock.new_subtype("Sub", base);
+    let other = mock.new_type("Other");
+    let (sub_product, other_product) = (mock.exactly(&[sub]), mock.exactly(&[other]));
+    let mut tasks = new_tasks();
+    tasks
+      .singleton_add(mock.instance(sub, vec![]), sub_product)
+      .unwrap();
+    tasks
+      .bulk_add(&[task_record(mock.function("produce"), other_product, 0)], &[], &[])
+      .unwrap();
+
+    // Both registered products are checked against the requested product in a single call.
+    let requested = mock.subclasses_of(&[base]);
+    assert!(tasks.gen_singleton(&requested).is_some());
+    assert!(tasks.gen_tasks(&requested).is_empty());
+    assert_eq!(1, mock.satisfied_by_type_calls(&requested));
+  }
+
+  #[test]
+  fn exact_tasks_shadow_compatible_singletons() {
+    let mock = mock();
+    let base = mock.new_type("Base");
+    let sub = mock.new_subtype("Sub", base);
+    let requested = mock.subclasses_of(&[base]);
+    let mut tasks = new_tasks();
+    tasks
+      .singleton_add(mock.instance(sub, vec![]), mock.exactly(&[sub]))
+      .unwrap();
+    assert!(tasks.gen_singleton(&requested).is_some());
+
+    tasks
+      .bulk_add(&[task_record(mock.function("produce"), requested, 0)], &[], &[])
+      .unwrap();
+    assert!(tasks.gen_singleton(&requested).is_none());
+    assert_eq!(1, tasks.gen_tasks(&requested).len());
+  }
 }
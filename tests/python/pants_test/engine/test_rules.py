@@ -76,7 +76,7 @@ class RuleIndexTest(unittest.TestCase):
 
 class RulesetValidatorTest(unittest.TestCase):
   def create_validator(self, goal_to_product, rules):
-    return create_native_scheduler(rules)
+    return create_native_scheduler(rules, goal_to_product)
 
   def test_ruleset_with_missing_product_type(self):
     rules = _suba_root_rules + [TaskRule(A, [Select(B)], noop)]
@@ -131,7 +131,6 @@ class RulesetValidatorTest(unittest.TestCase):
                                       """).strip(),
                                     str(cm.exception))
 
-  @unittest.skip('testing api not used by non-example code')
   def test_ruleset_with_goal_not_produced(self):
     # The graph is complete, but the goal 'goal-name' requests A,
     # which is not produced by any rule.
@@ -146,6 +145,16 @@ class RulesetValidatorTest(unittest.TestCase):
     self.assert_equal_with_printing("no task for product used by goal \"goal-name\": AGoal",
                                     str(cm.exception))
 
+  def test_ruleset_with_goal_produced(self):
+    rules = _suba_root_rules + [
+      TaskRule(B, [Select(SubA)], noop)
+    ]
+
+    validator = self.create_validator({'goal-name': B}, rules)
+    validator.assert_ruleset_valid()
+    self.assertEquals(Exactly(B), validator.goal_product('goal-name'))
+    self.assertIsNone(validator.goal_product('other-goal-name'))
+
   def test_ruleset_with_explicit_type_constraint(self):
     rules = _suba_root_rules + [
       TaskRule(Exactly(A), [Select(B)], noop),
@@ -232,7 +241,7 @@ class RulesetValidatorTest(unittest.TestCase):
   def test_secondary_select_projection_failure(self):
     rules = _suba_root_rules + [
       TaskRule(Exactly(A), [SelectProjection(B, D, 'some', C)], noop),
-      TaskRule(C, [], noop)
+      TaskRule(C, [], noop, allow_empty=True)
     ]
 
     validator = self.create_validator({}, rules)
@@ -355,7 +364,7 @@ class RuleGraphMakerTest(unittest.TestCase):
   def test_multiple_selects(self):
     rules = [
       TaskRule(Exactly(A), [Select(SubA), Select(B)], noop),
-      TaskRule(B, [], noop)
+      TaskRule(B, [], noop, allow_empty=True)
     ]
 
     subgraph = self.create_subgraph(A, rules, SubA())
@@ -395,7 +404,7 @@ class RuleGraphMakerTest(unittest.TestCase):
   def test_noop_removal_in_subgraph(self):
     rules = [
       TaskRule(Exactly(A), [Select(C)], noop),
-      TaskRule(Exactly(A), [], noop),
+      TaskRule(Exactly(A), [], noop, allow_empty=True),
       SingletonRule(B, B()),
     ]
 
@@ -415,7 +424,7 @@ class RuleGraphMakerTest(unittest.TestCase):
   def test_noop_removal_full_single_subject_type(self):
     rules = _suba_root_rules + [
       TaskRule(Exactly(A), [Select(C)], noop),
-      TaskRule(Exactly(A), [], noop),
+      TaskRule(Exactly(A), [], noop, allow_empty=True),
     ]
 
     fullgraph = self.create_full_graph(RuleIndex.create(rules))
@@ -458,7 +467,7 @@ class RuleGraphMakerTest(unittest.TestCase):
     rules = [
       TaskRule(Exactly(B), [Select(C)], noop),
       TaskRule(Exactly(A), [Select(B)], noop),
-      TaskRule(Exactly(A), [], noop),
+      TaskRule(Exactly(A), [], noop, allow_empty=True),
     ]
     subgraph = self.create_subgraph(A, rules, SubA())
 
@@ -587,7 +596,7 @@ class RuleGraphMakerTest(unittest.TestCase):
       TaskRule(Exactly(A), [SelectDependencies(B, SubA, field_types=(C, D,))], noop),
       TaskRule(B, [Select(A)], noop),
       TaskRule(C, [Select(SubA)], noop),
-      TaskRule(SubA, [], noop)
+      TaskRule(SubA, [], noop, allow_empty=True)
     ]
 
     subgraph = self.create_subgraph(A, rules, SubA())
@@ -6,16 +6,21 @@ from __future__ import (absolute_import, division, generators, nested_scopes, pr
                         unicode_literals, with_statement)
 
 import os
+import time
 import unittest
 from textwrap import dedent
 
 from pants.base.cmd_line_spec_parser import CmdLineSpecParser
 from pants.build_graph.address import Address
-from pants.engine.addressable import BuildFileAddresses
+from pants.engine.addressable import BuildFileAddresses, Exactly, SubclassesOf
+from pants.engine.native import Function, TypeConstraint, TypeId
 from pants.engine.nodes import Return, Throw
-from pants.engine.rules import RootRule, TaskRule
-from pants.engine.selectors import Select, SelectVariant
+from pants.engine.rules import FirstMatchRule, MergeableRule, RootRule, SingletonRule, TaskRule
+from pants.engine.selectors import (Select, SelectDependencies, SelectTransitive, SelectUnion,
+                                    SelectVariant, SelectVariantValue)
+from pants.engine.struct import HasProducts, Variants
 from pants.util.contextutil import temporary_dir
+from pants.util.objects import datatype
 from pants_test.engine.examples.planners import (ApacheThriftJavaConfiguration, Classpath, GenGoal,
                                                  Jar, ThriftSources, setup_json_scheduler)
 from pants_test.engine.util import (assert_equal_with_printing, create_native_scheduler,
@@ -237,6 +242,54 @@ class B(object):
   pass
 
 
+class C(object):
+  pass
+
+
+class Polled(datatype('Polled', ['value'])):
+  pass
+
+
+class Generated(datatype('Generated', ['name'])):
+  pass
+
+
+class Selected(datatype('Selected', ['value'])):
+  pass
+
+
+class Deps(datatype('Deps', ['dependencies'])):
+  pass
+
+
+class Names(datatype('Names', ['names'])):
+  pass
+
+
+class Items(tuple):
+  pass
+
+
+class Holder(datatype('Holder', ['products']), HasProducts):
+  pass
+
+
+class Target(datatype('Target', ['name', 'children'])):
+  pass
+
+
+class SubTarget(Target):
+  pass
+
+
+class NotATarget(datatype('NotATarget', ['name'])):
+  pass
+
+
+class Node(datatype('Node', ['name', 'dependencies'])):
+  pass
+
+
 def fn_raises(x):
   raise Exception('An exception for {}'.format(type(x).__name__))
 
@@ -245,7 +298,49 @@ def nested_raise(x):
   fn_raises(x)
 
 
-class SchedulerTraceTest(unittest.TestCase):
+def node_for_target(target):
+  return Node(target.name, target.children)
+
+
+def collect_names(nodes):
+  return Names(tuple(n.name for n in nodes))
+
+
+class SchedulerRulesTestBase(unittest.TestCase):
+  """A base for tests which run requests against a scheduler created for a handful of rules."""
+
+  def _run(self, rules, subject, product):
+    """Requests the product for the subject from a new scheduler for the rules, returning the root.
+
+    The scheduler and request are kept as `self.scheduler` and `self.request` so that the graph
+    may be inspected, or so that another request may be made with `_run_again`.
+    """
+    self.scheduler = create_native_scheduler(rules)
+    return self._run_again(subject, product)
+
+  def _run_again(self, subject, product):
+    """Requests the product for the subject from the scheduler of the last `_run`."""
+    self.request = self.scheduler._native.new_execution_request()
+    self.scheduler.add_root_selection(self.request, subject, product)
+    root, = self.scheduler.run_and_return_roots(self.request)
+    return root
+
+  def _returned(self, rules, subject, product):
+    """Like `_run`, but asserts that the request succeeded, and returns its value."""
+    root = self._run(rules, subject, product)
+    self.assertEquals(Return, type(root))
+    return root.value
+
+  def _visualize(self):
+    """Returns a visualization of the graph of the last request."""
+    with temporary_dir() as td:
+      output_path = os.path.join(td, 'output.dot')
+      self.scheduler.visualize_graph_to_file(self.request, output_path)
+      with open(output_path, 'rb') as fh:
+        return fh.read()
+
+
+class SchedulerDiagnosticsTest(SchedulerRulesTestBase):
   assert_equal_with_printing = assert_equal_with_printing
 
   def test_trace_includes_rule_exception_traceback(self):
@@ -253,14 +348,9 @@ class SchedulerTraceTest(unittest.TestCase):
       RootRule(B),
       TaskRule(A, [Select(B)], nested_raise)
     ]
+    self._run(rules, B(), A)
 
-    scheduler = create_native_scheduler(rules)
-    request = scheduler._native.new_execution_request()
-    subject = B()
-    scheduler.add_root_selection(request, subject, A)
-    scheduler.run_and_return_roots(request)
-
-    trace = '\n'.join(scheduler.graph_trace(request))
+    trace = '\n'.join(self.scheduler.graph_trace(self.request))
     # NB removing location info to make trace repeatable
     trace = remove_locations_from_traceback(trace)
 
@@ -277,3 +367,983 @@ class SchedulerTraceTest(unittest.TestCase):
                                raise Exception('An exception for {}'.format(type(x).__name__))
                            Exception: An exception for B''').lstrip() + '\n\n', # Traces include two empty lines after.
                                trace)
+
+  def test_visualize_highlights_the_producer_of_a_selected_value(self):
+    def a_from_b(b):
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], a_from_b),
+    ]
+    self._returned(rules, B(), A)
+
+    # Only the edge from the Select to the Task which produced its value is highlighted.
+    bold_edges = [line for line in self._visualize().splitlines() if '[style=bold]' in line]
+    self.assertEquals(1, len(bold_edges))
+    select_str, task_str = bold_edges[0].split(' -> ')
+    self.assertIn('Select(', select_str)
+    self.assertIn('Task(<function a_from_b', task_str)
+
+  def test_subject_in_its_own_dependencies_is_skipped(self):
+    def self_deps(b):
+      return Deps((b,))
+
+    def collect(deps):
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(Deps, [Select(B)], self_deps),
+      TaskRule(A, [SelectDependencies(A, Deps, field_types=(B,))], collect),
+    ]
+    self._returned(rules, B(), A)
+
+    # The skip is recorded in the diagnostics of the Task that selected the dependencies.
+    self.assertIn('in its own `dependencies` field.', self._visualize())
+
+  def test_self_select_completes_with_cycle(self):
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B), Select(A)], lambda b, a: A(), allow_self_select=True),
+    ]
+    root = self._run(rules, B(), A)
+
+    self.assertEquals(Throw, type(root))
+    self.assertIn('Dep graph contained a cycle.',
+                  '\n'.join(self.scheduler.graph_trace(self.request)))
+
+  def test_outcome_counts_by_node_kind(self):
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], lambda b: A()),
+    ]
+    self._run(rules, B(), A)
+
+    counts = self.scheduler.outcome_counts()
+    self.assertEquals(1, counts[('Task', 'Return')])
+    self.assertEquals(1, counts[('Select', 'Return')])
+
+  def test_reruns_are_counted(self):
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], lambda b: A(), cacheable=False),
+    ]
+    subject = B()
+    self._run(rules, subject, A)
+    self._run_again(subject, A)
+
+    # The uncacheable Task runs in each execution. Each A differs from the last, so the Select
+    # which depends on it is removed, and runs again as well.
+    counts = self.scheduler.outcome_counts()
+    self.assertEquals(2, counts[('Task', 'Return')])
+    self.assertEquals(2, counts[('Select', 'Return')])
+
+
+class SchedulerRuleOptionsTest(SchedulerRulesTestBase):
+
+  def _run_twice(self, poll):
+    """Requests an A (computed from an uncacheable Polled value) twice, returning the A calls."""
+    calls = []
+
+    def produce_a(polled):
+      calls.append(polled.value)
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(Polled, [Select(B)], poll, cacheable=False),
+      TaskRule(A, [Select(Polled)], produce_a),
+    ]
+    subject = B()
+    self.assertEquals(Return, type(self._run(rules, subject, A)))
+    self.assertEquals(Return, type(self._run_again(subject, A)))
+    return calls
+
+  def test_unchanged_uncacheable_result_reuses_dependents(self):
+    polls = []
+
+    def poll(b):
+      polls.append(b)
+      return Polled('same')
+
+    self.assertEquals(['same'], self._run_twice(poll))
+    self.assertEquals(2, len(polls))
+
+  def test_changed_uncacheable_result_reruns_dependents(self):
+    polls = []
+
+    def poll(b):
+      polls.append(b)
+      return Polled(len(polls))
+
+    self.assertEquals([1, 2], self._run_twice(poll))
+
+  def test_uncacheable_rule_reruns(self):
+    calls = []
+
+    def produce_a(b):
+      calls.append('a')
+      return A()
+
+    def produce_c(b):
+      calls.append('c')
+      return C()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], produce_a),
+      TaskRule(C, [Select(B)], produce_c, cacheable=False),
+    ]
+    subject = B()
+    self._run(rules, subject, A)
+    self._run_again(subject, A)
+    self._run_again(subject, C)
+    self._run_again(subject, C)
+
+    self.assertEquals(['a', 'c', 'c'], calls)
+
+  def test_slow_rule_exceeds_timeout(self):
+    def produce_a(b):
+      time.sleep(0.1)
+      return A()
+
+    def produce_c(b):
+      return C()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], produce_a, timeout_ms=1),
+      TaskRule(C, [Select(B)], produce_c, timeout_ms=60000),
+    ]
+    a_state = self._run(rules, B(), A)
+    c_state = self._run_again(B(), C)
+
+    self.assertEquals(Throw, type(a_state))
+    self.assertIn('produce_a took', str(a_state.exc))
+    self.assertIn('which exceeded its timeout of 1ms', str(a_state.exc))
+    self.assertEquals(Return, type(c_state))
+
+  def test_subject_is_passed_first(self):
+    received = []
+
+    def produce_a(subject, b):
+      received.append((subject, b))
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], produce_a, include_subject=True),
+    ]
+    subject = B()
+    self._returned(rules, subject, A)
+
+    self.assertEquals([(subject, subject)], received)
+
+
+class SchedulerSelectorsTest(SchedulerRulesTestBase):
+
+  def _generated_rules(self, address_variants, selector):
+    return [
+      RootRule(Address),
+      TaskRule(Variants, [Select(Address)], address_variants),
+      TaskRule(Generated, [Select(Address)], lambda address: Generated('apache_java')),
+      TaskRule(Generated, [Select(Address)], lambda address: Generated('scrooge')),
+      TaskRule(Selected, [selector], lambda generated: Selected(generated.name)),
+    ]
+
+  def _selected(self, rules, *subjects):
+    return [self._returned(rules, subject, Selected).value for subject in subjects]
+
+  def test_variant_configured_on_subject_selects_task_output(self):
+    # The variant configured for each address selects between the outputs of the Generated tasks.
+    def address_variants(address):
+      return Variants(default={'thrift': address.target_name})
+
+    rules = self._generated_rules(address_variants, SelectVariant(Generated, 'thrift'))
+    self.assertEquals(['apache_java', 'scrooge'],
+                      self._selected(rules,
+                                     Address.parse('a:apache_java'),
+                                     Address.parse('a:scrooge')))
+
+  def test_configured_variant_takes_precedence_over_default(self):
+    def address_variants(address):
+      if address.target_name == 'configured':
+        return Variants(default={'thrift': 'apache_java'})
+      return Variants()
+
+    selector = SelectVariant(Generated, 'thrift', default_value='scrooge')
+    rules = self._generated_rules(address_variants, selector)
+    self.assertEquals(['apache_java', 'scrooge'],
+                      self._selected(rules,
+                                     Address.parse('a:configured'),
+                                     Address.parse('a:unconfigured')))
+
+  def test_variants_computed_by_task_for_address(self):
+    # Computing the Variants of an Address with a task must not recursively look up the Variants
+    # of that Address.
+    def address_variants(address):
+      return Variants(default={'thrift': 'apache_java'})
+
+    def produce_a(address):
+      return A()
+
+    rules = [
+      RootRule(Address),
+      TaskRule(Variants, [Select(Address)], address_variants),
+      TaskRule(A, [Select(Address)], produce_a),
+    ]
+    self.assertIsInstance(self._returned(rules, Address.parse('a:b'), A), A)
+
+  def test_variant_value_selected_by_task(self):
+    def address_variants(address):
+      return Variants(default={'thrift': 'apache_java'})
+
+    rules = [
+      RootRule(Address),
+      TaskRule(Variants, [Select(Address)], address_variants),
+      TaskRule(Selected, [SelectVariantValue('thrift')], lambda value: Selected(value)),
+    ]
+    self.assertEquals(['apache_java'], self._selected(rules, Address.parse('a:b')))
+
+  def test_address_listed_twice_is_selected_once(self):
+    def address_deps(b):
+      return Deps((Address.parse('a:b'), Address.parse('c:d'), Address.parse('a:b')))
+
+    def collect(selected):
+      return Names(tuple(s.value for s in selected))
+
+    rules = [
+      RootRule(B),
+      TaskRule(Deps, [Select(B)], address_deps),
+      TaskRule(Selected, [Select(Address)], lambda address: Selected(address.spec)),
+      TaskRule(Names, [SelectDependencies(Selected, Deps, field_types=(Address,))], collect),
+    ]
+    self.assertEquals(('a:b', 'c:d'), self._returned(rules, B(), Names).names)
+
+  def test_dependencies_of_mixed_types(self):
+    # Members which are instances of a subclass of a field type are selected as that field type,
+    # while members of other types are skipped.
+    rules = [
+      RootRule(Target),
+      TaskRule(Node, [Select(SubclassesOf(Target))], node_for_target),
+      TaskRule(Names, [SelectDependencies(Node, Node, field_types=(Target,))], collect_names),
+    ]
+    subject = Target('root', (Target('a', ()), NotATarget('b'), SubTarget('c', ())))
+    self.assertEquals(('a', 'c'), self._returned(rules, subject, Names).names)
+
+  def test_transitive_dependencies_of_mixed_types(self):
+    # Likewise for each round of a transitive expansion.
+    rules = [
+      RootRule(Target),
+      TaskRule(Node, [Select(SubclassesOf(Target))], node_for_target),
+      TaskRule(Names, [SelectTransitive(Node, Node, field_types=(Target,))], collect_names),
+    ]
+    subject = Target('root', (SubTarget('sub', (Target('leaf', ()), NotATarget('x'))),
+                              NotATarget('y')))
+    self.assertEquals(('sub', 'leaf'), self._returned(rules, subject, Names).names)
+
+  def _select_union(self, rules):
+    rules = [
+      RootRule(B),
+      TaskRule(Selected, [SelectUnion((C, A))], lambda value: Selected(value)),
+    ] + rules
+    return self._returned(rules, B(), Selected).value
+
+  def test_union_falls_back_to_later_product(self):
+    rules = [
+      TaskRule(A, [Select(B)], lambda b: A()),
+    ]
+    self.assertIsInstance(self._select_union(rules), A)
+
+  def test_union_earlier_product_wins(self):
+    rules = [
+      TaskRule(A, [Select(B)], lambda b: A()),
+      TaskRule(C, [Select(B)], lambda b: C()),
+    ]
+    self.assertIsInstance(self._select_union(rules), C)
+
+
+class SchedulerCandidatesTest(SchedulerRulesTestBase):
+
+  def test_fallback_loses_to_earlier_task(self):
+    calls = []
+
+    def preferred(b):
+      calls.append('preferred')
+      return A()
+
+    def fallback(b):
+      calls.append('fallback')
+      return A()
+
+    rules = [
+      RootRule(B),
+      FirstMatchRule(A),
+      TaskRule(A, [Select(B)], preferred),
+      TaskRule(A, [Select(B)], fallback),
+    ]
+    self._returned(rules, B(), A)
+
+    self.assertEquals(['preferred'], calls)
+
+  def test_values_of_all_tasks_are_merged(self):
+    rules = [
+      RootRule(B),
+      MergeableRule(Items),
+      TaskRule(Items, [Select(B)], lambda b: Items(('a',))),
+      TaskRule(Items, [Select(B)], lambda b: Items(('b', 'c'))),
+    ]
+    self.assertEquals(('a', 'b', 'c'), tuple(self._returned(rules, B(), Items)))
+
+  def test_empty_products_fall_through_to_tasks(self):
+    rules = [
+      RootRule(Holder),
+      TaskRule(Selected, [Select(Holder)], lambda holder: Selected('task')),
+    ]
+    empty = self._returned(rules, Holder(()), Selected)
+    held = self._returned(rules, Holder((Selected('held'),)), Selected)
+
+    self.assertEquals('task', empty.value)
+    self.assertEquals('held', held.value)
+
+  def test_compatible_constraint_resolves_to_registered_tasks(self):
+    # The task for A is registered for Exactly(A), but is requested via SubclassesOf(A).
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], lambda b: A()),
+      TaskRule(C, [Select(SubclassesOf(A))], lambda a: C()),
+    ]
+    self.assertEquals(C, type(self._returned(rules, B(), C)))
+
+
+class SchedulerFingerprintTest(unittest.TestCase):
+
+  def test_priority_changes_fingerprint(self):
+    def produce_a(b):
+      return A()
+
+    def fingerprint(priority):
+      rules = [RootRule(B), TaskRule(A, [Select(B)], produce_a, priority=priority)]
+      return create_native_scheduler(rules).rules_fingerprint()
+
+    self.assertEquals(fingerprint(0), fingerprint(0))
+    self.assertNotEqual(fingerprint(0), fingerprint(1))
+
+  def test_function_code_changes_fingerprint(self):
+    def fingerprint(func):
+      rules = [RootRule(B), TaskRule(A, [Select(B)], func)]
+      return create_native_scheduler(rules).rules_fingerprint()
+
+    # Functions with the same name are identified by their code, rather than by their names.
+    self.assertEquals(fingerprint(lambda b: A()), fingerprint(lambda b: A()))
+    self.assertNotEqual(fingerprint(lambda b: A()), fingerprint(lambda b: A(b)))
+
+  def test_singleton_without_repr_has_stable_fingerprint(self):
+    def produce_a(b, c):
+      return A()
+
+    def fingerprint(singleton):
+      rules = [
+        RootRule(B),
+        SingletonRule(C, singleton),
+        TaskRule(A, [Select(B), Select(C)], produce_a),
+      ]
+      return create_native_scheduler(rules).rules_fingerprint()
+
+    # The default repr of a C includes its address, so it is identified by its attributes instead.
+    self.assertEquals(fingerprint(C()), fingerprint(C()))
+    different = C()
+    different.value = 1
+    self.assertNotEqual(fingerprint(C()), fingerprint(different))
+
+  def test_variant_changes_fingerprint(self):
+    def produce_a(b, generated):
+      return A()
+
+    def generate(b):
+      return Generated('thrift')
+
+    def fingerprint(selector):
+      rules = [
+        RootRule(B),
+        TaskRule(Generated, [Select(B)], generate),
+        TaskRule(A, [Select(B), selector], produce_a),
+      ]
+      return create_native_scheduler(rules).rules_fingerprint()
+
+    self.assertNotEqual(fingerprint(SelectVariant(Generated, 'thrift')),
+                        fingerprint(SelectVariant(Generated, 'scrooge')))
+    self.assertNotEqual(fingerprint(SelectVariant(Generated, 'thrift')),
+                        fingerprint(SelectVariant(Generated, 'thrift', default_value='scrooge')))
+
+  def test_doc_does_not_change_fingerprint(self):
+    def produce_a(b):
+      return A()
+
+    def fingerprint(doc):
+      rules = [RootRule(B), TaskRule(A, [Select(B)], produce_a, doc=doc)]
+      return create_native_scheduler(rules).rules_fingerprint()
+
+    self.assertEquals(fingerprint('One doc.'), fingerprint('Another doc.'))
+
+
+class SchedulerRulesReportTest(unittest.TestCase):
+
+  def test_candidates_report(self):
+    def from_b(b):
+      return A()
+
+    def from_c(c):
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], from_b),
+      TaskRule(A, [Select(C)], from_c),
+    ]
+    scheduler = create_native_scheduler(rules)
+
+    report = scheduler.candidates_report(B, A)
+    self.assertIn('(A, (Select(B),), from_b): first clause is satisfiable', report)
+    self.assertIn('(A, (Select(C),), from_c): first clause is not satisfiable', report)
+
+  def test_candidates_report_includes_doc(self):
+    def from_b(b):
+      """Computes an A from a B.
+
+      More detail which is not included in the summary.
+      """
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], from_b),
+    ]
+    report = create_native_scheduler(rules).candidates_report(B, A)
+    self.assertIn('from_b): first clause is satisfiable (Computes an A from a B.)', report)
+    self.assertNotIn('More detail', report)
+
+  def test_candidates_ordered_by_priority(self):
+    def low(b):
+      return A()
+
+    def high(b):
+      return A()
+
+    def default(b):
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], low, priority=-1),
+      TaskRule(A, [Select(B)], default),
+      TaskRule(A, [Select(B)], high, priority=1),
+    ]
+    report = create_native_scheduler(rules).candidates_report(B, A)
+    positions = [report.index('{}: first clause'.format(name))
+                 for name in ('high', 'default', 'low')]
+    self.assertEquals(sorted(positions), positions)
+
+  def test_rules_report(self):
+    def produce_a(b):
+      return A()
+
+    rules = [
+      RootRule(B),
+      SingletonRule(C, C()),
+      TaskRule(A, [Select(B), Select(C)], produce_a, priority=2),
+    ]
+    report = create_native_scheduler(rules).rules_report()
+
+    self.assertIn(dedent('''\
+      Exactly(A):
+        task produce_a priority=2 cacheable=true include_subject=false
+          Select(B)
+          Select(C)'''), report)
+    self.assertIn('Exactly(C):\n  singleton ', report)
+    self.assertEquals(report, create_native_scheduler(rules).rules_report())
+
+  def test_rules_report_includes_doc(self):
+    def produce_a(b):
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], produce_a, doc='Produces an A.'),
+    ]
+    report = create_native_scheduler(rules).rules_report()
+
+    self.assertIn(dedent('''\
+      Exactly(A):
+        task produce_a priority=0 cacheable=true include_subject=false
+          doc: Produces an A.
+          Select(B)'''), report)
+
+  def test_shadowed_rules_report(self):
+    def first(b):
+      return A()
+
+    def second(b):
+      return A()
+
+    def third(b):
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], first),
+      TaskRule(A, [Select(B)], second, shadow=True),
+      TaskRule(A, [Select(B)], third),
+    ]
+    report = create_native_scheduler(rules).rules_report()
+
+    self.assertIn(dedent('''\
+      Exactly(A):
+        task second priority=0 cacheable=true include_subject=false
+          Select(B)
+        task third priority=0 cacheable=true include_subject=false
+          Select(B)
+        shadowed (A, (Select(B),), first)'''), report)
+
+  def test_all_products(self):
+    def a_from_b(b):
+      return A()
+
+    def a_from_c(b):
+      return A()
+
+    rules = [
+      RootRule(B),
+      SingletonRule(C, C()),
+      TaskRule(A, [Select(B)], a_from_b),
+      TaskRule(A, [Select(B)], a_from_c, priority=1),
+    ]
+    products = create_native_scheduler(rules).all_products()
+
+    self.assertEquals(1, products.count(Exactly(A)))
+    self.assertEquals(1, products.count(Exactly(C)))
+    self.assertEquals(len(products), len(set(products)))
+    self.assertEquals(products, create_native_scheduler(rules).all_products())
+
+  def test_rules_stats(self):
+    def produce_a(b, c):
+      return A()
+
+    def produce_b():
+      return B()
+
+    rules = [
+      RootRule(B),
+      SingletonRule(C, C()),
+      TaskRule(A, [Select(B), Select(C)], produce_a),
+      TaskRule(B, [], produce_b, allow_empty=True),
+    ]
+    stats = create_native_scheduler(rules).rules_stats()
+
+    self.assertEquals(2, stats['selectors'])
+    self.assertEquals(2, stats['max_clause_len'])
+    self.assertEquals(1, stats['singletons'])
+    self.assertEquals(0, stats['aliases'])
+    self.assertEquals(0, stats['preparing'])
+    self.assertEquals(stats['tasks'] - 2, create_native_scheduler([]).rules_stats()['tasks'])
+
+  def test_unreachable_rules(self):
+    def a_from_b(b):
+      return A()
+
+    def c_from_a(a):
+      return C()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], a_from_b),
+      TaskRule(C, [Select(A)], c_from_a),
+    ]
+    scheduler = create_native_scheduler(rules)
+
+    self.assertEquals([], scheduler.unreachable_rules([C]))
+    self.assertEquals(['(C, (Select(A),), c_from_a)'], scheduler.unreachable_rules([A]))
+
+
+class NativeTaskRegistrationTest(unittest.TestCase):
+
+  _native = init_native()
+
+  def setUp(self):
+    self.lib = self._native.lib
+    self.tasks = self._native.new_tasks()
+
+  def _constraint(self, typ):
+    return TypeConstraint(self._native.context.to_key(Exactly(typ)))
+
+  def _subclass_constraints_buf(self, types):
+    return self._native.context.type_constraints_buf(
+      [TypeConstraint(self._native.context.to_key(SubclassesOf(t))) for t in types])
+
+  def _begin_args(self, func=None, **options):
+    task_options = dict(cacheable=True,
+                        include_subject=False,
+                        priority=0,
+                        allow_empty=True,
+                        allow_self_select=False,
+                        shadow=False,
+                        has_timeout=False,
+                        timeout_ms=0,
+                        has_name=False,
+                        name=self._native.context.utf8_buf(''),
+                        has_doc=False,
+                        doc=self._native.context.utf8_buf(''))
+    task_options.update(options)
+    return (self.tasks,
+            func or Function(self._native.context.to_key(lambda b: A())),
+            self._constraint(A),
+            task_options)
+
+  def _begin(self):
+    return self.lib.tasks_task_begin(*self._begin_args())
+
+  def _builder_begin(self):
+    return self.lib.tasks_builder_begin(*self._begin_args())
+
+  def _remove(self, func, product):
+    res = self.lib.tasks_task_remove(self.tasks, func, product)
+    self.assertFalse(res.is_throw)
+    return self._native.context.from_value(res.value)
+
+  def assert_registration_error(self, res, msg_str):
+    self.assertTrue(res.is_throw)
+    self.assertIn(msg_str, str(self._native.context.from_value(res.value)))
+
+  def test_builder_begin_undecodable_name(self):
+    builder = self.lib.tasks_builder_begin(
+      *self._begin_args(has_name=True, name=self._native.context.buf(b'\xff')))
+    res = self.lib.tasks_builder_finish(self.tasks, builder)
+    self.assert_registration_error(res, 'Failed to decode name for task')
+    # The builder was consumed, and nothing was registered.
+    res = self.lib.tasks_builder_finish(self.tasks, builder)
+    self.assert_registration_error(res, 'it was never begun, or was already finished')
+
+  def test_end_without_begin(self):
+    self.assert_registration_error(self.lib.tasks_task_end(self.tasks), 'Must `begin()`')
+
+  def test_task_abort(self):
+    self.assertFalse(self.lib.tasks_task_abort(self.tasks))
+    self.assertFalse(self._begin().is_throw)
+    self.assertTrue(self.lib.tasks_task_abort(self.tasks))
+    self.assertFalse(self.lib.tasks_task_abort(self.tasks))
+    # Another task may be begun, and the registry may be frozen.
+    self.assertFalse(self._begin().is_throw)
+    self.assertFalse(self.lib.tasks_task_end(self.tasks).is_throw)
+    self.assertFalse(self.lib.tasks_freeze(self.tasks).is_throw)
+
+  def test_builder_abort(self):
+    builder = self._builder_begin()
+    self.assertTrue(self.lib.tasks_builder_abort(self.tasks, builder))
+    self.assertFalse(self.lib.tasks_builder_abort(self.tasks, builder))
+    res = self.lib.tasks_builder_finish(self.tasks, builder)
+    self.assert_registration_error(res, 'No task is being built with id')
+    self.assertFalse(self.lib.tasks_freeze(self.tasks).is_throw)
+
+  def test_clause_without_begin(self):
+    res = self.lib.tasks_add_select_subject(self.tasks, 0, self._constraint(B))
+    self.assert_registration_error(res, 'Must `begin()`')
+
+  def test_begin_without_end(self):
+    self.assertFalse(self._begin().is_throw)
+    self.assert_registration_error(self._begin(), 'Must `end()`')
+
+  def test_empty_union(self):
+    builder = self._builder_begin()
+    res = self.lib.tasks_add_select_union(self.tasks,
+                                          builder,
+                                          self._native.context.type_constraints_buf([]))
+    self.assert_registration_error(res, 'must select at least one product')
+
+  def test_int_variant_key(self):
+    builder = self._builder_begin()
+    res = self.lib.tasks_add_select_variant(self.tasks,
+                                            builder,
+                                            self._constraint(A),
+                                            self._native.context.to_key(1),
+                                            False,
+                                            self._native.context.utf8_buf(''))
+    self.assert_registration_error(res, 'A variant key must be a string, but `1` has type int.')
+    res = self.lib.tasks_add_select_variant_value(self.tasks,
+                                                  builder,
+                                                  self._constraint(A),
+                                                  self._native.context.to_key(1))
+    self.assert_registration_error(res, 'A variant key must be a string')
+
+  def test_empty_clause(self):
+    builder = self.lib.tasks_builder_begin(*self._begin_args(allow_empty=False))
+    res = self.lib.tasks_builder_finish(self.tasks, builder)
+    self.assert_registration_error(res, 'has an empty clause')
+
+  def test_self_select(self):
+    builder = self._builder_begin()
+    res = self.lib.tasks_add_select_subject(self.tasks, builder, self._constraint(B))
+    self.assertFalse(res.is_throw)
+    res = self.lib.tasks_add_select(self.tasks, builder, self._constraint(A), False, False)
+    self.assertFalse(res.is_throw)
+    res = self.lib.tasks_builder_finish(self.tasks, builder)
+    self.assert_registration_error(res, 'selects its own product for the same subject at clause '
+                                        'index 1 (Select(A))')
+
+  def test_self_select_allowed(self):
+    builder = self.lib.tasks_builder_begin(*self._begin_args(allow_self_select=True))
+    res = self.lib.tasks_add_select(self.tasks, builder, self._constraint(A), False, False)
+    self.assertFalse(res.is_throw)
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, builder).is_throw)
+
+  def test_self_select_rule(self):
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B), Select(A)], lambda b, a: A()),
+    ]
+    with self.assertRaises(Exception) as cm:
+      create_native_scheduler(rules)
+    self.assertIn('must be registered with allow_self_select', str(cm.exception))
+
+  def _shadow_args(self, func):
+    return self._begin_args(func, shadow=True)
+
+  def test_shadow_then_register_again(self):
+    first, second = self._func(), self._func()
+    begin_args = self._begin_args(first)
+    builder = self.lib.tasks_builder_begin(*begin_args)
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, builder).is_throw)
+    builder = self.lib.tasks_builder_begin(*self._shadow_args(second))
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, builder).is_throw)
+    self.assertEquals(1, self.lib.tasks_stats(self.tasks).shadowed)
+
+    # The shadowed task is no longer registered, so it may be registered again after the shadow.
+    builder = self.lib.tasks_builder_begin(*begin_args)
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, builder).is_throw)
+    self.assertTrue(self._remove(second, self._constraint(A)))
+    self.assertTrue(self._remove(first, self._constraint(A)))
+
+  def test_shadow_equal_task(self):
+    func = self._func()
+    builder = self.lib.tasks_builder_begin(*self._shadow_args(func))
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, builder).is_throw)
+    builder = self.lib.tasks_builder_begin(*self._shadow_args(func))
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, builder).is_throw)
+    self.assertEquals(0, self.lib.tasks_stats(self.tasks).shadowed)
+    self.assertTrue(self._remove(func, self._constraint(A)))
+
+  def test_duplicate_field_types(self):
+    builder = self._builder_begin()
+    res = self.lib.tasks_add_select_dependencies(self.tasks,
+                                                 builder,
+                                                 self._constraint(A),
+                                                 self._constraint(B),
+                                                 self._native.context.utf8_buf('dependencies'),
+                                                 self._native.to_ids_buf([C, C]),
+                                                 self._subclass_constraints_buf([C, C]),
+                                                 False,
+                                                 False)
+    self.assert_registration_error(res, 'more than once')
+
+  def test_interleaved_builders(self):
+    first = self._builder_begin()
+    second = self._builder_begin()
+    self.assertNotEquals(first, second)
+    res = self.lib.tasks_add_select_subject(self.tasks, second, self._constraint(B))
+    self.assertFalse(res.is_throw)
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, first).is_throw)
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, second).is_throw)
+
+  def test_clause_for_unknown_builder(self):
+    res = self.lib.tasks_add_select_subject(self.tasks, 42, self._constraint(B))
+    self.assert_registration_error(res, 'No task is being built with id 42')
+
+  def test_finish_twice(self):
+    builder = self._builder_begin()
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, builder).is_throw)
+    res = self.lib.tasks_builder_finish(self.tasks, builder)
+    self.assert_registration_error(res, 'already finished')
+
+  def test_double_registration_ignores_names(self):
+    def produce_a(b):
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], produce_a),
+      TaskRule(A, [Select(B)], produce_a, name='renamed'),
+    ]
+    with self.assertRaises(Exception) as cm:
+      create_native_scheduler(rules)
+    self.assertIn('was double-registered', str(cm.exception))
+
+  def test_registration_with_different_clauses(self):
+    def produce_a(*args):
+      return A()
+
+    rules = [
+      RootRule(B),
+      RootRule(C),
+      TaskRule(A, [Select(B)], produce_a),
+      TaskRule(A, [Select(C)], produce_a),
+    ]
+    with self.assertRaises(Exception) as cm:
+      create_native_scheduler(rules)
+    self.assertIn(dedent('''\
+      The task produce_a was registered twice with different clauses for Exactly(A):
+        existing: (A, (Select(B),), produce_a)
+        new: (A, (Select(C),), produce_a)'''), str(cm.exception))
+
+  def test_singleton_conflicts_with_task(self):
+    rules = [
+      RootRule(B),
+      SingletonRule(A, A()),
+      TaskRule(A, [Select(B)], lambda b: A()),
+    ]
+    with self.assertRaises(Exception) as cm:
+      create_native_scheduler(rules)
+    self.assertIn('singleton', str(cm.exception))
+
+  def test_singleton_does_not_satisfy_product(self):
+    res = self.lib.tasks_singleton_add(self.tasks,
+                                       self._native.context.to_value(B()),
+                                       self._constraint(A))
+    self.assert_registration_error(res, 'A singleton of type B was installed for the product '
+                                        'Exactly(A), which it does not satisfy.')
+
+  def test_singleton_rule_does_not_satisfy_product(self):
+    rules = [
+      SingletonRule(A, B()),
+    ]
+    with self.assertRaises(Exception) as cm:
+      create_native_scheduler(rules)
+    self.assertIn('which it does not satisfy', str(cm.exception))
+
+  def test_remove_unregistered_task(self):
+    func = Function(self._native.context.to_key(lambda b: A()))
+    self.assertFalse(self._remove(func, self._constraint(A)))
+
+  def test_remove_task(self):
+    begin_args = self._begin_args()
+    func = begin_args[1]
+    builder = self.lib.tasks_builder_begin(*begin_args)
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, builder).is_throw)
+    self.assertTrue(self._remove(func, self._constraint(A)))
+    self.assertFalse(self._remove(func, self._constraint(A)))
+
+  def test_replace_unregistered_task(self):
+    builder = self._builder_begin()
+    func = Function(self._native.context.to_key(lambda b: A()))
+    res = self.lib.tasks_builder_replace(self.tasks, builder, func)
+    self.assert_registration_error(res, 'to be replaced by')
+
+  def _func(self):
+    return Function(self._native.context.to_key(lambda b: A()))
+
+  def _task_record(self, func, clause_len, has_name=False, has_doc=False):
+    return (func, self._constraint(A), True, False, 0, False, False, False, False, 0, has_name,
+            has_doc, clause_len)
+
+  def _select_record(self, kind=0):
+    return (kind, self._constraint(B), False, False)
+
+  def _add_bulk(self, task_records, selector_records, strings=()):
+    context = self._native.context
+    return self.lib.tasks_add_bulk(self.tasks,
+                                   context.task_records_buf(task_records),
+                                   context.selector_records_buf(selector_records),
+                                   context.utf8_buf_buf(strings))
+
+  def test_bulk_add(self):
+    first, second = self._func(), self._func()
+    res = self._add_bulk([self._task_record(first, 1), self._task_record(second, 2)],
+                         [self._select_record(), self._select_record(), self._select_record(1)])
+    self.assertFalse(res.is_throw)
+    self.assertTrue(self._remove(first, self._constraint(A)))
+    self.assertTrue(self._remove(second, self._constraint(A)))
+
+  def test_bulk_add_clause_out_of_bounds(self):
+    res = self._add_bulk([self._task_record(self._func(), 1), self._task_record(self._func(), 2)],
+                         [self._select_record(), self._select_record()])
+    self.assert_registration_error(res, 'Task record 1 has a clause of length 2, but only 1 of 2')
+
+  def test_bulk_add_huge_clause_len(self):
+    res = self._add_bulk([self._task_record(self._func(), 2**64 - 1)], [self._select_record()])
+    self.assert_registration_error(res, 'Task record 0 has a clause of length')
+
+  def test_bulk_add_unused_selector_records(self):
+    res = self._add_bulk([self._task_record(self._func(), 1)],
+                         [self._select_record(), self._select_record()])
+    self.assert_registration_error(res, '1 of 2 selector records were not part of the clause')
+
+  def test_bulk_add_unknown_selector_kind(self):
+    res = self._add_bulk([self._task_record(self._func(), 1)], [self._select_record(kind=7)])
+    self.assert_registration_error(res, 'unknown kind 7')
+
+  def test_bulk_add_names_and_docs(self):
+    func = self._func()
+    res = self._add_bulk([self._task_record(func, 1, has_name=True, has_doc=True)],
+                         [self._select_record()],
+                         ['named', 'A documented task.'])
+    self.assertFalse(res.is_throw)
+    report = self._native.context.from_value(self.lib.tasks_report(self.tasks))
+    self.assertIn('task named ', report)
+    self.assertIn('doc: A documented task.', report)
+
+  def test_bulk_add_missing_doc(self):
+    res = self._add_bulk([self._task_record(self._func(), 1, has_doc=True)],
+                         [self._select_record()])
+    self.assert_registration_error(res, 'Task record 0 has a doc, but all 0 strings')
+
+  def test_bulk_add_is_all_or_nothing(self):
+    func = self._func()
+    res = self._add_bulk([self._task_record(func, 1), self._task_record(func, 1)],
+                         [self._select_record(), self._select_record()])
+    self.assert_registration_error(res, 'was double-registered')
+    self.assertFalse(self._remove(func, self._constraint(A)))
+
+  def test_task_alias(self):
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, self._builder_begin()).is_throw)
+    res = self.lib.tasks_task_alias(self.tasks, self._constraint(A), self._constraint(C))
+    self.assertFalse(res.is_throw)
+    report = self._native.context.from_value(
+      self.lib.tasks_gen_tasks_report(self.tasks,
+                                      TypeId(self._native.context.to_id(B)),
+                                      self._constraint(C)))
+    self.assertIn('<lambda>: first clause is empty', report)
+
+  def test_task_alias_cycle(self):
+    res = self.lib.tasks_task_alias(self.tasks, self._constraint(A), self._constraint(C))
+    self.assertFalse(res.is_throw)
+    res = self.lib.tasks_task_alias(self.tasks, self._constraint(C), self._constraint(A))
+    self.assert_registration_error(res, 'would create a cycle of aliases')
+
+  def test_task_alias_for_produced_product(self):
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, self._builder_begin()).is_throw)
+    res = self.lib.tasks_task_alias(self.tasks, self._constraint(C), self._constraint(A))
+    self.assert_registration_error(res, 'already produced by rules of its own')
+
+  def test_freeze(self):
+    func = self._func()
+    self.assertFalse(self.lib.tasks_freeze(self.tasks).is_throw)
+    res = self.lib.tasks_builder_finish(self.tasks, self._builder_begin())
+    self.assert_registration_error(res, 'The task registry is frozen')
+    res = self.lib.tasks_singleton_add(self.tasks,
+                                       self._native.context.to_value(A()),
+                                       self._constraint(A))
+    self.assert_registration_error(res, 'The task registry is frozen')
+    res = self.lib.tasks_task_remove(self.tasks, func, self._constraint(A))
+    self.assert_registration_error(res, 'The task registry is frozen')
+    self.assertFalse(self.lib.tasks_freeze(self.tasks).is_throw)
+
+  def test_freeze_while_building(self):
+    self._builder_begin()
+    res = self.lib.tasks_freeze(self.tasks)
+    self.assert_registration_error(res, 'while 1 task(s) are still being built')
+
+  def test_freeze_preserves_fingerprint(self):
+    self.assertFalse(self.lib.tasks_builder_finish(self.tasks, self._builder_begin()).is_throw)
+    before = self._native.context.from_value(self.lib.tasks_fingerprint(self.tasks))
+    self.assertFalse(self.lib.tasks_freeze(self.tasks).is_throw)
+    after = self._native.context.from_value(self.lib.tasks_fingerprint(self.tasks))
+    self.assertEquals(before, after)
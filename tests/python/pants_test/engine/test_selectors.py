@@ -7,20 +7,38 @@ from __future__ import (absolute_import, division, generators, nested_scopes, pr
 
 import unittest
 
-from pants.engine.selectors import Select, SelectDependencies, SelectProjection, SelectVariant
+from pants.engine.addressable import Exactly, SubclassesOf
+from pants.engine.selectors import (Select, SelectDependencies, SelectProjection, SelectSubject,
+                                    SelectUnion, SelectVariant, SelectVariantValue)
 
 
 class AClass(object):
   pass
 
 
+class BClass(object):
+  pass
+
+
 class SelectorsTest(unittest.TestCase):
   def test_select_repr(self):
     self.assert_repr("Select(AClass)", Select(AClass))
     self.assert_repr("Select(AClass, optional=True)", Select(AClass, optional=True))
+    self.assert_repr("Select(AClass, many=True)", Select(AClass, many=True))
+
+  def test_subject_repr(self):
+    self.assert_repr("SelectSubject(AClass)", SelectSubject(AClass))
 
   def test_variant_repr(self):
     self.assert_repr("SelectVariant(AClass, u'field')", SelectVariant(AClass, 'field'))
+    self.assert_repr("SelectVariant(AClass, u'field', default_value=u'value')",
+                     SelectVariant(AClass, 'field', default_value='value'))
+
+  def test_variant_value_repr(self):
+    self.assert_repr("SelectVariantValue(u'field')", SelectVariantValue('field'))
+
+  def test_union_repr(self):
+    self.assert_repr("SelectUnion(AClass, BClass)", SelectUnion((AClass, BClass)))
 
   def test_dependencies_repr(self):
     self.assert_repr("SelectDependencies(AClass, AClass)", SelectDependencies(AClass, AClass))
@@ -30,10 +48,18 @@ class SelectorsTest(unittest.TestCase):
                      SelectDependencies(AClass, AClass, field='some_field', field_types=(AClass,)))
     self.assert_repr("SelectDependencies(AClass, AClass)",
                      SelectDependencies(AClass, AClass))
+    self.assert_repr("SelectDependencies(AClass, AClass, skip_self=False)",
+                     SelectDependencies(AClass, AClass, skip_self=False))
 
   def test_projection_repr(self):
     self.assert_repr("SelectProjection(AClass, AClass, u'field', AClass)",
                      SelectProjection(AClass, AClass, 'field', AClass))
+    self.assert_repr("SelectProjection(AClass, AClass, (u'a', u'b'), AClass)",
+                     SelectProjection(AClass, AClass, ('a', 'b'), AClass))
+    self.assert_repr("SelectProjection(AClass, AClass, u'field', AClass, skip_missing=True)",
+                     SelectProjection(AClass, AClass, 'field', AClass, skip_missing=True))
+    self.assert_repr("SelectProjection(AClass, AClass, u'field', (AClass, AClass))",
+                     SelectProjection(AClass, AClass, 'field', (AClass, AClass)))
 
   def assert_repr(self, expected, selector):
     self.assertEqual(expected, repr(selector))
@@ -41,3 +67,30 @@ class SelectorsTest(unittest.TestCase):
   def test_select_variant_requires_string_key(self):
     with self.assertRaises(ValueError):
       SelectVariant(AClass, None)
+    with self.assertRaises(ValueError):
+      SelectVariant(AClass, 1)
+
+  def test_select_variant_requires_string_default_value(self):
+    with self.assertRaises(ValueError):
+      SelectVariant(AClass, 'field', default_value=1)
+
+  def test_select_variant_value_requires_string_key(self):
+    with self.assertRaises(ValueError):
+      SelectVariantValue(None)
+
+  def test_select_union_product(self):
+    self.assertEqual(Exactly(AClass, BClass), SelectUnion([AClass, Exactly(BClass)]).product)
+
+  def test_select_union_requires_products(self):
+    with self.assertRaises(ValueError):
+      SelectUnion(())
+
+  def test_select_union_requires_exact_products(self):
+    with self.assertRaises(TypeError):
+      SelectUnion((AClass, SubclassesOf(BClass)))
+
+  def test_select_projection_requires_string_fields(self):
+    with self.assertRaises(ValueError):
+      SelectProjection(AClass, AClass, ('a', 1), AClass)
+    with self.assertRaises(ValueError):
+      SelectProjection(AClass, AClass, (), AClass)
@@ -9,7 +9,7 @@ import unittest
 
 from pants.build_graph.address import Address
 from pants.engine.objects import ValidationError
-from pants.engine.struct import Struct
+from pants.engine.struct import Struct, Variants
 
 
 class StructTest(unittest.TestCase):
@@ -111,3 +111,11 @@ class StructTest(unittest.TestCase):
     jeb = Subclass(name='jeb')
     with self.assertRaises(ValidationError):
       jeb.validate()
+
+  def test_variants_default_pairs(self):
+    self.assertEqual((), Variants().default_pairs)
+    self.assertEqual(('a', '1', 'b', '2'), Variants(default={'b': '2', 'a': '1'}).default_pairs)
+
+  def test_variants_default_pairs_priority(self):
+    variants = Variants(default={'python': ['cp39', 'cp38'], 'os': 'linux'})
+    self.assertEqual(('os', 'linux', 'python', 'cp39', 'python', 'cp38'), variants.default_pairs)
@@ -25,11 +25,11 @@ def init_native():
   return Native.create(opts.for_global_scope())
 
 
-def create_native_scheduler(rules):
+def create_native_scheduler(rules, goals=None):
   """Create a WrappedNativeScheduler, with an initialized native instance."""
   rule_index = RuleIndex.create(rules)
   native = init_native()
-  scheduler = WrappedNativeScheduler(native, '.', './.pants.d', [], rule_index)
+  scheduler = WrappedNativeScheduler(native, '.', './.pants.d', [], rule_index, goals)
   return scheduler
 
 